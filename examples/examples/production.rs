@@ -0,0 +1,129 @@
+//! An example that combines the features of the server and the middleware
+//! into a single application.
+//!
+//! The settings are read from the environment variables:
+//!
+//! * `ADDR` - the address to listen on (default: `127.0.0.1:4000`)
+//! * `MAX_BODY_BYTES` - the maximum size of the request body (default: `1048576`)
+//! * `TRUSTED_PROXY` - the CIDR of the reverse proxy in front of the server (optional)
+//!
+//! The server shuts down gracefully on Ctrl-C. TLS and a separate admin socket
+//! are left out because the servers only listen on plain TCP; terminate TLS at
+//! the reverse proxy instead.
+
+use bytes::Bytes;
+use http::{header::HeaderValue, Request, Response, StatusCode};
+use izanami::{
+    app::handler_fn,
+    forwarded::{Cidr, ForwardedFor},
+    record::OnResponse,
+    request_id::{RequestId, SetRequestId},
+    response,
+};
+use izanami_hyper::MemoryGauge;
+use std::{env, sync::Arc, time::Duration};
+
+struct Config {
+    addr: String,
+    max_body_bytes: u64,
+    trusted_proxy: Option<Cidr>,
+}
+
+impl Config {
+    fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            addr: env::var("ADDR").unwrap_or_else(|_| "127.0.0.1:4000".into()),
+            max_body_bytes: match env::var("MAX_BODY_BYTES") {
+                Ok(max) => max.parse()?,
+                Err(..) => 1024 * 1024,
+            },
+            trusted_proxy: match env::var("TRUSTED_PROXY") {
+                Ok(cidr) => Some(cidr.parse()?),
+                Err(..) => None,
+            },
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    let gauge = MemoryGauge::new();
+
+    // The oversized bodies are rejected with 413 while they are being received.
+    let handler = {
+        let gauge = gauge.clone();
+        handler_fn(move |request: Request<Bytes>| {
+            let gauge = gauge.clone();
+            async move { route(request, &gauge) }
+        })
+        .max_body_size(config.max_body_bytes)
+    };
+
+    let mut app = ForwardedFor::new(SetRequestId::new(handler));
+    if let Some(cidr) = config.trusted_proxy {
+        app = app.trust(cidr);
+    }
+
+    // The access log is written after each response is completed or abandoned.
+    let app = OnResponse::new(app, |record| {
+        let elapsed = record.end - record.start;
+        eprintln!(
+            "{:5} {} {} {} {} {}B {:?}{}",
+            record.level,
+            record
+                .remote_addr
+                .map_or_else(|| "-".into(), |addr| addr.to_string()),
+            record.method,
+            record.path,
+            record.status.map_or(0, |status| status.as_u16()),
+            record.response_body_bytes,
+            elapsed,
+            if record.completed { "" } else { " (cancelled)" },
+        );
+    });
+
+    let server = izanami_hyper::Server::bind(config.addr)
+        .await?
+        .tcp_nodelay(true)
+        .request_body_idle_timeout(Duration::from_secs(30))
+        .max_response_header_bytes(16 * 1024)
+        .accept_rate_limit(1000, 100)
+        .memory_gauge(gauge)
+        .finalize_headers(|_, response| {
+            response
+                .headers_mut()
+                .insert("server", HeaderValue::from_static("izanami"));
+        });
+    server
+        .serve_until_ctrl_c(Arc::new(app))
+        .await
+        .map_err(|err| anyhow::anyhow!(err))?;
+
+    Ok(())
+}
+
+fn route(request: Request<Bytes>, gauge: &MemoryGauge) -> Result<Response<Bytes>, http::Error> {
+    match request.uri().path() {
+        "/healthz" => Ok(response::text(StatusCode::OK, "ok\n")),
+
+        "/metrics" => Ok(response::text(
+            StatusCode::OK,
+            format!(
+                "buffered_request_bytes {}\nbuffered_response_bytes {}\n",
+                gauge.request_bytes(),
+                gauge.response_bytes(),
+            ),
+        )),
+
+        "/echo" => {
+            let id = RequestId::get(&request).map_or("-", RequestId::as_str);
+            Ok(response::text(
+                StatusCode::OK,
+                format!("request {}: {} bytes\n", id, request.body().len()),
+            ))
+        }
+
+        _ => Ok(response::status(StatusCode::NOT_FOUND)),
+    }
+}
@@ -1,8 +1,8 @@
 #![allow(clippy::type_complexity)]
 
 use async_trait::async_trait;
-use http::{Request, Response};
-use izanami::{App, Events};
+use http::{Request, Response, StatusCode};
+use izanami::prelude::*;
 use izanami_hyper::Events as HyperEvents;
 use regex::{Regex, RegexSet};
 
@@ -29,9 +29,26 @@ impl<'a> App<HyperEvents<'a>> for Router {
         'l1: 'async_trait,
         HyperEvents<'a>: 'async_trait,
     {
+        // Match against the decoded path, so that the patterns need not
+        // take the percent-encoding into account.
+        let path = match izanami::uri::decode_path(request.uri().path()) {
+            Ok(path) => path,
+            Err(..) => {
+                return Box::pin(async move {
+                    let mut response = Response::new(());
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    let mut events = request.into_body();
+                    events
+                        .start_send_response(response, true)
+                        .await
+                        .map_err(Into::into)
+                })
+            }
+        };
+
         match self
             .re_set
-            .matches(request.uri().path())
+            .matches(&path)
             .iter()
             .next()
             .and_then(|index| self.routes.get(index))
@@ -95,7 +112,6 @@ impl<E: Events> App<E> for Hello
 where
     E: Send,
     E::Data: Send,
-    &'static str: Into<E::Data>,
 {
     type Error = BoxedError;
 
@@ -109,7 +125,7 @@ where
             .await
             .map_err(Into::into)?;
         events
-            .send_data("Hello, world!\n".into(), true)
+            .send("Hello, world!\n", true)
             .await
             .map_err(Into::into)?;
         Ok(())
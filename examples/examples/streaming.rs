@@ -1,12 +1,13 @@
 use async_trait::async_trait;
 use http::{Request, Response};
+use izanami::prelude::*;
 use izanami_h2::{Events, Server};
 
 #[derive(Clone)]
 struct Streaming;
 
 #[async_trait]
-impl<'a> izanami::App<Events<'a>> for Streaming {
+impl<'a> App<Events<'a>> for Streaming {
     type Error = anyhow::Error;
 
     async fn call(&self, req: Request<Events<'a>>) -> Result<(), Self::Error> {
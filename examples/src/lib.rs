@@ -1,15 +1,15 @@
 use async_trait::async_trait;
 use http::{Request, Response};
+use izanami::prelude::*;
 
 #[derive(Clone, Default)]
 pub struct Hello(());
 
 #[async_trait]
-impl<E> izanami::App<E> for Hello
+impl<E> App<E> for Hello
 where
-    E: izanami::Events + Send,
+    E: Events + Send,
     E::Data: Send,
-    &'static str: Into<E::Data>,
 {
     type Error = E::Error;
 
@@ -29,7 +29,7 @@ where
             )
             .await?;
 
-        events.send_data("Hello, world!\n".into(), true).await?;
+        events.send("Hello, world!\n", true).await?;
 
         Ok(())
     }
@@ -0,0 +1,247 @@
+//! Transport-level helpers for serving gRPC.
+//!
+//! This module provides the length-prefixed message framing and
+//! the construction of the `grpc-status`/`grpc-message` trailers.
+//! The encoding of the messages themselves (e.g. protobuf) is out of scope.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header::HeaderName, HeaderMap, HeaderValue};
+use std::{error, fmt};
+
+/// The size of the prefix of each message (the flags and the length).
+const PREFIX_LEN: usize = 5;
+
+/// The flag indicating that the message is compressed.
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// The default maximum length of a message accepted by `decode_frames` (4 MiB),
+/// which is the same as the default of the common gRPC implementations.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Encodes a message with the length-prefixed framing.
+///
+/// # Panics
+///
+/// This function panics if the length of `payload` does not fit in `u32`.
+pub fn encode_frame(flags: u8, payload: &[u8]) -> Bytes {
+    assert!(
+        payload.len() <= u32::MAX as usize,
+        "the payload is too large"
+    );
+    let mut buf = BytesMut::with_capacity(PREFIX_LEN + payload.len());
+    buf.put_u8(flags);
+    buf.put_u32_be(payload.len() as u32);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Returns an iterator over the complete messages in `buf`.
+///
+/// The payloads of the messages share the memory of `buf`. The bytes
+/// that do not form a complete message are available via `Frames::remainder`,
+/// so that they can be prepended to the next chunk of the request body.
+///
+/// The iterator returns `FrameTooLarge` as soon as the prefix of a message
+/// declares a length exceeding `DEFAULT_MAX_MESSAGE_SIZE`, without waiting
+/// for its payload. The limit can be changed by `Frames::max_message_size`.
+pub fn decode_frames(buf: Bytes) -> Frames {
+    Frames {
+        buf,
+        max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+    }
+}
+
+/// A message decoded from the length-prefixed framing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// The flags of the message.
+    pub flags: u8,
+
+    /// The payload of the message.
+    pub payload: Bytes,
+}
+
+impl Frame {
+    /// Returns whether the payload of this message is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+}
+
+/// An iterator over the messages, created by `decode_frames`.
+#[derive(Debug)]
+pub struct Frames {
+    buf: Bytes,
+    max_message_size: usize,
+}
+
+impl Frames {
+    /// Sets the maximum length of the payload of a message.
+    pub fn max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = max;
+        self
+    }
+
+    /// Returns the bytes that have not been decoded yet.
+    pub fn remainder(&self) -> &Bytes {
+        &self.buf
+    }
+
+    /// Consumes this iterator and returns the bytes that have not been decoded yet.
+    pub fn into_remainder(self) -> Bytes {
+        self.buf
+    }
+}
+
+impl Iterator for Frames {
+    type Item = Result<Frame, FrameTooLarge>;
+
+    /// Decodes the next message.
+    ///
+    /// After returning an error, it keeps returning the same error and the
+    /// oversized message is left at the beginning of the remainder.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < PREFIX_LEN {
+            return None;
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&self.buf[1..PREFIX_LEN]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_message_size {
+            return Some(Err(FrameTooLarge {
+                len,
+                max: self.max_message_size,
+            }));
+        }
+        if self.buf.len() - PREFIX_LEN < len {
+            return None;
+        }
+
+        let mut frame = self.buf.split_to(PREFIX_LEN + len);
+        let flags = frame[0];
+        frame.advance(PREFIX_LEN);
+        Some(Ok(Frame {
+            flags,
+            payload: frame,
+        }))
+    }
+}
+
+/// The error returned from `Frames` when a message exceeds the maximum length.
+///
+/// The server should respond with the `RESOURCE_EXHAUSTED` status (`8`)
+/// when receiving this error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    len: usize,
+    max: usize,
+}
+
+impl FrameTooLarge {
+    /// Returns the length declared by the prefix of the message.
+    pub fn message_len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the maximum length of a message.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the message has {} bytes while the maximum is {}",
+            self.len, self.max
+        )
+    }
+}
+
+impl error::Error for FrameTooLarge {}
+
+/// Creates the trailers that carry the gRPC status.
+///
+/// `message` is percent-encoded as required by the gRPC specification.
+pub fn trailers(status: u32, message: Option<&str>) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert(HeaderName::from_static("grpc-status"), status.into());
+    if let Some(message) = message {
+        trailers.insert(
+            HeaderName::from_static("grpc-message"),
+            HeaderValue::from_str(&percent_encode(message))
+                .expect("the percent-encoded message should be a valid header value"),
+        );
+    }
+    trailers
+}
+
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for &b in message.as_bytes() {
+        if (0x20..=0x7e).contains(&b) && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_roundtrip() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&encode_frame(0, b"hello"));
+        buf.extend_from_slice(&encode_frame(FLAG_COMPRESSED, b""));
+        buf.extend_from_slice(&encode_frame(0, b"world")[..7]);
+
+        let mut frames = decode_frames(buf.freeze());
+        let frame = frames.next().unwrap().unwrap();
+        assert_eq!(frame.payload, "hello");
+        assert!(!frame.is_compressed());
+        let frame = frames.next().unwrap().unwrap();
+        assert_eq!(frame.payload, "");
+        assert!(frame.is_compressed());
+        assert!(frames.next().is_none());
+        assert_eq!(frames.into_remainder(), &b"\x00\x00\x00\x00\x05wo"[..]);
+    }
+
+    #[test]
+    fn frames_reject_the_declared_length_over_the_limit() {
+        // only the prefix has been received.
+        let buf = Bytes::from_static(b"\x00\xff\xff\xff\xff");
+        let mut frames = decode_frames(buf.clone());
+        let err = frames.next().unwrap().unwrap_err();
+        assert_eq!(err.message_len(), u32::MAX as usize);
+        assert_eq!(err.max(), DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(frames.next(), Some(Err(err)));
+        assert_eq!(*frames.remainder(), buf);
+    }
+
+    #[test]
+    fn frames_with_custom_max_message_size() {
+        let buf = encode_frame(0, b"hello");
+        let mut frames = decode_frames(buf.clone()).max_message_size(5);
+        assert_eq!(frames.next().unwrap().unwrap().payload, "hello");
+
+        let mut frames = decode_frames(buf).max_message_size(4);
+        let err = frames.next().unwrap().unwrap_err();
+        assert_eq!((err.message_len(), err.max()), (5, 4));
+    }
+
+    #[test]
+    fn trailers_percent_encode_the_message() {
+        let headers = trailers(3, Some("bad value: 100%\n"));
+        assert_eq!(headers["grpc-status"], "3");
+        assert_eq!(headers["grpc-message"], "bad value: 100%25%0A");
+
+        let headers = trailers(0, None);
+        assert_eq!(headers["grpc-status"], "0");
+        assert!(!headers.contains_key("grpc-message"));
+    }
+}
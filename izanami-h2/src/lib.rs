@@ -1,63 +1,862 @@
+pub mod grpc;
+#[cfg(test)]
+mod tests;
+
 use async_trait::async_trait;
 use bytes::{Buf, Bytes};
-use futures::future::poll_fn;
+use futures::{
+    future::{self, poll_fn, Either, FutureExt},
+    stream::{FuturesUnordered, StreamExt},
+};
 use h2::{
     server::{Connection, SendResponse},
-    RecvStream, SendStream,
+    Reason, RecvStream, SendStream,
+};
+use http::{HeaderMap, Method, Request, Response};
+use izanami::{
+    cancel::CancelToken,
+    conn::{
+        ConnectionExtensions, ConnectionId, Protocol, RequestExtensions, RequestSequence,
+        SuggestedChunkSize,
+    },
+    finalize::{HeaderFinalizers, InvalidResponseState},
+    App,
+};
+use izanami_net::{
+    can_have_body, content_length, limit_header_size, panic_message, request_head,
+    strip_body_headers, ConnectionLimit, EventsError, GaugeGuard, Incoming, RateLimit, TaskTracker,
+};
+use std::{
+    error, fmt,
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    ops::Deref,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use http::{HeaderMap, Request, Response};
-use izanami::App;
-use std::{io, net::ToSocketAddrs};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    executor::{DefaultExecutor, Executor},
+    net::TcpStream,
+    timer::Timeout,
+};
+
+pub use izanami_net::{BindRetry, MemoryGauge, TcpConfig};
+
+#[cfg(unix)]
+pub use izanami_net::{listen_fd, run_sharded};
+
+/// The maximum duration to wait for the in-flight requests after
+/// the connection has been closed.
+const REQUEST_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_MAX_RESPONSE_HEADER_BYTES: usize = 64 * 1024;
+
+const DEFAULT_MAX_RESETS_PER_MINUTE: u32 = 600;
+
+const DEFAULT_MAX_PROTOCOL_ERRORS: usize = 5;
+
+/// The length of the window in which the stream resets are counted.
+const RESET_WINDOW: Duration = Duration::from_secs(60);
+
+/// The callback registered by `Server::on_connection_closed`.
+#[derive(Clone)]
+struct ClosedCallback(Arc<dyn Fn(Duration) + Send + Sync + 'static>);
+
+impl fmt::Debug for ClosedCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClosedCallback").finish()
+    }
+}
+
+/// The callback registered by `Server::on_limit_exceeded`.
+#[derive(Clone)]
+struct LimitCallback(Arc<dyn Fn(LimitExceeded) + Send + Sync + 'static>);
+
+impl fmt::Debug for LimitCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LimitCallback").finish()
+    }
+}
+
+/// The limit whose violation made the server close a connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The client reset more streams than `max_resets_per_minute` or
+    /// `max_resets_total`. The connection is closed with `ENHANCE_YOUR_CALM`.
+    Resets,
+
+    /// The client violated the protocol more times than
+    /// `max_protocol_errors_per_connection`. The connection is closed gracefully.
+    ProtocolErrors,
+}
+
+/// The chunk size suggested to the application, which is the default
+/// `SETTINGS_MAX_FRAME_SIZE` that every peer accepts.
+const SUGGESTED_CHUNK_SIZE: usize = 16 * 1024;
 
 #[derive(Debug)]
-pub struct Server {
-    listener: TcpListener,
+pub struct Server<E = DefaultExecutor> {
+    incoming: Incoming,
     h2: h2::server::Builder,
+    max_connections: Option<usize>,
+    on_connection_closed: Option<ClosedCallback>,
+    reset_limit: ResetLimit,
+    body_idle_timeout: Option<Duration>,
+    max_response_header_bytes: usize,
+    memory_gauge: Option<MemoryGauge>,
+    strict_content_length: bool,
+    request_extensions: RequestExtensions,
+    header_finalizers: HeaderFinalizers,
+    executor: E,
 }
 
 impl Server {
     pub async fn bind<A>(addr: A) -> io::Result<Self>
     where
-        A: ToSocketAddrs,
+        A: ToSocketAddrs + Send + 'static,
     {
-        let addr = addr.to_socket_addrs()?.next().unwrap();
-        let listener = TcpListener::bind(&addr).await?;
+        Self::bind_with(addr, &TcpConfig::default()).await
+    }
+
+    pub async fn bind_with<A>(addr: A, config: &TcpConfig) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        Self::bind_inner(addr, config, None).await
+    }
+
+    /// Creates a server bound to the specified address, retrying while the address is in use.
+    ///
+    /// The host name in `addr` is resolved without blocking the runtime, and
+    /// all of the resolved addresses are bound (see `TcpConfig::bind_all`).
+    pub async fn bind_with_retry<A>(
+        addr: A,
+        config: &TcpConfig,
+        retry: BindRetry,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        Self::bind_inner(addr, config, Some(retry)).await
+    }
+
+    async fn bind_inner<A>(
+        addr: A,
+        config: &TcpConfig,
+        retry: Option<BindRetry>,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        let addrs = izanami_net::resolve(addr).await?;
+        let listeners = config.bind_all(&addrs, retry).await?;
+        Ok(Self::from_incoming(Incoming::from_std_listeners(
+            listeners,
+        )?))
+    }
+
+    /// Creates a server from a listener that has already been bound.
+    ///
+    /// On Unix, this can be combined with `listen_fd` to serve a socket
+    /// passed by systemd's socket activation.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        Ok(Self::from_incoming(Incoming::from_std(listener)?))
+    }
+
+    fn from_incoming(incoming: Incoming) -> Self {
         let h2 = h2::server::Builder::new();
-        Ok(Self { listener, h2 })
+        Self {
+            incoming,
+            h2,
+            max_connections: None,
+            on_connection_closed: None,
+            reset_limit: ResetLimit {
+                per_minute: Some(DEFAULT_MAX_RESETS_PER_MINUTE),
+                total: None,
+                protocol_errors: Some(DEFAULT_MAX_PROTOCOL_ERRORS),
+                on_exceeded: None,
+            },
+            body_idle_timeout: None,
+            max_response_header_bytes: DEFAULT_MAX_RESPONSE_HEADER_BYTES,
+            memory_gauge: None,
+            strict_content_length: true,
+            request_extensions: RequestExtensions::new(),
+            header_finalizers: HeaderFinalizers::new(),
+            executor: DefaultExecutor::current(),
+        }
+    }
+}
+
+impl<E> Server<E> {
+    /// Returns the local addresses that the server is bound to.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        self.incoming.local_addrs()
+    }
+
+    /// Sets the executor used to spawn the tasks for connections.
+    ///
+    /// The requests are processed within the task of the connection.
+    ///
+    /// The default value is `DefaultExecutor`, that spawns the tasks onto
+    /// the runtime running the server.
+    pub fn executor<E2>(self, executor: E2) -> Server<E2>
+    where
+        E2: Executor + Clone + Send + 'static,
+    {
+        Server {
+            incoming: self.incoming,
+            h2: self.h2,
+            max_connections: self.max_connections,
+            on_connection_closed: self.on_connection_closed,
+            reset_limit: self.reset_limit,
+            body_idle_timeout: self.body_idle_timeout,
+            max_response_header_bytes: self.max_response_header_bytes,
+            memory_gauge: self.memory_gauge,
+            strict_content_length: self.strict_content_length,
+            request_extensions: self.request_extensions,
+            header_finalizers: self.header_finalizers,
+            executor,
+        }
+    }
+
+    /// Sets whether to set `TCP_NODELAY` on the accepted connections.
+    ///
+    /// The default value is `false`.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.incoming.set_nodelay(enabled);
+        self
+    }
+
+    /// Sets the keepalive interval of the accepted connections.
+    ///
+    /// If `None` is specified, `SO_KEEPALIVE` is not enabled.
+    /// The default value is `None`.
+    pub fn tcp_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.incoming.set_keepalive(keepalive);
+        self
+    }
+
+    /// Sets the initial window size of the streams, advertised in `SETTINGS_INITIAL_WINDOW_SIZE`.
+    ///
+    /// The default value is 65,535.
+    pub fn initial_window_size(mut self, size: u32) -> Self {
+        self.h2.initial_window_size(size);
+        self
+    }
+
+    /// Sets the initial window size of the connections.
+    ///
+    /// The default value is 65,535.
+    pub fn initial_connection_window_size(mut self, size: u32) -> Self {
+        self.h2.initial_connection_window_size(size);
+        self
+    }
+
+    /// Sets the maximum size of the frame payload, advertised in `SETTINGS_MAX_FRAME_SIZE`.
+    ///
+    /// The value must be between 16,384 and 16,777,215. The default value is 16,384.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the value is out of the range.
+    pub fn max_frame_size(mut self, max: u32) -> Self {
+        self.h2.max_frame_size(max);
+        self
+    }
+
+    /// Sets the maximum number of the concurrent streams per connection,
+    /// advertised in `SETTINGS_MAX_CONCURRENT_STREAMS`.
+    ///
+    /// By default, there is no limit.
+    pub fn max_concurrent_streams(mut self, max: u32) -> Self {
+        self.h2.max_concurrent_streams(max);
+        self
+    }
+
+    /// Sets the maximum size of the request header, advertised in `SETTINGS_MAX_HEADER_LIST_SIZE`.
+    ///
+    /// By default, there is no limit.
+    pub fn max_header_list_size(mut self, max: u32) -> Self {
+        self.h2.max_header_list_size(max);
+        self
+    }
+
+    /// Sets the maximum number of streams that the client may reset
+    /// on each connection per minute.
+    ///
+    /// A client resetting the streams right after opening them (the "rapid reset"
+    /// pattern) makes the server set up and tear down requests at little cost
+    /// to itself. When the limit is exceeded, the connection is closed with
+    /// `GOAWAY` (`ENHANCE_YOUR_CALM`), and the callback registered by
+    /// `on_limit_exceeded` is invoked. The streams that have already been reset
+    /// when they are accepted are not passed to the application.
+    ///
+    /// The default value is 600.
+    pub fn max_resets_per_minute(mut self, max: u32) -> Self {
+        self.reset_limit.per_minute = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of streams that the client may reset
+    /// over the lifetime of each connection.
+    ///
+    /// The connection is closed in the same way as `max_resets_per_minute`
+    /// when the limit is exceeded.
+    ///
+    /// By default, there is no limit.
+    pub fn max_resets_total(mut self, max: u64) -> Self {
+        self.reset_limit.total = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of the streams on which the client may violate
+    /// the protocol over the lifetime of each connection.
+    ///
+    /// A violation is a request body that does not match `Content-Length`, or
+    /// any other error for which h2 resets the stream with `PROTOCOL_ERROR`
+    /// after the request header has been received. The requests with
+    /// malformed headers are dropped by h2 before reaching the server, and
+    /// are not counted. When the limit is exceeded, the connection
+    /// is closed gracefully: `GOAWAY` is sent, and the streams in flight are
+    /// completed.
+    ///
+    /// The default value is 5.
+    pub fn max_protocol_errors_per_connection(mut self, max: usize) -> Self {
+        self.reset_limit.protocol_errors = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of the concurrent connections.
+    ///
+    /// When the limit is reached, the server stops accepting connections
+    /// until one of the active connections is closed. The pending connections
+    /// are left in the backlog of the listener.
+    ///
+    /// By default, there is no limit.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `max` is zero.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        assert!(
+            max > 0,
+            "the maximum number of connections must be positive"
+        );
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the maximum duration to wait for the next chunk of the request body.
+    ///
+    /// The timer is reset every time a chunk is received, so slow but steady
+    /// uploads are not interrupted. When the timer expires, `Events::data`
+    /// (or `Events::trailers`) returns an error caused by `BodyTimedOut`.
+    ///
+    /// By default, there is no timeout.
+    pub fn request_body_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.body_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum total size of the response header fields.
+    ///
+    /// If the application starts a response whose header fields exceed this
+    /// size, an empty `500 Internal Server Error` response is sent instead,
+    /// and the data of the original response is discarded.
+    ///
+    /// The default value is 64 KiB.
+    pub fn max_response_header_bytes(mut self, max: usize) -> Self {
+        self.max_response_header_bytes = max;
+        self
+    }
+
+    /// Sets whether to check the length of the request body against `Content-Length`.
+    ///
+    /// If enabled, `Events::data` returns an error caused by `IncompleteBody`
+    /// instead of the end of stream when the request body is shorter than
+    /// declared, and when it is longer than declared.
+    ///
+    /// Note that h2 itself also resets such a stream with `PROTOCOL_ERROR`
+    /// as soon as the mismatch is received, without waking a pending
+    /// `Events::data`. Set `request_body_idle_timeout` to bound the wait.
+    ///
+    /// The default value is `true`.
+    pub fn strict_content_length(mut self, enabled: bool) -> Self {
+        self.strict_content_length = enabled;
+        self
+    }
+
+    /// Registers a function that derives a value from each accepted connection.
+    ///
+    /// The function is called once per connection with the address of the
+    /// peer, and a clone of the returned value is inserted into the extensions
+    /// of every request on that connection.
+    pub fn with_request_extension<F, T>(self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> T + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+    {
+        self.try_with_request_extension(move |addr| Ok::<_, std::convert::Infallible>(f(addr)))
+    }
+
+    /// Registers a fallible function that derives a value from each accepted connection.
+    ///
+    /// This is the same as `with_request_extension`, except that the connection
+    /// is closed before the handshake if the function returns an error.
+    pub fn try_with_request_extension<F, T, Err>(mut self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> Result<T, Err> + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+        Err: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        self.request_extensions.push(f);
+        self
+    }
+
+    /// Registers a callback that modifies the response header just before it is sent.
+    ///
+    /// The callbacks are called in the order of registration with the head of
+    /// the request, exactly once for each response, including the responses
+    /// that the server sends in place of the application.
+    pub fn finalize_headers<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Request<()>, &mut Response<()>) + Send + Sync + 'static,
+    {
+        self.header_finalizers.push(f);
+        self
+    }
+
+    /// Sets the gauge that accounts for the buffered body data.
+    ///
+    /// The received chunks are accounted until they are dropped by the
+    /// application, and the chunks passed to `Events::send_data` are
+    /// accounted until they are written to the socket or discarded.
+    ///
+    /// By default, the buffered data is not accounted.
+    pub fn memory_gauge(mut self, gauge: MemoryGauge) -> Self {
+        self.memory_gauge = Some(gauge);
+        self
+    }
+
+    /// Sets the interval to sleep before retrying when accepting
+    /// an incoming connection fails due to a resource error,
+    /// such as running out of file descriptors (`EMFILE`).
+    ///
+    /// If `None` is specified, the error is returned from `serve`.
+    /// Errors specific to a single connection (e.g. `ECONNABORTED`) are
+    /// always ignored and the server continues to accept connections.
+    ///
+    /// The default value is 1 second.
+    pub fn sleep_on_errors(mut self, interval: Option<Duration>) -> Self {
+        self.incoming.set_sleep_on_errors(interval);
+        self
+    }
+
+    /// Registers a callback invoked when the server accepts a connection.
+    pub fn on_accept<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) + Send + Sync + 'static,
+    {
+        self.incoming.set_on_accept(f);
+        self
+    }
+
+    /// Registers a callback invoked with the lifetime of each connection when it is closed.
+    ///
+    /// Together with `on_accept`, this can be used to track the number
+    /// of the active connections.
+    pub fn on_connection_closed<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        self.on_connection_closed = Some(ClosedCallback(Arc::new(f)));
+        self
+    }
+
+    /// Registers a callback invoked when a connection is closed because the
+    /// client has exceeded one of the limits of the stream resets or the
+    /// protocol errors.
+    ///
+    /// The callback is called once per connection, when `GOAWAY` is sent, and
+    /// can be used to count the abusive connections separately from the
+    /// ordinary ones.
+    pub fn on_limit_exceeded<F>(mut self, f: F) -> Self
+    where
+        F: Fn(LimitExceeded) + Send + Sync + 'static,
+    {
+        self.reset_limit.on_exceeded = Some(LimitCallback(Arc::new(f)));
+        self
+    }
+
+    /// Registers a callback invoked with every error that occurs while
+    /// accepting incoming connections.
+    pub fn on_accept_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.incoming.set_on_error(f);
+        self
+    }
+
+    /// Registers a callback invoked when accepting a connection fails
+    /// because of running out of file descriptors (`EMFILE`/`ENFILE`).
+    pub fn on_resource_exhausted<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.incoming.set_on_resource_exhausted(f);
+        self
+    }
+
+    /// Limits the rate of accepting connections to `rate` connections
+    /// per second, allowing bursts of up to `burst` connections.
+    ///
+    /// While the limit is exceeded, the pending connections are left
+    /// in the backlog of the listener.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `rate` or `burst` is zero.
+    pub fn accept_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.incoming
+            .set_rate_limit(Some(RateLimit::new(rate, burst)));
+        self
     }
 
     pub async fn serve<T>(self, app: T) -> io::Result<()>
     where
         T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+        E: Executor + Clone + Send + 'static,
+    {
+        self.serve_with_shutdown(app, future::pending()).await
+    }
+
+    /// Serves the application until the process receives Ctrl-C, and then shuts down gracefully.
+    pub async fn serve_until_ctrl_c<T>(self, app: T) -> io::Result<()>
+    where
+        T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+        E: Executor + Clone + Send + 'static,
     {
-        let mut listener = self.listener;
+        let mut ctrl_c = tokio::net::signal::ctrl_c()?;
+        let signal = async move {
+            let _ =
+                poll_fn(|cx| tokio::prelude::Stream::poll_next(Pin::new(&mut ctrl_c), cx)).await;
+        };
+        self.serve_with_shutdown(app, signal).await
+    }
+
+    /// Serves the application until `signal` completes, and then shuts down gracefully.
+    ///
+    /// When the signal fires, the server closes the listeners and sends `GOAWAY`
+    /// to every active connection. The returned future completes once the
+    /// in-flight requests have finished and all the connections have been closed.
+    pub async fn serve_with_shutdown<T, F>(self, app: T, signal: F) -> io::Result<()>
+    where
+        T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+        E: Executor + Clone + Send + 'static,
+        F: Future<Output = ()>,
+    {
+        let mut incoming = self.incoming;
+        let mut executor = self.executor;
+        let config = StreamConfig {
+            body_idle_timeout: self.body_idle_timeout,
+            max_response_header_bytes: self.max_response_header_bytes,
+            memory_gauge: self.memory_gauge,
+            strict_content_length: self.strict_content_length,
+            header_finalizers: self.header_finalizers,
+        };
+        let reset_limit = self.reset_limit;
+        let limit = self.max_connections.map(ConnectionLimit::new);
+        let tracker = TaskTracker::new();
+        let shutdown = CancelToken::new();
+        let mut signal = Box::pin(signal);
+        let mut next_id = 0;
         loop {
-            if let Ok((socket, _)) = listener.accept().await {
-                let handshake = self.h2.handshake(socket);
-                let app = app.clone();
-                tokio::spawn(async move {
+            let accept = async {
+                let guard = match limit {
+                    Some(ref limit) => Some(limit.acquire().await),
+                    None => None,
+                };
+                (guard, incoming.accept().await)
+            };
+            let (guard, accepted) = match future::select(Box::pin(accept), signal.as_mut()).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(..) => break,
+            };
+            let (socket, addr) = accepted?;
+            let accepted_at = Instant::now();
+
+            let conn_id = ConnectionId(next_id);
+            next_id += 1;
+
+            let mut extensions = match self.request_extensions.for_connection(&addr) {
+                Ok(extensions) => extensions,
+                Err(err) => {
+                    tracing::debug!("rejected the connection from {}: {}", addr, err);
+                    if let Some(ref on_closed) = self.on_connection_closed {
+                        (on_closed.0)(accepted_at.elapsed());
+                    }
+                    continue;
+                }
+            };
+
+            if let Ok(local_addr) = socket.local_addr() {
+                extensions.set_local_addr(local_addr);
+            }
+            let handshake = self.h2.handshake(socket);
+            let app = app.clone();
+            let config = config.clone();
+            let reset_limit = reset_limit.clone();
+            let on_closed = self.on_connection_closed.clone();
+            let shutdown = shutdown.child_token();
+            let tracked = tracker.track();
+            let spawned = executor.spawn(Box::pin(async move {
+                let _tracked = tracked;
+                let _guard = guard;
+                // A panic in the connection task only closes this connection, and
+                // the slot and the close callback are released as usual.
+                let result = AssertUnwindSafe(async move {
                     match handshake.await {
-                        Ok(conn) => handle_connection(conn, app).await,
-                        Err(err) => {
-                            tracing::error!("handshake error: {}", err);
-                            return;
+                        Ok(conn) => {
+                            handle_connection(
+                                conn,
+                                conn_id,
+                                extensions,
+                                config,
+                                reset_limit,
+                                shutdown,
+                                app,
+                            )
+                            .await
                         }
+                        Err(err) => tracing::error!("handshake error: {}", err),
                     }
-                });
+                })
+                .catch_unwind()
+                .await;
+                if let Err(panic) = result {
+                    tracing::error!(
+                        "the task for the connection {} panicked: {}",
+                        conn_id.0,
+                        panic_message(&*panic)
+                    );
+                }
+                if let Some(on_closed) = on_closed {
+                    (on_closed.0)(accepted_at.elapsed());
+                }
+            }));
+            if let Err(err) = spawned {
+                tracing::error!("failed to spawn the connection task: {}", err);
             }
         }
+
+        drop(incoming);
+        tracing::info!(
+            "shutting down: waiting for {} connection(s) to be closed",
+            tracker.active()
+        );
+        shutdown.cancel();
+        tracker.wait_idle().await;
+        Ok(())
     }
 }
 
-async fn handle_connection<T>(mut conn: Connection<TcpStream, Data>, app: T)
-where
+/// The limits of the streams that the client may reset or violate
+/// the protocol on a connection.
+#[derive(Debug, Clone)]
+struct ResetLimit {
+    per_minute: Option<u32>,
+    total: Option<u64>,
+    protocol_errors: Option<usize>,
+    on_exceeded: Option<LimitCallback>,
+}
+
+/// Counts the streams reset by the client in fixed windows of one minute,
+/// and the streams on which the client has violated the protocol.
+#[derive(Debug)]
+struct ResetCounter {
+    limit: ResetLimit,
+    window_start: Instant,
+    in_window: u32,
+    total: u64,
+    exceeded: bool,
+    protocol_errors: usize,
+    protocol_errors_exceeded: bool,
+}
+
+impl ResetCounter {
+    fn new(limit: ResetLimit) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            in_window: 0,
+            total: 0,
+            exceeded: false,
+            protocol_errors: 0,
+            protocol_errors_exceeded: false,
+        }
+    }
+
+    /// Records a protocol error and returns whether the limit has just been exceeded.
+    fn record_protocol_error(&mut self) -> bool {
+        self.protocol_errors = self.protocol_errors.saturating_add(1);
+        if self.protocol_errors_exceeded {
+            return false;
+        }
+        self.protocol_errors_exceeded = self
+            .limit
+            .protocol_errors
+            .is_some_and(|max| self.protocol_errors > max);
+        self.protocol_errors_exceeded
+    }
+
+    /// Records a reset and returns whether the limit has just been exceeded.
+    fn record(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= RESET_WINDOW {
+            self.window_start = now;
+            self.in_window = 0;
+        }
+        self.in_window = self.in_window.saturating_add(1);
+        self.total += 1;
+        if self.exceeded {
+            return false;
+        }
+        self.exceeded = self
+            .limit
+            .per_minute
+            .is_some_and(|max| self.in_window > max)
+            || self.limit.total.is_some_and(|max| self.total > max);
+        self.exceeded
+    }
+
+    /// Reports that the connection is closed because of `exceeded`.
+    fn notify(&self, exceeded: LimitExceeded) {
+        if let Some(ref on_exceeded) = self.limit.on_exceeded {
+            (on_exceeded.0)(exceeded);
+        }
+    }
+}
+
+/// The events that drive the connection task.
+enum ConnectionEvent<A> {
+    Accepted(A),
+    Finished(StreamOutcome),
+    Shutdown,
+}
+
+/// How the client behaved on a stream whose request has finished.
+#[derive(Debug, Default, Copy, Clone)]
+struct StreamOutcome {
+    /// The client has reset the stream.
+    reset: bool,
+    /// The client has violated the protocol while sending the request.
+    protocol_error: bool,
+}
+
+impl StreamOutcome {
+    /// Classifies a stream reset with `reason`.
+    ///
+    /// h2 resets the stream with `PROTOCOL_ERROR` when the client violates
+    /// the protocol on it, which is counted separately from the resets
+    /// sent by the client.
+    fn reset_with(reason: Reason) -> Self {
+        let protocol_error = reason == Reason::PROTOCOL_ERROR;
+        Self {
+            reset: !protocol_error,
+            protocol_error,
+        }
+    }
+}
+
+/// The settings passed from the server to each stream.
+#[derive(Debug, Clone)]
+struct StreamConfig {
+    body_idle_timeout: Option<Duration>,
+    max_response_header_bytes: usize,
+    memory_gauge: Option<MemoryGauge>,
+    strict_content_length: bool,
+    header_finalizers: HeaderFinalizers,
+}
+
+async fn handle_connection<T>(
+    mut conn: Connection<TcpStream, Data>,
+    conn_id: ConnectionId,
+    extensions: ConnectionExtensions,
+    config: StreamConfig,
+    reset_limit: ResetLimit,
+    shutdown: CancelToken,
+    app: T,
+) where
     T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
 {
+    // The requests are driven by the connection task, rather than being spawned,
+    // so that none of them outlives the connection.
+    let mut requests = FuturesUnordered::new();
+    let cancel = CancelToken::new();
+    let mut resets = ResetCounter::new(reset_limit);
+    let mut next_sequence = 1;
+    let mut shutting_down = false;
+
     loop {
-        match conn.accept().await {
-            Some(Ok((request, sender))) => {
-                tokio::spawn(handle_request(app.clone(), request, sender));
+        let event = {
+            let accept = conn.accept().map(ConnectionEvent::Accepted).boxed();
+            let finished = if requests.is_empty() {
+                Either::Left(future::pending())
+            } else {
+                Either::Right(
+                    requests
+                        .next()
+                        .map(|outcome| ConnectionEvent::Finished(outcome.unwrap_or_default())),
+                )
+            };
+            let signal = if shutting_down {
+                Either::Left(future::pending())
+            } else {
+                Either::Right(shutdown.cancelled().map(|()| ConnectionEvent::Shutdown))
+            };
+            let others = future::select(finished, signal).map(|either| either.factor_first().0);
+            future::select(accept, others).await.factor_first().0
+        };
+
+        let accepted = match event {
+            ConnectionEvent::Accepted(accepted) => accepted,
+            ConnectionEvent::Finished(outcome) => {
+                record_outcome(&mut conn, &mut resets, conn_id, outcome, &mut shutting_down);
+                continue;
+            }
+            ConnectionEvent::Shutdown => {
+                // The connection is closed by h2 once the active streams have completed.
+                tracing::debug!("shutting down the connection {}", conn_id.0);
+                conn.graceful_shutdown();
+                shutting_down = true;
+                continue;
+            }
+        };
+
+        match accepted {
+            Some(Ok((mut request, mut sender))) => {
+                // A stream that has been reset before being accepted can no
+                // longer be answered, so the application is not called for it.
+                if let Some(Ok(reason)) = poll_fn(|cx| sender.poll_reset(cx)).now_or_never() {
+                    let outcome = StreamOutcome::reset_with(reason);
+                    record_outcome(&mut conn, &mut resets, conn_id, outcome, &mut shutting_down);
+                    continue;
+                }
+                request.extensions_mut().insert(cancel.child_token());
+                requests.push(handle_request(
+                    app.clone(),
+                    conn_id,
+                    RequestSequence(next_sequence),
+                    extensions.clone(),
+                    config.clone(),
+                    request,
+                    sender,
+                ));
+                next_sequence += 1;
             }
             Some(Err(err)) => {
                 tracing::error!("accept error: {}", err);
@@ -69,14 +868,87 @@ where
             }
         }
     }
+
+    // Dropping the connection resets the remaining streams, so the pending
+    // operations on `Events` fail immediately. The requests that do not touch
+    // the streams are given a short grace period before being cancelled.
+    drop(conn);
+    cancel.cancel();
+    if !requests.is_empty() {
+        let drain = async { while requests.next().await.is_some() {} };
+        if Timeout::new(drain, REQUEST_DRAIN_TIMEOUT).await.is_err() {
+            tracing::debug!(
+                "cancelling {} request(s) after the connection was closed",
+                requests.len()
+            );
+        }
+    }
 }
 
-async fn handle_request<T>(app: T, request: Request<RecvStream>, mut sender: SendResponse<Data>)
+/// Counts the resets and the protocol errors of a finished stream,
+/// and sends `GOAWAY` if one of the limits is exceeded.
+fn record_outcome(
+    conn: &mut Connection<TcpStream, Data>,
+    resets: &mut ResetCounter,
+    conn_id: ConnectionId,
+    outcome: StreamOutcome,
+    shutting_down: &mut bool,
+) {
+    if outcome.reset && resets.record() {
+        tracing::warn!(
+            "closing the connection {}: the client reset too many streams",
+            conn_id.0
+        );
+        conn.abrupt_shutdown(Reason::ENHANCE_YOUR_CALM);
+        resets.notify(LimitExceeded::Resets);
+    }
+    if outcome.protocol_error && resets.record_protocol_error() && !*shutting_down {
+        tracing::warn!(
+            "closing the connection {}: the client violated the protocol too many times",
+            conn_id.0
+        );
+        conn.graceful_shutdown();
+        *shutting_down = true;
+        resets.notify(LimitExceeded::ProtocolErrors);
+    }
+}
+
+async fn handle_request<T>(
+    app: T,
+    conn_id: ConnectionId,
+    sequence: RequestSequence,
+    extensions: ConnectionExtensions,
+    config: StreamConfig,
+    request: Request<RecvStream>,
+    mut sender: SendResponse<Data>,
+) -> StreamOutcome
 where
     T: for<'a> App<Events<'a>>,
 {
-    let (parts, mut receiver) = request.into_parts();
+    let (mut parts, mut receiver) = request.into_parts();
+    parts.extensions.insert(conn_id);
+    parts.extensions.insert(sequence);
+    extensions.insert_into(&mut parts.extensions);
+    let remote_addr = extensions.remote_addr();
+    let local_addr = extensions.local_addr();
+    parts.extensions.insert(Protocol::Http2 { is_tls: false });
+    parts
+        .extensions
+        .insert(SuggestedChunkSize(SUGGESTED_CHUNK_SIZE));
+    let is_head = parts.method == Method::HEAD;
+    let content_length = if config.strict_content_length {
+        content_length(&parts.headers)
+    } else {
+        None
+    };
+    let request_head = if config.header_finalizers.is_empty() {
+        None
+    } else {
+        Some(request_head(&parts))
+    };
     let mut stream = None;
+    let mut reset_sent = false;
+    let mut protocol_error = false;
 
     if let Err(err) = app
         .call(Request::from_parts(
@@ -85,6 +957,21 @@ where
                 receiver: &mut receiver,
                 sender: &mut sender,
                 stream: &mut stream,
+                reset_sent: &mut reset_sent,
+                protocol_error: &mut protocol_error,
+                remote_addr,
+                local_addr,
+                is_head,
+                discard_body: false,
+                send_finished: false,
+                body_idle_timeout: config.body_idle_timeout,
+                max_response_header_bytes: config.max_response_header_bytes,
+                memory_gauge: config.memory_gauge,
+                header_finalizers: config.header_finalizers,
+                request_head,
+                content_length,
+                received: 0,
+                recv_state: RecvState::Data,
             },
         ))
         .await
@@ -94,6 +981,19 @@ where
     }
 
     drop(receiver);
+
+    // The streams are still alive here, so the reset is the one received
+    // from the client unless the application has sent it.
+    let reset = match stream {
+        Some(ref mut stream) => poll_fn(|cx| stream.poll_reset(cx)).now_or_never(),
+        None => poll_fn(|cx| sender.poll_reset(cx)).now_or_never(),
+    };
+    let mut outcome = match reset {
+        Some(Ok(reason)) if !reset_sent => StreamOutcome::reset_with(reason),
+        _ => StreamOutcome::default(),
+    };
+    outcome.protocol_error |= protocol_error;
+    outcome
 }
 
 #[derive(Debug)]
@@ -101,70 +1001,318 @@ pub struct Events<'a> {
     receiver: &'a mut RecvStream,
     sender: &'a mut SendResponse<Data>,
     stream: &'a mut Option<SendStream<Data>>,
+    reset_sent: &'a mut bool,
+    protocol_error: &'a mut bool,
+    remote_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    is_head: bool,
+    discard_body: bool,
+    send_finished: bool,
+    body_idle_timeout: Option<Duration>,
+    max_response_header_bytes: usize,
+    memory_gauge: Option<MemoryGauge>,
+    header_finalizers: HeaderFinalizers,
+    request_head: Option<Request<()>>,
+    content_length: Option<u64>,
+    received: u64,
+    recv_state: RecvState,
+}
+
+/// The progress of receiving the request from the client.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RecvState {
+    Data,
+    Trailers,
+    Done,
 }
 
 impl Events<'_> {
-    pub async fn data(&mut self) -> Option<Result<Data, h2::Error>> {
-        let data = self.receiver.data().await;
-        if let Some(Ok(ref data)) = data {
-            let release_capacity = self.receiver.release_capacity();
-            if let Err(err) = release_capacity.release_capacity(data.len()) {
-                return Some(Err(err));
+    /// Returns the remote address of the connection.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Returns the local address of the connection, if available.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub async fn data(&mut self) -> Option<Result<Data, Error>> {
+        if self.recv_state != RecvState::Data {
+            return None;
+        }
+        let data = match self.body_idle_timeout {
+            Some(timeout) => match Timeout::new(self.receiver.data(), timeout).await {
+                Ok(data) => data,
+                Err(..) => return Some(Err(Error::body_timed_out())),
+            },
+            None => self.receiver.data().await,
+        };
+        match data {
+            Some(Ok(bytes)) => {
+                let release_capacity = self.receiver.release_capacity();
+                if let Err(err) = release_capacity.release_capacity(bytes.len()) {
+                    return Some(Err(err.into()));
+                }
+                if let Err(err) = self.count_received(bytes.len()) {
+                    *self.protocol_error = true;
+                    return Some(Err(err));
+                }
+                Some(Ok(Data {
+                    tracked: self
+                        .memory_gauge
+                        .as_ref()
+                        .map(|gauge| gauge.track_request(bytes.len())),
+                    bytes,
+                }))
+            }
+            Some(Err(err)) => Some(Err(self.recv_error(err))),
+            None => {
+                self.recv_state = RecvState::Trailers;
+                let result = self.check_received();
+                *self.protocol_error |= result.is_err();
+                result.err().map(Err)
+            }
+        }
+    }
+
+    /// Records whether an error on receiving the request has been caused
+    /// by the client violating the protocol.
+    fn recv_error(&mut self, err: h2::Error) -> Error {
+        if err.reason() == Some(Reason::PROTOCOL_ERROR) {
+            *self.protocol_error = true;
+        }
+        err.into()
+    }
+
+    /// Adds the length of a received chunk and checks that
+    /// the total does not exceed `Content-Length`.
+    fn count_received(&mut self, len: usize) -> Result<(), Error> {
+        self.received += len as u64;
+        match self.content_length {
+            Some(expected) if self.received > expected => {
+                Err(Error::incomplete_body(expected, self.received))
             }
+            _ => Ok(()),
         }
-        data.map(|res| res.map(Data))
     }
 
-    pub async fn trailers(&mut self) -> Result<Option<HeaderMap>, h2::Error> {
-        self.receiver.trailers().await
+    /// Applies the header finalizers registered to the server.
+    fn finalize_headers(&self, response: &mut Response<()>) {
+        if let Some(ref head) = self.request_head {
+            self.header_finalizers.apply(head, response);
+        }
+    }
+
+    /// Checks that the total length of the request body has reached
+    /// `Content-Length` at the end of stream.
+    fn check_received(&self) -> Result<(), Error> {
+        match self.content_length {
+            Some(expected) if self.received < expected => {
+                Err(Error::incomplete_body(expected, self.received))
+            }
+            _ => Ok(()),
+        }
     }
 
-    pub async fn send_response<T>(&mut self, response: Response<T>) -> Result<(), h2::Error>
+    pub async fn trailers(&mut self) -> Result<Option<HeaderMap>, Error> {
+        match self.recv_state {
+            RecvState::Data => return Err(Error::body_not_consumed()),
+            RecvState::Trailers => {}
+            RecvState::Done => return Ok(None),
+        }
+        let trailers = match self.body_idle_timeout {
+            Some(timeout) => Timeout::new(self.receiver.trailers(), timeout)
+                .await
+                .map_err(|_| Error::body_timed_out())?,
+            None => self.receiver.trailers().await,
+        };
+        let trailers = trailers.map_err(|err| self.recv_error(err))?;
+        self.recv_state = RecvState::Done;
+        Ok(trailers)
+    }
+
+    /// Sends a complete response whose body is held in memory.
+    ///
+    /// This is the same as `izanami::Events::send_response`.
+    pub async fn send_response<T>(&mut self, response: Response<T>) -> Result<(), Error>
     where
-        T: Into<Data>,
+        T: Into<Data> + Send,
     {
-        let (parts, body) = response.into_parts();
-        let response = Response::from_parts(parts, ());
-        self.start_send_response(response, false).await?;
-        self.send_data(body.into(), true).await?;
-        Ok(())
+        izanami::Events::send_response(self, response).await
     }
 
+    /// Sends the response header to the client.
+    ///
+    /// If the response must not have a body (i.e. the request method is
+    /// `HEAD` or the status is `204` or `304`), the stream is closed
+    /// immediately and the data passed to `send_data` and `send_trailers`
+    /// are discarded. `Transfer-Encoding` is removed, and so is `Content-Length`
+    /// for `204` and `304` responses.
+    ///
+    /// It returns an error if the response header has already been sent.
     pub async fn start_send_response(
         &mut self,
-        response: Response<()>,
+        mut response: Response<()>,
         end_of_stream: bool,
-    ) -> Result<(), h2::Error> {
-        let stream = self.sender.send_response(response, end_of_stream)?;
+    ) -> Result<(), Error> {
+        if self.stream.is_some() {
+            return Err(Error::invalid_response_state(
+                InvalidResponseState::already_started(),
+            ));
+        }
+
+        self.finalize_headers(&mut response);
+        self.discard_body = if limit_header_size(&mut response, self.max_response_header_bytes) {
+            self.finalize_headers(&mut response);
+            !end_of_stream
+        } else if !can_have_body(self.is_head, response.status()) {
+            strip_body_headers(&mut response);
+            !end_of_stream
+        } else {
+            false
+        };
+        let stream = self
+            .sender
+            .send_response(response, end_of_stream || self.discard_body)?;
         self.stream.replace(stream);
+        self.send_finished = end_of_stream;
         Ok(())
     }
 
-    pub async fn send_data<T>(&mut self, data: T, end_of_stream: bool) -> Result<(), h2::Error>
+    /// Returns the stream of the response body, or an error if the response
+    /// header has not been sent or the response has already been completed.
+    fn send_stream(&mut self) -> Result<&mut SendStream<Data>, Error> {
+        match self.stream.as_mut() {
+            Some(..) if self.send_finished => Err(Error::invalid_response_state(
+                InvalidResponseState::finished(),
+            )),
+            Some(stream) => Ok(stream),
+            None => Err(Error::invalid_response_state(
+                InvalidResponseState::not_started(),
+            )),
+        }
+    }
+
+    /// Waits until the flow control window of the stream has room for
+    /// the next chunk of the response body.
+    ///
+    /// It returns immediately if the response header has not been sent
+    /// or the response has already been completed.
+    pub async fn ready(&mut self) -> Result<(), Error> {
+        if self.discard_body || self.send_finished {
+            return Ok(());
+        }
+        match self.stream.as_mut() {
+            Some(stream) => {
+                if stream.capacity() == 0 {
+                    stream.reserve_capacity(SUGGESTED_CHUNK_SIZE);
+                }
+                wait_capacity(stream).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a chunk of the response body.
+    ///
+    /// The chunk is passed to the connection once the flow control window
+    /// of the stream has some room, so at most one chunk beyond the window
+    /// is buffered for a client that reads slowly. It returns an error if
+    /// the response header has not been sent or the response has already
+    /// been completed.
+    pub async fn send_data<T>(&mut self, data: T, end_of_stream: bool) -> Result<(), Error>
     where
         T: Into<Data>,
     {
-        let stream = self.stream.as_mut().unwrap();
-        let data = data.into();
+        if self.discard_body {
+            return Ok(());
+        }
 
-        stream.reserve_capacity(data.remaining());
-        poll_fn(|cx| stream.poll_capacity(cx)).await.transpose()?;
+        let mut data = data.into();
+        if let Some(ref gauge) = self.memory_gauge {
+            data.tracked = Some(gauge.track_response(data.remaining()));
+        }
+
+        let stream = self.send_stream()?;
+
+        if data.has_remaining() {
+            stream.reserve_capacity(data.remaining());
+            wait_capacity(stream).await?;
+        }
         stream.send_data(data, end_of_stream)?;
+        self.send_finished = end_of_stream;
 
         Ok(())
     }
 
-    pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), h2::Error> {
-        let stream = self.stream.as_mut().unwrap();
-        stream.send_trailers(trailers)
+    /// Sends the trailers and completes the response.
+    ///
+    /// It returns an error if the response header has not been sent or
+    /// the response has already been completed.
+    pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Error> {
+        if self.discard_body {
+            return Ok(());
+        }
+
+        self.send_stream()?.send_trailers(trailers)?;
+        self.send_finished = true;
+        Ok(())
+    }
+
+    /// Sends the trailers carrying the gRPC status, created by `grpc::trailers`.
+    pub async fn send_grpc_trailers(
+        &mut self,
+        status: u32,
+        message: Option<&str>,
+    ) -> Result<(), Error> {
+        self.send_trailers(grpc::trailers(status, message)).await
+    }
+
+    /// Resets the stream with the specified error code (`RST_STREAM`).
+    ///
+    /// If the response header has not been sent yet, the stream is reset
+    /// without sending it.
+    pub fn send_reset(&mut self, reason: h2::Reason) {
+        *self.reset_sent = true;
+        match self.stream.as_mut() {
+            Some(stream) => stream.send_reset(reason),
+            None => self.sender.send_reset(reason),
+        }
     }
 }
 
+/// Waits until some capacity is assigned to the stream.
+///
+/// `poll_capacity` only reports the increase of the capacity, so the capacity
+/// that has already been assigned is checked first.
+async fn wait_capacity(stream: &mut SendStream<Data>) -> Result<(), Error> {
+    while stream.capacity() == 0 {
+        match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(Ok(..)) => {}
+            Some(Err(err)) => return Err(err.into()),
+            // The stream has been closed, and sending the data reports the error.
+            None => break,
+        }
+    }
+    Ok(())
+}
+
 #[async_trait]
 #[allow(clippy::needless_lifetimes)]
 impl<'a> izanami::Events for Events<'a> {
     type Data = Data;
-    type Error = h2::Error;
+    type Error = Error;
+
+    #[inline]
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+
+    #[inline]
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr()
+    }
 
     #[inline]
     async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
@@ -198,30 +1346,109 @@ impl<'a> izanami::Events for Events<'a> {
     async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
         self.send_trailers(trailers).await
     }
+
+    #[inline]
+    async fn ready(&mut self) -> Result<(), Self::Error> {
+        self.ready().await
+    }
 }
 
+/// The error type returned from `Events`.
+pub type Error = EventsError<h2::Error>;
+
 #[derive(Debug)]
-pub struct Data(Bytes);
+pub struct Data {
+    bytes: Bytes,
+    tracked: Option<GaugeGuard>,
+}
+
+impl Data {
+    fn from_bytes(bytes: Bytes) -> Self {
+        Self {
+            bytes,
+            tracked: None,
+        }
+    }
+
+    /// Returns the number of bytes in this chunk.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns whether this chunk is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Consumes this chunk and returns the underlying `Bytes` without copying.
+    pub fn into_bytes(self) -> Bytes {
+        self.bytes
+    }
+}
+
+impl AsRef<[u8]> for Data {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes.as_ref()
+    }
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.bytes.as_ref()
+    }
+}
+
+impl From<Bytes> for Data {
+    fn from(bytes: Bytes) -> Self {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl From<&'static [u8]> for Data {
+    fn from(bytes: &'static [u8]) -> Self {
+        Self::from_bytes(Bytes::from_static(bytes))
+    }
+}
+
+impl From<&'static str> for Data {
+    fn from(s: &'static str) -> Self {
+        Self::from_bytes(Bytes::from_static(s.as_bytes()))
+    }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(vec: Vec<u8>) -> Self {
+        Self::from_bytes(vec.into())
+    }
+}
+
+impl From<String> for Data {
+    fn from(s: String) -> Self {
+        Self::from_bytes(s.into())
+    }
+}
 
-impl<T: Into<Bytes>> From<T> for Data {
-    fn from(bytes: T) -> Self {
-        Self(bytes.into())
+impl From<Data> for Bytes {
+    fn from(data: Data) -> Self {
+        data.bytes
     }
 }
 
 impl Buf for Data {
     #[inline]
     fn remaining(&self) -> usize {
-        self.0.len()
+        self.bytes.len()
     }
 
     #[inline]
     fn bytes(&self) -> &[u8] {
-        self.0.as_ref()
+        self.bytes.as_ref()
     }
 
     #[inline]
     fn advance(&mut self, amt: usize) {
-        self.0.advance(amt);
+        self.bytes.advance(amt);
     }
 }
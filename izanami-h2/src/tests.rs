@@ -0,0 +1,992 @@
+use super::*;
+use futures::future::BoxFuture;
+use h2::client::{self, SendRequest};
+use http::{
+    header::{HeaderValue, CONTENT_LENGTH},
+    response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::{sync::oneshot, timer::delay_for};
+
+type Handler = for<'a, 'b> fn(&'b mut Events<'a>) -> BoxFuture<'b, Result<(), Error>>;
+
+#[derive(Clone)]
+struct TestApp(Handler);
+
+#[async_trait]
+impl<'a> App<Events<'a>> for TestApp {
+    type Error = Error;
+
+    async fn call(&self, req: Request<Events<'a>>) -> Result<(), Self::Error> {
+        let mut events = req.into_body();
+        (self.0)(&mut events).await
+    }
+}
+
+/// Starts a server running `handler` and connects to it.
+async fn connect(handler: Handler) -> SendRequest<Bytes> {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    connect_with(server, handler).await.0
+}
+
+/// Starts the server running `handler` and connects to it.
+///
+/// It also returns a receiver notified when the connection is closed.
+async fn connect_with(
+    server: Server,
+    handler: Handler,
+) -> (SendRequest<Bytes>, oneshot::Receiver<Result<(), h2::Error>>) {
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, conn) = client::handshake(stream).await.unwrap();
+    let (closed_tx, closed_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = closed_tx.send(conn.await);
+    });
+    (client, closed_rx)
+}
+
+/// Sends a request without body, and returns the head, the body and
+/// the trailers of the response.
+async fn send(
+    client: SendRequest<Bytes>,
+    method: Method,
+) -> Result<(response::Parts, Vec<u8>, Option<HeaderMap>), h2::Error> {
+    let mut client = client.ready().await?;
+    let request = Request::builder()
+        .method(method)
+        .uri("http://localhost/")
+        .body(())
+        .unwrap();
+    let (response, _) = client.send_request(request, true)?;
+    let (parts, mut body) = response.await?.into_parts();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk?);
+    }
+    let trailers = body.trailers().await?;
+    Ok((parts, data, trailers))
+}
+
+fn response_with_length(status: StatusCode) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+    response
+}
+
+#[tokio::test]
+async fn head_keeps_content_length_without_body() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::OK), false)
+                .await?;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let (parts, body, _) = send(client, Method::HEAD).await.unwrap();
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(parts.headers[CONTENT_LENGTH], "5");
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn no_content_drops_content_length_with_end_of_stream() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::NO_CONTENT), true)
+                .await
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let (parts, body, _) = send(client, Method::GET).await.unwrap();
+    assert_eq!(parts.status, StatusCode::NO_CONTENT);
+    assert!(!parts.headers.contains_key(CONTENT_LENGTH));
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn no_content_drops_content_length_and_body() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::NO_CONTENT), false)
+                .await?;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let (parts, body, _) = send(client, Method::GET).await.unwrap();
+    assert_eq!(parts.status, StatusCode::NO_CONTENT);
+    assert!(!parts.headers.contains_key(CONTENT_LENGTH));
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn not_modified_drops_content_length_and_body() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::NOT_MODIFIED), false)
+                .await?;
+            events.send_data("hello", false).await?;
+            events.send_trailers(HeaderMap::new()).await
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let (parts, body, trailers) = send(client, Method::GET).await.unwrap();
+    assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+    assert!(!parts.headers.contains_key(CONTENT_LENGTH));
+    assert!(body.is_empty());
+    assert!(trailers.is_none());
+}
+
+#[tokio::test]
+async fn graceful_shutdown_completes_in_flight_requests() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events.start_send_response(Response::new(()), false).await?;
+            delay_for(Duration::from_millis(100)).await;
+            events.send_data("done", true).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    let (signal_tx, signal_rx) = oneshot::channel::<()>();
+    let (done_tx, done_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let signal = async move {
+            let _ = signal_rx.await;
+        };
+        server
+            .serve_with_shutdown(TestApp(handler), signal)
+            .await
+            .unwrap();
+        let _ = done_tx.send(());
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, conn) = client::handshake(stream).await.unwrap();
+    let (conn_tx, conn_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = conn_tx.send(conn.await);
+    });
+
+    let mut client = client.ready().await.unwrap();
+    let request = Request::get("http://localhost/").body(()).unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let mut body = response.await.unwrap().into_body();
+
+    signal_tx.send(()).unwrap();
+    assert_eq!(body.data().await.unwrap().unwrap(), "done");
+    assert!(body.data().await.is_none());
+
+    Timeout::new(done_rx, Duration::from_secs(5))
+        .await
+        .expect("the server did not shut down")
+        .unwrap();
+    conn_rx.await.unwrap().unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+/// Sends a request with the body and `Content-Length`, and returns the response body.
+async fn post(
+    client: SendRequest<Bytes>,
+    content_length: usize,
+    body: &'static str,
+) -> Result<Vec<u8>, h2::Error> {
+    let mut client = client.ready().await?;
+    let request = Request::post("http://localhost/")
+        .header(CONTENT_LENGTH, content_length)
+        .body(())
+        .unwrap();
+    let (response, mut request_body) = client.send_request(request, false)?;
+    request_body.send_data(Bytes::from_static(body.as_bytes()), true)?;
+    let mut response_body = response.await?.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = response_body.data().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+#[tokio::test]
+async fn connection_is_closed_after_too_many_protocol_errors() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            let mut len = 0;
+            while let Some(chunk) = events.data().await {
+                len += chunk?.len();
+            }
+            events.send_response(Response::new(len.to_string())).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_protocol_errors_per_connection(2);
+    let (client, closed) = connect_with(server, handler).await;
+
+    // The valid requests in between do not count.
+    for _ in 0..2 {
+        assert!(post(client.clone(), 10, "hello").await.is_err());
+        assert_eq!(post(client.clone(), 5, "hello").await.unwrap(), b"5");
+    }
+
+    assert!(post(client.clone(), 10, "hello").await.is_err());
+    Timeout::new(closed, Duration::from_secs(5))
+        .await
+        .expect("the connection was not closed")
+        .unwrap()
+        .unwrap();
+    assert!(post(client, 5, "hello").await.is_err());
+}
+
+#[test]
+fn protocol_errors_are_counted_separately_from_resets() {
+    let mut counter = ResetCounter::new(ResetLimit {
+        per_minute: None,
+        total: Some(1),
+        protocol_errors: Some(2),
+        on_exceeded: None,
+    });
+    let protocol_error = StreamOutcome::reset_with(Reason::PROTOCOL_ERROR);
+    assert!(protocol_error.protocol_error && !protocol_error.reset);
+    let cancel = StreamOutcome::reset_with(Reason::CANCEL);
+    assert!(cancel.reset && !cancel.protocol_error);
+
+    assert!(!counter.record_protocol_error());
+    assert!(!counter.record_protocol_error());
+    assert!(!counter.record());
+    assert!(counter.record_protocol_error());
+    // The limit is reported only once.
+    assert!(!counter.record_protocol_error());
+    assert!(counter.record());
+}
+
+#[tokio::test]
+async fn misordered_response_calls_return_errors() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            let err = events.send_data("data", false).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            let err = events.send_trailers(HeaderMap::new()).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+
+            events.start_send_response(Response::new(()), false).await?;
+            let err = events
+                .start_send_response(Response::new(()), true)
+                .await
+                .unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+
+            events.send_data("ok", true).await?;
+            let err = events.send_data("data", true).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            let err = events.send_trailers(HeaderMap::new()).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    // The connection is still usable after the misuses.
+    for _ in 0..2 {
+        let (parts, body, trailers) = send(client.clone(), Method::GET).await.unwrap();
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, b"ok");
+        assert!(trailers.is_none());
+    }
+}
+
+#[tokio::test]
+async fn stalled_request_body_times_out() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            let err = events.data().await.unwrap().unwrap_err();
+            assert!(err.is_body_timed_out(), "{}", err);
+            let mut response = Response::new("timed out");
+            *response.status_mut() = StatusCode::REQUEST_TIMEOUT;
+            events.send_response(response).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .request_body_idle_timeout(Duration::from_millis(100));
+    let (client, _) = connect_with(server, handler).await;
+
+    let mut client = client.ready().await.unwrap();
+    let request = Request::post("http://localhost/").body(()).unwrap();
+    // Keep the request body open without sending anything.
+    let (response, _request_body) = client.send_request(request, false).unwrap();
+    let (parts, mut body) = Timeout::new(response, Duration::from_secs(5))
+        .await
+        .expect("the request body did not time out")
+        .unwrap()
+        .into_parts();
+    assert_eq!(parts.status, StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(body.data().await.unwrap().unwrap(), "timed out");
+}
+
+#[tokio::test]
+async fn truncated_request_body_does_not_end_cleanly() {
+    // 1: the body ended with an error, 2: the body ended cleanly.
+    static OBSERVED: AtomicUsize = AtomicUsize::new(0);
+
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            // Let the client know that the request has been accepted.
+            events.start_send_response(Response::new(()), false).await?;
+            loop {
+                match events.data().await {
+                    Some(Ok(..)) => {}
+                    Some(Err(..)) => break OBSERVED.store(1, Ordering::SeqCst),
+                    None => break OBSERVED.store(2, Ordering::SeqCst),
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // h2 resets the stream by itself without waking the receiver,
+    // so the body idle timeout is needed to bound the wait.
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .request_body_idle_timeout(Duration::from_millis(100));
+    let (client, _) = connect_with(server, handler).await;
+    let mut client = client.ready().await.unwrap();
+    let request = Request::post("http://localhost/")
+        .header(CONTENT_LENGTH, 10)
+        .body(())
+        .unwrap();
+    let (response, mut request_body) = client.send_request(request, false).unwrap();
+    let _response = response.await.unwrap();
+    request_body
+        .send_data(Bytes::from_static(b"hello"), true)
+        .unwrap();
+
+    Timeout::new(
+        async {
+            while OBSERVED.load(Ordering::SeqCst) == 0 {
+                delay_for(Duration::from_millis(10)).await;
+            }
+        },
+        Duration::from_secs(5),
+    )
+    .await
+    .expect("the request body did not end");
+    assert_eq!(OBSERVED.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn request_body_without_content_length_is_not_checked() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            let mut len = 0;
+            while let Some(chunk) = events.data().await {
+                len += chunk?.len();
+            }
+            events.send_response(Response::new(len.to_string())).await
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let mut client = client.ready().await.unwrap();
+    let request = Request::post("http://localhost/").body(()).unwrap();
+    let (response, mut request_body) = client.send_request(request, false).unwrap();
+    request_body
+        .send_data(Bytes::from_static(b"hello"), false)
+        .unwrap();
+    request_body
+        .send_data(Bytes::from_static(b"world"), true)
+        .unwrap();
+    let mut body = response.await.unwrap().into_body();
+    assert_eq!(body.data().await.unwrap().unwrap(), "10");
+}
+
+#[tokio::test]
+async fn oversized_response_header_is_replaced_with_500() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            let response = Response::builder()
+                .header("x-large", "a".repeat(100))
+                .body(())
+                .unwrap();
+            events.start_send_response(response, false).await?;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_response_header_bytes(64);
+    let (client, _) = connect_with(server, handler).await;
+    let (parts, body, _) = send(client, Method::GET).await.unwrap();
+    assert_eq!(parts.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(parts.headers[CONTENT_LENGTH], "0");
+    assert!(!parts.headers.contains_key("x-large"));
+    assert!(body.is_empty());
+}
+
+/// Echoes the gRPC messages in the request body, or responds with
+/// `RESOURCE_EXHAUSTED` if one of them is too large.
+fn grpc_echo<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+    async move {
+        let mut buf = bytes::BytesMut::new();
+        while let Some(chunk) = events.data().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        let response = Response::builder()
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+        events.start_send_response(response, false).await?;
+        for frame in grpc::decode_frames(buf.freeze()) {
+            match frame {
+                Ok(frame) => {
+                    let frame = grpc::encode_frame(frame.flags, &frame.payload);
+                    events.send_data(frame, false).await?;
+                }
+                Err(err) => return events.send_grpc_trailers(8, Some(&err.to_string())).await,
+            }
+        }
+        events.send_grpc_trailers(0, None).await
+    }
+    .boxed()
+}
+
+/// Sends a gRPC request with the body, and returns the head, the body and
+/// the trailers of the response.
+async fn grpc_call(
+    client: SendRequest<Bytes>,
+    body: Bytes,
+) -> Result<(response::Parts, Vec<u8>, Option<HeaderMap>), h2::Error> {
+    let mut client = client.ready().await?;
+    let request = Request::post("http://localhost/echo.Echo/Echo")
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())
+        .unwrap();
+    let (response, mut request_body) = client.send_request(request, false)?;
+    request_body.send_data(body, true)?;
+    let (parts, mut body) = response.await?.into_parts();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk?);
+    }
+    let trailers = body.trailers().await?;
+    Ok((parts, data, trailers))
+}
+
+#[tokio::test]
+async fn grpc_messages_are_echoed_with_trailers() {
+    let client = connect(grpc_echo).await;
+    let mut body = bytes::BytesMut::new();
+    body.extend_from_slice(&grpc::encode_frame(0, b"ping"));
+    body.extend_from_slice(&grpc::encode_frame(grpc::FLAG_COMPRESSED, b"pong"));
+    let (parts, data, trailers) = grpc_call(client, body.freeze()).await.unwrap();
+
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(parts.headers["content-type"], "application/grpc");
+    assert_eq!(
+        data,
+        &b"\x00\x00\x00\x00\x04ping\x01\x00\x00\x00\x04pong"[..]
+    );
+    let trailers = trailers.expect("missing trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+    assert!(!trailers.contains_key("grpc-message"));
+}
+
+#[tokio::test]
+async fn grpc_message_over_the_limit_is_rejected() {
+    let client = connect(grpc_echo).await;
+    let body = Bytes::from_static(b"\x00\xff\xff\xff\xff");
+    let (_, data, trailers) = grpc_call(client, body).await.unwrap();
+
+    assert!(data.is_empty());
+    let trailers = trailers.expect("missing trailers");
+    assert_eq!(trailers["grpc-status"], "8");
+    assert_eq!(
+        trailers["grpc-message"],
+        "the message has 4294967295 bytes while the maximum is 4194304"
+    );
+}
+
+#[tokio::test]
+async fn reset_before_the_response_is_seen_by_the_client() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events.send_reset(Reason::REFUSED_STREAM);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let err = send(client, Method::GET).await.unwrap_err();
+    assert_eq!(err.reason(), Some(Reason::REFUSED_STREAM));
+}
+
+#[tokio::test]
+async fn reset_in_the_middle_of_the_body_is_seen_by_the_client() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("hel", false).await?;
+            events.send_reset(Reason::INTERNAL_ERROR);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    let client = connect(handler).await;
+    let err = send(client, Method::GET).await.unwrap_err();
+    assert_eq!(err.reason(), Some(Reason::INTERNAL_ERROR));
+}
+
+#[tokio::test]
+async fn rapid_resets_close_only_the_abusive_connection() {
+    /// Waits for the request body, which the abusive client never sends.
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            while let Some(chunk) = events.data().await {
+                chunk?;
+            }
+            events.send_response(Response::new("ok")).await
+        }
+        .boxed()
+    }
+
+    static EXCEEDED: AtomicUsize = AtomicUsize::new(0);
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_resets_per_minute(5)
+        .on_limit_exceeded(|exceeded| {
+            assert_eq!(exceeded, LimitExceeded::Resets);
+            EXCEEDED.fetch_add(1, Ordering::SeqCst);
+        });
+    let addr = server.local_addrs()[0];
+    let (abusive, mut closed) = connect_with(server, handler).await;
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (well_behaved, conn) = client::handshake(stream).await.unwrap();
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let mut sent = 0;
+    let err = loop {
+        let mut client = match abusive.clone().ready().await {
+            Ok(client) => client,
+            Err(err) => break err,
+        };
+        let request = Request::post("http://localhost/").body(()).unwrap();
+        let (_, mut body) = match client.send_request(request, false) {
+            Ok(sent) => sent,
+            Err(err) => break err,
+        };
+        // The stream is reset after the HEADERS have been flushed, since h2
+        // sends the reset of a stream still queued before its HEADERS.
+        delay_for(Duration::from_millis(5)).await;
+        body.send_reset(Reason::CANCEL);
+        sent += 1;
+        assert!(sent < 100, "the connection was not closed");
+        // Let the server observe the reset before opening the next stream.
+        delay_for(Duration::from_millis(10)).await;
+        if let std::task::Poll::Ready(result) = futures::poll!(&mut closed) {
+            break result.unwrap().unwrap_err();
+        }
+    };
+    assert_eq!(err.reason(), Some(Reason::ENHANCE_YOUR_CALM), "{}", err);
+    assert!(sent > 5 && sent <= 8, "{} streams were reset", sent);
+    assert_eq!(EXCEEDED.load(Ordering::SeqCst), 1);
+
+    // The concurrent connection is left alone.
+    assert_eq!(post(well_behaved, 5, "hello").await.unwrap(), b"ok");
+}
+
+/// Sends the client preface and an empty SETTINGS frame over a raw connection.
+async fn raw_handshake(addr: SocketAddr) -> TcpStream {
+    use tokio::io::AsyncWriteExt;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+        .await
+        .unwrap();
+    stream
+}
+
+/// Reads a frame from a raw connection, and returns its type, flags,
+/// stream identifier and payload.
+async fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    use tokio::io::AsyncReadExt;
+
+    let mut head = [0; 9];
+    stream.read_exact(&mut head).await.unwrap();
+    let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+    let stream_id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await.unwrap();
+    (head[3], head[4], stream_id, payload)
+}
+
+#[tokio::test]
+async fn client_sees_the_configured_settings() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move { events.start_send_response(Response::new(()), true).await }.boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .initial_window_size(100_000)
+        .initial_connection_window_size(1_000_000)
+        .max_frame_size(32_768)
+        .max_concurrent_streams(7)
+        .max_header_list_size(4096);
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let mut stream = raw_handshake(addr).await;
+    let mut settings = None;
+    let mut window_update = None;
+    while settings.is_none() || window_update.is_none() {
+        match read_frame(&mut stream).await {
+            // SETTINGS without the ACK flag.
+            (0x4, flags, 0, payload) if flags & 0x1 == 0 => {
+                let params: HashMap<u16, u32> = payload
+                    .chunks(6)
+                    .map(|p| {
+                        let id = u16::from_be_bytes([p[0], p[1]]);
+                        (id, u32::from_be_bytes([p[2], p[3], p[4], p[5]]))
+                    })
+                    .collect();
+                settings = Some(params);
+            }
+            // WINDOW_UPDATE on the connection.
+            (0x8, _, 0, payload) => {
+                let increment =
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                window_update = Some(increment & 0x7fff_ffff);
+            }
+            _ => {}
+        }
+    }
+
+    let settings = settings.unwrap();
+    assert_eq!(settings.get(&0x3), Some(&7)); // MAX_CONCURRENT_STREAMS
+    assert_eq!(settings.get(&0x4), Some(&100_000)); // INITIAL_WINDOW_SIZE
+    assert_eq!(settings.get(&0x5), Some(&32_768)); // MAX_FRAME_SIZE
+    assert_eq!(settings.get(&0x6), Some(&4096)); // MAX_HEADER_LIST_SIZE
+
+    // The connection window starts at 65,535 bytes and is raised with a WINDOW_UPDATE.
+    assert_eq!(window_update, Some(1_000_000 - 65_535));
+}
+
+#[tokio::test]
+async fn connection_cap_queues_the_third_connection() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move { events.start_send_response(Response::new(()), true).await }.boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_connections(2);
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    // Hold two connections open; the server answers both with its SETTINGS.
+    let mut first = raw_handshake(addr).await;
+    let mut second = raw_handshake(addr).await;
+    assert_eq!(read_frame(&mut first).await.0, 0x4);
+    assert_eq!(read_frame(&mut second).await.0, 0x4);
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, conn) = client::handshake(stream).await.unwrap();
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let mut response = send(client, Method::GET).boxed();
+    let queued = Timeout::new(&mut response, Duration::from_millis(200)).await;
+    assert!(queued.is_err(), "the third connection was served");
+
+    // Closing one of the held connections frees a slot for the third one.
+    drop(first);
+    let (parts, _, _) = Timeout::new(response, Duration::from_secs(5))
+        .await
+        .expect("the third connection was not served after a slot was freed")
+        .unwrap();
+    assert_eq!(parts.status, StatusCode::OK);
+    drop(second);
+}
+
+#[tokio::test]
+async fn connection_limit_accepts_the_second_connection_after_the_first_closes() {
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move { events.start_send_response(Response::new(()), true).await }.boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_connections(1);
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (first, conn) = client::handshake(stream).await.unwrap();
+    let (conn, first_handle) = future::abortable(conn);
+    tokio::spawn(conn.map(|_| ()));
+    let (parts, _, _) = send(first.clone(), Method::GET).await.unwrap();
+    assert_eq!(parts.status, StatusCode::OK);
+
+    // The first connection is kept alive, so the second one is not accepted yet.
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (second, conn) = client::handshake(stream).await.unwrap();
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let mut response = send(second, Method::GET).boxed();
+    let pending = Timeout::new(&mut response, Duration::from_millis(200)).await;
+    assert!(pending.is_err(), "the second connection was served");
+
+    // Aborting the task closes the first connection.
+    first_handle.abort();
+    drop(first);
+    let (parts, _, _) = Timeout::new(response, Duration::from_secs(5))
+        .await
+        .expect("the second connection was not served after the first closed")
+        .unwrap();
+    assert_eq!(parts.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn killed_client_fails_send_data_and_drains_the_requests() {
+    static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static SEND_FAILED: AtomicUsize = AtomicUsize::new(0);
+
+    struct InFlight;
+
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Streams the response until the client stops reading it.
+    fn handler<'b>(events: &'b mut Events<'_>) -> BoxFuture<'b, Result<(), Error>> {
+        async move {
+            IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+            let _guard = InFlight;
+            events.start_send_response(Response::new(()), false).await?;
+            loop {
+                if let Err(err) = events.send_data(vec![0; 16 * 1024], false).await {
+                    SEND_FAILED.fetch_add(1, Ordering::SeqCst);
+                    return Err(err);
+                }
+            }
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, conn) = client::handshake(stream).await.unwrap();
+    let (conn, conn_handle) = future::abortable(conn);
+    tokio::spawn(conn.map(|_| ()));
+    let mut client = client.ready().await.unwrap();
+    let request = Request::get("http://localhost/").body(()).unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Let the handler fill the flow control window, then kill the client.
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(IN_FLIGHT.load(Ordering::SeqCst), 1);
+    assert_eq!(SEND_FAILED.load(Ordering::SeqCst), 0);
+    conn_handle.abort();
+    drop((client, response));
+
+    let drained = async {
+        while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+            delay_for(Duration::from_millis(10)).await;
+        }
+    };
+    Timeout::new(drained, Duration::from_secs(1))
+        .await
+        .expect("the request was not drained after the client was killed");
+    assert_eq!(SEND_FAILED.load(Ordering::SeqCst), 1);
+}
+
+/// An application that responds with a description of the request extensions.
+#[derive(Clone)]
+struct Describe(fn(&http::Extensions) -> String);
+
+#[async_trait]
+impl<'a> App<Events<'a>> for Describe {
+    type Error = Error;
+
+    async fn call(&self, req: Request<Events<'a>>) -> Result<(), Self::Error> {
+        let description = (self.0)(req.extensions());
+        let mut events = req.into_body();
+        events.send_response(Response::new(description)).await
+    }
+}
+
+/// Starts a server running `app`, and returns its address.
+fn serve_app<T>(server: Server, app: T) -> SocketAddr
+where
+    T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+{
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(app).await.unwrap();
+    });
+    addr
+}
+
+/// Connects to the server, and returns the client and its local address.
+async fn connect_to(addr: SocketAddr) -> (SendRequest<Bytes>, SocketAddr) {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let local_addr = stream.local_addr().unwrap();
+    let (client, conn) = client::handshake(stream).await.unwrap();
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    (client, local_addr)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeerPort(u16);
+
+#[tokio::test]
+async fn request_extension_reaches_every_request_on_the_connection() {
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .with_request_extension(|addr| {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+            PeerPort(addr.port())
+        });
+    let addr = serve_app(
+        server,
+        Describe(|extensions| format!("{:?}", extensions.get::<PeerPort>())),
+    );
+
+    let (client, local_addr) = connect_to(addr).await;
+    let expected = format!("{:?}", Some(PeerPort(local_addr.port())));
+    for _ in 0..2 {
+        let (_, body, _) = send(client.clone(), Method::GET).await.unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+    }
+    assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn failed_request_extension_closes_the_connection() {
+    use tokio::io::AsyncReadExt;
+
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .try_with_request_extension(|_| {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+            Err::<PeerPort, _>("rejected")
+        });
+    let addr = serve_app(server, Describe(|_| panic!("the request was handled")));
+
+    // The connection is closed before the server sends its SETTINGS.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut received = vec![];
+    Timeout::new(stream.read_to_end(&mut received), Duration::from_secs(1))
+        .await
+        .expect("the connection was not closed")
+        .unwrap();
+    assert!(received.is_empty(), "{:?}", received);
+    assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn requests_on_a_connection_share_the_connection_id() {
+    fn describe(extensions: &http::Extensions) -> String {
+        format!(
+            "{:?} {:?} {:?}",
+            extensions.get::<ConnectionId>(),
+            extensions.get::<RequestSequence>(),
+            extensions.get::<Protocol>(),
+        )
+    }
+
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = serve_app(server, Describe(describe));
+
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let (client, _) = connect_to(addr).await;
+        let mut descriptions = vec![];
+        for _ in 0..2 {
+            let (_, body, _) = send(client.clone(), Method::GET).await.unwrap();
+            descriptions.push(String::from_utf8(body).unwrap());
+        }
+        let id = descriptions[0].split(' ').next().unwrap().to_owned();
+        let protocol = format!("{:?}", Some(Protocol::Http2 { is_tls: false }));
+        assert_eq!(
+            descriptions,
+            [
+                format!("{} {:?} {}", id, Some(RequestSequence(1)), protocol),
+                format!("{} {:?} {}", id, Some(RequestSequence(2)), protocol),
+            ]
+        );
+        ids.push(id);
+    }
+    assert_ne!(ids[0], ids[1]);
+}
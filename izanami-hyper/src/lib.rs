@@ -1,125 +1,1276 @@
 use async_trait::async_trait;
 use futures::{
-    future::{poll_fn, Future},
+    future::{self, poll_fn, Either, Future, FutureExt},
     task::{self, Poll},
 };
-use http::{HeaderMap, Request, Response, StatusCode};
-use http_body::Body as _Body;
+use http::{
+    header::{HeaderValue, CONNECTION, CONTENT_LENGTH, UPGRADE},
+    HeaderMap, Method, Request, Response, StatusCode, Version,
+};
+use http_body::{Body as _Body, SizeHint};
 use hyper::{
     body::{Body, Chunk, Sender as BodySender},
-    server::{conn::AddrIncoming, Builder as ServerBuilder, Server as HyperServer},
-    upgrade::Upgraded,
+    server::{accept::Accept, Server as HyperServer},
+};
+use izanami::{
+    cancel::{CancelToken, Cancelled},
+    conn::{
+        ConnectionExtensions, ConnectionId, Protocol, RequestExtensions, RequestSequence,
+        SuggestedChunkSize,
+    },
+    finalize::{HeaderFinalizers, InvalidResponseState},
+    App,
+};
+use izanami_net::{
+    can_have_body, content_length, limit_header_size, panic_message, request_head,
+    strip_body_headers, ConnectionGuard, ConnectionLimit, EventsError, GaugeGuard, Incoming,
+    RateLimit, TaskTracker,
+};
+use std::{
+    error, fmt, io,
+    marker::PhantomData,
+    net::{SocketAddr, ToSocketAddrs},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Waker,
+    time::{Duration, Instant},
+};
+use tokio::{
+    executor::{DefaultExecutor, Executor, SpawnError, TypedExecutor},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::oneshot,
+    timer::{delay_for, Delay, Timeout},
 };
-use izanami::App;
-use std::{marker::PhantomData, net::ToSocketAddrs, pin::Pin};
-use tokio::sync::oneshot;
 use tower_service::Service;
 
+#[cfg(test)]
+mod tests;
+
+pub use hyper::upgrade::Upgraded;
+pub use izanami_net::{BindRetry, MemoryGauge, TcpConfig};
+
+#[cfg(unix)]
+pub use izanami_net::{listen_fd, run_sharded};
+
+const DEFAULT_MAX_CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_RESPONSE_HEADER_BYTES: usize = 64 * 1024;
+
+/// The maximum duration to wait for the request tasks after all the
+/// connections have been closed by a graceful shutdown.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The callback registered by `Server::on_connection_closed`.
+#[derive(Clone)]
+struct ClosedCallback(Arc<dyn Fn(Duration) + Send + Sync + 'static>);
+
+impl fmt::Debug for ClosedCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClosedCallback").finish()
+    }
+}
+
 #[derive(Debug)]
-pub struct Server {
-    builder: ServerBuilder<AddrIncoming>,
+pub struct Server<E = DefaultExecutor> {
+    incoming: Incoming,
+    max_connections: Option<usize>,
+    on_connection_closed: Option<ClosedCallback>,
+    read_header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    body_idle_timeout: Option<Duration>,
+    max_chunk_size: usize,
+    max_response_header_bytes: usize,
+    memory_gauge: Option<MemoryGauge>,
+    strict_content_length: bool,
+    request_extensions: RequestExtensions,
+    header_finalizers: HeaderFinalizers,
+    executor: E,
 }
 
 impl Server {
-    pub async fn bind<A>(addr: A) -> hyper::Result<Self>
+    pub async fn bind<A>(addr: A) -> io::Result<Self>
     where
-        A: ToSocketAddrs,
+        A: ToSocketAddrs + Send + 'static,
     {
-        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        Ok(Self {
-            builder: HyperServer::try_bind(&addr)?,
-        })
+        Self::bind_with(addr, &TcpConfig::default()).await
+    }
+
+    pub async fn bind_with<A>(addr: A, config: &TcpConfig) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        Self::bind_inner(addr, config, None).await
+    }
+
+    /// Creates a server bound to the specified address, retrying while the address is in use.
+    ///
+    /// The host name in `addr` is resolved without blocking the runtime, and
+    /// all of the resolved addresses are bound (see `TcpConfig::bind_all`).
+    pub async fn bind_with_retry<A>(
+        addr: A,
+        config: &TcpConfig,
+        retry: BindRetry,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        Self::bind_inner(addr, config, Some(retry)).await
+    }
+
+    async fn bind_inner<A>(
+        addr: A,
+        config: &TcpConfig,
+        retry: Option<BindRetry>,
+    ) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        let addrs = izanami_net::resolve(addr).await?;
+        let listeners = config.bind_all(&addrs, retry).await?;
+        Ok(Self::from_incoming(Incoming::from_std_listeners(
+            listeners,
+        )?))
+    }
+
+    /// Creates a server from a listener that has already been bound.
+    ///
+    /// On Unix, this can be combined with `listen_fd` to serve a socket
+    /// passed by systemd's socket activation.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        Ok(Self::from_incoming(Incoming::from_std(listener)?))
+    }
+
+    fn from_incoming(incoming: Incoming) -> Self {
+        Self {
+            incoming,
+            max_connections: None,
+            on_connection_closed: None,
+            read_header_timeout: None,
+            idle_timeout: None,
+            request_timeout: None,
+            body_idle_timeout: None,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            max_response_header_bytes: DEFAULT_MAX_RESPONSE_HEADER_BYTES,
+            memory_gauge: None,
+            strict_content_length: true,
+            request_extensions: RequestExtensions::new(),
+            header_finalizers: HeaderFinalizers::new(),
+            executor: DefaultExecutor::current(),
+        }
+    }
+}
+
+impl<E> Server<E> {
+    /// Returns the local addresses that the server is bound to.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        self.incoming.local_addrs()
+    }
+
+    /// Sets the executor used to spawn the tasks for connections and requests.
+    ///
+    /// The default value is `DefaultExecutor`, that spawns the tasks onto
+    /// the runtime running the server.
+    pub fn executor<E2>(self, executor: E2) -> Server<E2>
+    where
+        E2: Executor + Clone + Send + Sync + 'static,
+    {
+        Server {
+            incoming: self.incoming,
+            max_connections: self.max_connections,
+            on_connection_closed: self.on_connection_closed,
+            read_header_timeout: self.read_header_timeout,
+            idle_timeout: self.idle_timeout,
+            request_timeout: self.request_timeout,
+            body_idle_timeout: self.body_idle_timeout,
+            max_chunk_size: self.max_chunk_size,
+            max_response_header_bytes: self.max_response_header_bytes,
+            memory_gauge: self.memory_gauge,
+            strict_content_length: self.strict_content_length,
+            request_extensions: self.request_extensions,
+            header_finalizers: self.header_finalizers,
+            executor,
+        }
+    }
+
+    /// Sets whether to set `TCP_NODELAY` on the accepted connections.
+    ///
+    /// The default value is `false`.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.incoming.set_nodelay(enabled);
+        self
+    }
+
+    /// Sets the keepalive interval of the accepted connections.
+    ///
+    /// If `None` is specified, `SO_KEEPALIVE` is not enabled.
+    /// The default value is `None`.
+    pub fn tcp_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.incoming.set_keepalive(keepalive);
+        self
+    }
+
+    /// Sets the maximum duration to receive the head of a request.
+    ///
+    /// The timer starts when the connection is accepted and when all the
+    /// requests received on the connection have been processed, so it also
+    /// bounds how long a keep-alive connection is left idle. If `idle_timeout`
+    /// is set, the timer for the next request starts when the client starts
+    /// sending it instead. If the request head is not complete when the timer
+    /// expires, the connection is closed.
+    ///
+    /// By default, there is no timeout.
+    pub fn read_header_timeout(mut self, timeout: Duration) -> Self {
+        self.read_header_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum duration to keep a connection open between requests.
+    ///
+    /// The timer starts when all the requests received on the connection have
+    /// been processed, and stops when the client starts sending the next one.
+    /// If no data arrives before the timer expires, the connection is closed.
+    ///
+    /// By default, there is no timeout.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum duration for the application to process a request.
+    ///
+    /// The timer starts when the request head has been received. When it
+    /// expires, the application is cancelled and the response is aborted
+    /// unless it has already been completed. The timer is stopped once the
+    /// connection has been upgraded by `101 Switching Protocols`, since the
+    /// upgraded connection is driven within `App::call` for as long as it is used.
+    ///
+    /// By default, there is no timeout.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum duration to wait for the next chunk of the request body.
+    ///
+    /// The timer is reset every time a chunk is received, so slow but steady
+    /// uploads are not interrupted. When the timer expires, `Events::data`
+    /// (or `Events::trailers`) returns an error caused by `BodyTimedOut`.
+    ///
+    /// By default, there is no timeout.
+    pub fn request_body_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.body_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum size of the chunks passed to hyper when sending the response body.
+    ///
+    /// Larger chunks given to `Events::send_data` are split into pieces of this
+    /// size, and each piece is sent after the previous one has been taken by
+    /// the connection. This bounds the amount of data buffered per response and
+    /// allows a disconnected client to be detected in the middle of a large chunk.
+    /// The value is also passed to the application as `conn::SuggestedChunkSize`.
+    ///
+    /// The default value is 64 KiB.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `size` is zero.
+    pub fn max_chunk_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "the chunk size must be positive");
+        self.max_chunk_size = size;
+        self
+    }
+
+    /// Sets the maximum total size of the response header fields.
+    ///
+    /// If the application starts a response whose header fields exceed this
+    /// size, an empty `500 Internal Server Error` response is sent instead,
+    /// and the data of the original response is discarded.
+    ///
+    /// The default value is 64 KiB.
+    pub fn max_response_header_bytes(mut self, max: usize) -> Self {
+        self.max_response_header_bytes = max;
+        self
+    }
+
+    /// Sets whether to check the length of the request body against `Content-Length`.
+    ///
+    /// If enabled, `Events::data` returns an error caused by `IncompleteBody`
+    /// instead of the end of stream when the request body is shorter than
+    /// declared, and when it is longer than declared.
+    ///
+    /// The default value is `true`.
+    pub fn strict_content_length(mut self, enabled: bool) -> Self {
+        self.strict_content_length = enabled;
+        self
+    }
+
+    /// Registers a function that derives a value from each accepted connection.
+    ///
+    /// The function is called once per connection with the address of the
+    /// peer, and a clone of the returned value is inserted into the extensions
+    /// of every request on that connection.
+    pub fn with_request_extension<F, T>(self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> T + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+    {
+        self.try_with_request_extension(move |addr| Ok::<_, std::convert::Infallible>(f(addr)))
+    }
+
+    /// Registers a fallible function that derives a value from each accepted connection.
+    ///
+    /// This is the same as `with_request_extension`, except that the connection
+    /// is closed without reading any requests if the function returns an error.
+    pub fn try_with_request_extension<F, T, Err>(mut self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> Result<T, Err> + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+        Err: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        self.request_extensions.push(f);
+        self
+    }
+
+    /// Registers a callback that modifies the response header just before it is sent.
+    ///
+    /// The callbacks are called in the order of registration with the head of
+    /// the request, exactly once for each response, including the responses
+    /// that the server sends in place of the application.
+    pub fn finalize_headers<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Request<()>, &mut Response<()>) + Send + Sync + 'static,
+    {
+        self.header_finalizers.push(f);
+        self
+    }
+
+    /// Sets the gauge that accounts for the buffered response body data.
+    ///
+    /// A chunk passed to `Events::send_data` is accounted until the connection
+    /// becomes ready to take the next chunk, or until the `Events` is dropped.
+    /// The received chunks are not accounted, since they are handed to the
+    /// application as `hyper::Chunk`.
+    ///
+    /// By default, the buffered data is not accounted.
+    pub fn memory_gauge(mut self, gauge: MemoryGauge) -> Self {
+        self.memory_gauge = Some(gauge);
+        self
+    }
+
+    /// Sets the interval to sleep before retrying when accepting
+    /// an incoming connection fails due to a resource error,
+    /// such as running out of file descriptors (`EMFILE`).
+    ///
+    /// If `None` is specified, the error is returned from `serve`.
+    /// Errors specific to a single connection (e.g. `ECONNABORTED`) are
+    /// always ignored and the server continues to accept connections.
+    ///
+    /// The default value is 1 second.
+    pub fn sleep_on_errors(mut self, interval: Option<Duration>) -> Self {
+        self.incoming.set_sleep_on_errors(interval);
+        self
+    }
+
+    /// Registers a callback invoked when the server accepts a connection.
+    pub fn on_accept<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) + Send + Sync + 'static,
+    {
+        self.incoming.set_on_accept(f);
+        self
+    }
+
+    /// Registers a callback invoked with the lifetime of each connection when it is closed.
+    ///
+    /// Together with `on_accept`, this can be used to track the number
+    /// of the active connections.
+    pub fn on_connection_closed<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        self.on_connection_closed = Some(ClosedCallback(Arc::new(f)));
+        self
+    }
+
+    /// Registers a callback invoked with every error that occurs while
+    /// accepting incoming connections.
+    pub fn on_accept_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.incoming.set_on_error(f);
+        self
+    }
+
+    /// Registers a callback invoked when accepting a connection fails
+    /// because of running out of file descriptors (`EMFILE`/`ENFILE`).
+    pub fn on_resource_exhausted<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.incoming.set_on_resource_exhausted(f);
+        self
+    }
+
+    /// Limits the rate of accepting connections to `rate` connections
+    /// per second, allowing bursts of up to `burst` connections.
+    ///
+    /// While the limit is exceeded, the pending connections are left
+    /// in the backlog of the listener.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `rate` or `burst` is zero.
+    pub fn accept_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.incoming
+            .set_rate_limit(Some(RateLimit::new(rate, burst)));
+        self
+    }
+
+    /// Sets the maximum number of the concurrent connections.
+    ///
+    /// When the limit is reached, the server stops accepting connections
+    /// until one of the active connections is closed. The pending connections
+    /// are left in the backlog of the listener.
+    ///
+    /// By default, there is no limit.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `max` is zero.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        assert!(
+            max > 0,
+            "the maximum number of connections must be positive"
+        );
+        self.max_connections = Some(max);
+        self
     }
 
     pub async fn serve<T>(self, app: T) -> hyper::Result<()>
     where
         T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+        E: Executor + Clone + Send + Sync + 'static,
     {
-        let server = self
-            .builder
-            .serve(hyper::service::make_service_fn(move |_| {
-                let app = app.clone();
-                async move { Ok::<_, std::convert::Infallible>(AppService(app)) }
-            }));
-        server.await
+        self.serve_with_shutdown(app, future::pending()).await
+    }
+
+    /// Serves the application until the process receives Ctrl-C, and then shuts down gracefully.
+    pub async fn serve_until_ctrl_c<T>(
+        self,
+        app: T,
+    ) -> Result<(), Box<dyn error::Error + Send + Sync>>
+    where
+        T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+        E: Executor + Clone + Send + Sync + 'static,
+    {
+        let mut ctrl_c = tokio::net::signal::ctrl_c()?;
+        let signal = async move {
+            let _ =
+                poll_fn(|cx| tokio::prelude::Stream::poll_next(Pin::new(&mut ctrl_c), cx)).await;
+        };
+        self.serve_with_shutdown(app, signal).await?;
+        Ok(())
+    }
+
+    /// Serves the application until `signal` completes, and then shuts down gracefully.
+    ///
+    /// When the signal fires, the server stops accepting connections and lets hyper
+    /// close the active connections once their in-flight responses have been sent.
+    /// The request tasks still running after that are given a few seconds to finish
+    /// before the returned future completes.
+    pub async fn serve_with_shutdown<T, F>(self, app: T, signal: F) -> hyper::Result<()>
+    where
+        T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+        E: Executor + Clone + Send + Sync + 'static,
+        F: Future<Output = ()>,
+    {
+        let executor = self.executor;
+        let request_timeout = self.request_timeout;
+        let body_idle_timeout = self.body_idle_timeout;
+        let max_chunk_size = self.max_chunk_size;
+        let max_response_header_bytes = self.max_response_header_bytes;
+        let memory_gauge = self.memory_gauge;
+        let strict_content_length = self.strict_content_length;
+        let request_extensions = self.request_extensions;
+        let header_finalizers = self.header_finalizers;
+        let tracker = TaskTracker::new();
+        let request_tasks = tracker.clone();
+        let shutdown = CancelToken::new();
+        let mut next_id = 0;
+        let incoming = AcceptIncoming {
+            incoming: self.incoming,
+            limit: self.max_connections.map(ConnectionLimit::new),
+            guard: None,
+            on_closed: self.on_connection_closed,
+            read_header_timeout: self.read_header_timeout,
+            idle_timeout: self.idle_timeout,
+            shutdown: shutdown.clone(),
+        };
+        let server = HyperServer::builder(incoming)
+            .executor(Exec(executor.clone()))
+            .serve(hyper::service::make_service_fn(
+                move |conn: &AcceptedStream| {
+                    let conn_id = ConnectionId(next_id);
+                    next_id += 1;
+
+                    let extensions = conn
+                        .stream
+                        .peer_addr()
+                        .map_err(Into::into)
+                        .and_then(|addr| request_extensions.for_connection(&addr))
+                        .map(|mut extensions| {
+                            if let Ok(local_addr) = conn.stream.local_addr() {
+                                extensions.set_local_addr(local_addr);
+                            }
+                            extensions
+                        });
+                    if let Err(ref err) = extensions {
+                        tracing::debug!("rejected the connection {}: {}", conn_id.0, err);
+                    }
+
+                    let app = app.clone();
+                    let memory_gauge = memory_gauge.clone();
+                    let header_finalizers = header_finalizers.clone();
+                    let executor = executor.clone();
+                    let tracker = request_tasks.clone();
+                    let cancel = conn.cancel.clone();
+                    let read_timer = conn.read_timer.clone();
+                    async move {
+                        Ok::<_, Box<dyn error::Error + Send + Sync>>(AppService {
+                            app,
+                            conn_id,
+                            extensions: extensions?,
+                            next_sequence: 1,
+                            cancel,
+                            read_timer,
+                            request_timeout,
+                            body_idle_timeout,
+                            max_chunk_size,
+                            max_response_header_bytes,
+                            memory_gauge,
+                            strict_content_length,
+                            header_finalizers,
+                            executor,
+                            tracker,
+                        })
+                    }
+                },
+            ))
+            .with_graceful_shutdown(async move {
+                signal.await;
+                shutdown.cancel();
+            });
+        server.await?;
+
+        if tracker.active() > 0 {
+            tracing::info!(
+                "shutting down: waiting for {} request task(s)",
+                tracker.active()
+            );
+            if Timeout::new(tracker.wait_idle(), SHUTDOWN_DRAIN_TIMEOUT)
+                .await
+                .is_err()
+            {
+                tracing::warn!(
+                    "shutting down: {} request task(s) did not finish in time",
+                    tracker.active()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An adaptor that allows hyper to accept the connections from `Incoming`.
+#[derive(Debug)]
+struct AcceptIncoming {
+    incoming: Incoming,
+    limit: Option<ConnectionLimit>,
+    guard: Option<ConnectionGuard>,
+    on_closed: Option<ClosedCallback>,
+    read_header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    shutdown: CancelToken,
+}
+
+impl Accept for AcceptIncoming {
+    type Conn = AcceptedStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let me = self.get_mut();
+
+        // The slot is acquired before accepting, so that the pending
+        // connections are left in the backlog while the limit is reached.
+        if me.guard.is_none() {
+            if let Some(ref limit) = me.limit {
+                me.guard = Some(futures::ready!(limit.poll_acquire(cx)));
+            }
+        }
+
+        let (stream, _) = futures::ready!(me.incoming.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(AcceptedStream {
+            stream,
+            accepted_at: Instant::now(),
+            on_closed: me.on_closed.clone(),
+            cancel: CancelToken::new(),
+            shutdown: me.shutdown.cancelled(),
+            received: false,
+            read_timer: match (me.read_header_timeout, me.idle_timeout) {
+                (None, None) => None,
+                (header, idle) => Some(Arc::new(ReadTimer::new(header, idle))),
+            },
+            _guard: me.guard.take(),
+        })))
+    }
+}
+
+/// An accepted connection that holds a slot of the connection limit until it is dropped.
+#[derive(Debug)]
+struct AcceptedStream {
+    stream: TcpStream,
+    accepted_at: Instant,
+    on_closed: Option<ClosedCallback>,
+    cancel: CancelToken,
+    // The future is kept so that the waker of a pending read stays registered
+    // until the shutdown is signaled.
+    shutdown: Cancelled,
+    received: bool,
+    read_timer: Option<Arc<ReadTimer>>,
+    _guard: Option<ConnectionGuard>,
+}
+
+impl Drop for AcceptedStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        if let Some(ref on_closed) = self.on_closed {
+            (on_closed.0)(self.accepted_at.elapsed());
+        }
+    }
+}
+
+impl AsyncRead for AcceptedStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.stream.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = self.get_mut();
+        match Pin::new(&mut me.stream).poll_read(cx, buf) {
+            Poll::Pending => me.poll_pending_read(cx),
+            ready => {
+                me.on_read(&ready);
+                ready
+            }
+        }
+    }
+
+    fn poll_read_buf<B: bytes::BufMut>(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        let me = self.get_mut();
+        match Pin::new(&mut me.stream).poll_read_buf(cx, buf) {
+            Poll::Pending => me.poll_pending_read(cx),
+            ready => {
+                me.on_read(&ready);
+                ready
+            }
+        }
+    }
+}
+
+impl AcceptedStream {
+    /// Called when a read has completed, to observe the progress of the client.
+    fn on_read(&mut self, result: &Poll<io::Result<usize>>) {
+        if let Poll::Ready(Ok(n)) = *result {
+            if n > 0 {
+                self.received = true;
+                if let Some(ref timer) = self.read_timer {
+                    timer.on_read();
+                }
+            }
+        }
+    }
+
+    /// Called while waiting for the data from the client.
+    fn poll_pending_read(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<usize>> {
+        // hyper does not close the connections on which no request has been
+        // received when shutting down gracefully, so the end of the stream is
+        // reported instead, as if the client had closed the connection.
+        if !self.received && Pin::new(&mut self.shutdown).poll(cx).is_ready() {
+            return Poll::Ready(Ok(0));
+        }
+        self.poll_read_timer(cx)
+    }
+
+    /// Called while waiting for the data from the client, to fail the read
+    /// once the read timer has expired.
+    fn poll_read_timer(&self, cx: &mut task::Context<'_>) -> Poll<io::Result<usize>> {
+        match self.read_timer {
+            Some(ref timer) => match timer.poll_expired(cx) {
+                Some(err) => Poll::Ready(Err(err)),
+                None => Poll::Pending,
+            },
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The timer that closes a connection on which the client does not send
+/// the next request in time.
+///
+/// The timer is stopped while the requests received on the connection are
+/// being processed. When the last of them has finished, the idle timeout
+/// applies until the client starts sending the next request, and then the
+/// header timeout applies until its head has been received.
+#[derive(Debug)]
+struct ReadTimer {
+    header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    state: Mutex<ReadTimerState>,
+}
+
+#[derive(Debug)]
+struct ReadTimerState {
+    in_flight: usize,
+    idle: bool,
+    delay: Option<Delay>,
+    waker: Option<Waker>,
+}
+
+impl ReadTimer {
+    fn new(header_timeout: Option<Duration>, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            header_timeout,
+            idle_timeout,
+            state: Mutex::new(ReadTimerState {
+                in_flight: 0,
+                idle: false,
+                delay: header_timeout.map(delay_for),
+                waker: None,
+            }),
+        }
+    }
+
+    fn start_request(self: &Arc<Self>) -> InFlight {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight += 1;
+        state.idle = false;
+        state.delay = None;
+        InFlight(self.clone())
+    }
+
+    fn on_read(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.idle {
+            state.idle = false;
+            // Without the idle timeout, the header timeout has been running since
+            // the previous request finished and is not restarted.
+            if self.idle_timeout.is_some() {
+                state.delay = self.header_timeout.map(delay_for);
+            }
+        }
+    }
+
+    fn poll_expired(&self, cx: &mut task::Context<'_>) -> Option<io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let expired = match state.delay {
+            Some(ref mut delay) => Pin::new(delay).poll(cx).is_ready(),
+            None => false,
+        };
+        // The connection task is woken up when the timer restarts.
+        state.waker = Some(cx.waker().clone());
+        if !expired {
+            return None;
+        }
+        Some(io::Error::new(
+            io::ErrorKind::TimedOut,
+            if state.idle {
+                "timed out waiting for the next request"
+            } else {
+                "timed out reading the request head"
+            },
+        ))
+    }
+}
+
+/// A request being processed, which keeps the read timer stopped until it is dropped.
+#[derive(Debug)]
+struct InFlight(Arc<ReadTimer>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let timer = &*self.0;
+        let mut state = timer.state.lock().unwrap();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            state.idle = true;
+            state.delay = timer.idle_timeout.or(timer.header_timeout).map(delay_for);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AcceptedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_buf<B: bytes::Buf>(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_buf(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// An adaptor that allows hyper to spawn its internal tasks onto an `Executor`.
+///
+/// The tasks include those driving the connections, so a panic in them
+/// is caught here and only closes the connection. The slot and the close
+/// callback are released when the `AcceptedStream` owned by the task is dropped.
+#[derive(Debug, Clone)]
+struct Exec<E>(E);
+
+impl<E, F> TypedExecutor<F> for Exec<E>
+where
+    E: Executor,
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn spawn(&mut self, future: F) -> Result<(), SpawnError> {
+        self.0.spawn(Box::pin(async move {
+            if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+                tracing::error!("a connection task panicked: {}", panic_message(&*panic));
+            }
+        }))
     }
 }
 
 #[derive(Debug)]
 pub struct Events<'a> {
     req_body: Option<Body>,
-    response_sender: Option<oneshot::Sender<Response<Body>>>,
+    response_sender: Option<oneshot::Sender<Response<ResponseBody>>>,
     state: State,
+    remote_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    is_head: bool,
+    is_http10: bool,
+    upgrade_requested: bool,
+    on_upgrade: Option<oneshot::Sender<()>>,
+    body_idle_timeout: Option<Duration>,
+    max_chunk_size: usize,
+    max_response_header_bytes: usize,
+    memory_gauge: Option<MemoryGauge>,
+    queued: Option<GaugeGuard>,
+    header_finalizers: HeaderFinalizers,
+    request_head: Option<Request<()>>,
+    content_length: Option<u64>,
+    received: u64,
+    recv_state: RecvState,
     _marker: PhantomData<&'a mut ()>,
 }
 
+/// The progress of receiving the request from the client.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RecvState {
+    Data,
+    Trailers,
+    Done,
+}
+
 #[derive(Debug)]
 enum State {
     Init,
-    Streaming(BodySender),
+    Streaming(BodySender, oneshot::Sender<HeaderMap>),
     Upgraded(Upgraded),
+    Discarding,
     Done,
 }
 
+/// The body of the responses passed to hyper.
+///
+/// hyper's body channel cannot carry trailers, so the trailers sent by
+/// `Events::send_trailers` are passed separately and returned after the
+/// end of the data. hyper writes the trailers only on HTTP/2.
+#[derive(Debug)]
+struct ResponseBody {
+    body: Body,
+    trailers: Option<oneshot::Receiver<HeaderMap>>,
+}
+
+impl ResponseBody {
+    fn new(body: Body) -> Self {
+        Self {
+            body,
+            trailers: None,
+        }
+    }
+
+    fn empty() -> Self {
+        Self::new(Body::empty())
+    }
+
+    /// Creates an empty body for the responses that must not have a body.
+    ///
+    /// hyper writes `Content-Length: 0` for the bodies known to be empty,
+    /// even on `204` and `304` responses, so the length is left unknown
+    /// and hyper sends neither `Content-Length` nor `Transfer-Encoding`.
+    fn unframed() -> Self {
+        let (_, body) = Body::channel();
+        Self::new(body)
+    }
+}
+
+impl _Body for ResponseBody {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        match self.trailers {
+            // The sender is dropped without the trailers when the body
+            // has been completed by `send_data`.
+            Some(ref mut trailers) => {
+                Poll::Ready(Ok(futures::ready!(Pin::new(trailers).poll(cx)).ok()))
+            }
+            None => Pin::new(&mut self.body).poll_trailers(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
 impl Events<'_> {
-    pub async fn data(&mut self) -> Option<hyper::Result<Chunk>> {
-        let req_body = self.req_body.as_mut().unwrap();
-        poll_fn(|cx| Pin::new(&mut *req_body).poll_data(cx)).await
+    /// Returns the remote address of the connection.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
     }
 
-    pub async fn trailers(&mut self) -> hyper::Result<Option<HeaderMap>> {
-        let req_body = self.req_body.as_mut().unwrap();
-        poll_fn(|cx| Pin::new(&mut *req_body).poll_trailers(cx)).await
+    /// Returns the local address of the connection, if available.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub async fn data(&mut self) -> Option<Result<Chunk, Error>> {
+        let req_body = match self.req_body {
+            Some(ref mut req_body) if self.recv_state == RecvState::Data => req_body,
+            Some(..) => return None,
+            None => return Some(Err(upgraded())),
+        };
+        let data = poll_fn(|cx| Pin::new(&mut *req_body).poll_data(cx));
+        let data = match self.body_idle_timeout {
+            Some(timeout) => match Timeout::new(data, timeout).await {
+                Ok(data) => data,
+                Err(..) => return Some(Err(Error::body_timed_out())),
+            },
+            None => data.await,
+        };
+        match data {
+            Some(Ok(data)) => match self.count_received(data.len()) {
+                Ok(()) => Some(Ok(data)),
+                Err(err) => Some(Err(err)),
+            },
+            Some(Err(err)) if is_unexpected_eof(&err) => {
+                // Report the connection closed in the middle of the body
+                // in the same way as a short body.
+                Some(Err(self
+                    .check_received()
+                    .err()
+                    .unwrap_or_else(|| err.into())))
+            }
+            Some(Err(err)) => Some(Err(err.into())),
+            None => {
+                self.recv_state = RecvState::Trailers;
+                self.check_received().err().map(Err)
+            }
+        }
+    }
+
+    /// Adds the length of a received chunk and checks that
+    /// the total does not exceed `Content-Length`.
+    fn count_received(&mut self, len: usize) -> Result<(), Error> {
+        self.received += len as u64;
+        match self.content_length {
+            Some(expected) if self.received > expected => {
+                Err(Error::incomplete_body(expected, self.received))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the header finalizers registered to the server.
+    fn finalize_headers(&self, response: &mut Response<()>) {
+        if let Some(ref head) = self.request_head {
+            self.header_finalizers.apply(head, response);
+        }
+        self.announce_close(response);
     }
 
-    pub async fn send_response<T>(&mut self, response: Response<T>) -> hyper::Result<()>
+    /// Marks the response with `Connection: close` if the client requested
+    /// an upgrade but the application declined it.
+    ///
+    /// hyper hands the connection over to the pending upgrade once the request
+    /// has an `Upgrade` header, so the connection is closed after such a
+    /// response and cannot be reused by the client.
+    fn announce_close(&self, response: &mut Response<()>) {
+        if self.upgrade_requested && response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            response
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+    }
+
+    /// Checks that the total length of the request body has reached
+    /// `Content-Length` at the end of stream.
+    fn check_received(&self) -> Result<(), Error> {
+        match self.content_length {
+            Some(expected) if self.received < expected => {
+                Err(Error::incomplete_body(expected, self.received))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn trailers(&mut self) -> Result<Option<HeaderMap>, Error> {
+        let req_body = self.req_body.as_mut().ok_or_else(upgraded)?;
+        match self.recv_state {
+            RecvState::Data => return Err(Error::body_not_consumed()),
+            RecvState::Trailers => {}
+            RecvState::Done => return Ok(None),
+        }
+        let trailers = poll_fn(|cx| Pin::new(&mut *req_body).poll_trailers(cx));
+        let trailers = match self.body_idle_timeout {
+            Some(timeout) => Timeout::new(trailers, timeout)
+                .await
+                .map_err(|_| Error::body_timed_out())??,
+            None => trailers.await?,
+        };
+        self.recv_state = RecvState::Done;
+        Ok(trailers)
+    }
+
+    /// Takes the sender of the response, or returns an error if
+    /// the response header has already been sent.
+    fn take_response_sender(&mut self) -> Result<oneshot::Sender<Response<ResponseBody>>, Error> {
+        self.response_sender
+            .take()
+            .ok_or_else(|| Error::invalid_response_state(InvalidResponseState::already_started()))
+    }
+
+    /// Sends a complete response.
+    ///
+    /// Unlike `izanami::Events::send_response`, the body can be any `Body`,
+    /// including a streaming one. hyper sets `Content-Length` for the bodies
+    /// whose length is known, so the in-memory bodies are sent in the same way.
+    /// The body and `Content-Length` of `204` and `304` responses are dropped.
+    pub async fn send_response<T>(&mut self, response: Response<T>) -> Result<(), Error>
     where
         T: Into<Body>,
     {
-        let sender = self.response_sender.take().unwrap();
-        let _ = sender.send(response.map(Into::into));
+        let sender = self.take_response_sender()?;
+        let (parts, body) = response.into_parts();
+        let mut head = Response::from_parts(parts, ());
+        self.finalize_headers(&mut head);
+        let body = if limit_header_size(&mut head, self.max_response_header_bytes) {
+            self.finalize_headers(&mut head);
+            ResponseBody::empty()
+        } else if !can_have_body(false, head.status()) {
+            strip_body_headers(&mut head);
+            ResponseBody::unframed()
+        } else {
+            ResponseBody::new(body.into())
+        };
+        let _ = sender.send(head.map(|()| body));
         self.state = State::Done;
 
         Ok(())
     }
 
+    /// Sends the response header to the client.
+    ///
+    /// If the response must not have a body (i.e. the request method is
+    /// `HEAD` or the status is `204` or `304`), the data passed to `send_data`
+    /// are discarded. `Transfer-Encoding` is removed, and so is `Content-Length`
+    /// for `204` and `304` responses.
+    /// For `101 Switching Protocols`, it waits until the connection is upgraded,
+    /// and the upgraded connection can then be taken by `into_upgraded`. The
+    /// request body belongs to the upgraded connection afterwards, so `data` and
+    /// `trailers` return an error caused by `InvalidResponseState::upgraded`.
+    ///
+    /// It returns an error if the response header has already been sent.
     pub async fn start_send_response(
         &mut self,
-        response: Response<()>,
+        mut response: Response<()>,
         end_of_stream: bool,
-    ) -> hyper::Result<()> {
-        let sender = self.response_sender.take().unwrap();
+    ) -> Result<(), Error> {
+        let sender = self.take_response_sender()?;
 
-        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        self.finalize_headers(&mut response);
+        if limit_header_size(&mut response, self.max_response_header_bytes) {
+            self.finalize_headers(&mut response);
+            let _ = sender.send(response.map(|_| ResponseBody::empty()));
+            self.state = if end_of_stream {
+                State::Done
+            } else {
+                State::Discarding
+            };
+        } else if !can_have_body(self.is_head, response.status()) {
+            strip_body_headers(&mut response);
+            let _ = sender.send(response.map(|_| ResponseBody::unframed()));
+            self.state = if end_of_stream {
+                State::Done
+            } else {
+                State::Discarding
+            };
+        } else if response.status() == StatusCode::SWITCHING_PROTOCOLS {
             debug_assert!(!end_of_stream);
 
-            let _ = sender.send(response.map(|_| Body::empty()));
+            let _ = sender.send(response.map(|_| ResponseBody::empty()));
 
-            let req_body = self.req_body.take().unwrap();
+            // The response cannot be continued if the upgrade fails.
+            self.state = State::Done;
+            let req_body = self
+                .req_body
+                .take()
+                .expect("the request body is taken only by the upgrade");
             let upgraded = req_body.on_upgrade().await?;
             self.state = State::Upgraded(upgraded);
+            if let Some(on_upgrade) = self.on_upgrade.take() {
+                let _ = on_upgrade.send(());
+            }
         } else if !end_of_stream {
-            let (body_sender, body) = hyper::Body::channel();
-            let _ = sender.send(response.map(|_| body));
+            if self.is_http10 && !response.headers().contains_key(CONTENT_LENGTH) {
+                // HTTP/1.0 has no chunked encoding, so hyper delimits the body
+                // by closing the connection. Tell the client not to wait for
+                // the connection to be kept alive. The version is lowered as
+                // well, since hyper otherwise replaces the header with
+                // `keep-alive` for a client that requested it.
+                *response.version_mut() = Version::HTTP_10;
+                response
+                    .headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            let (body_sender, body) = Body::channel();
+            let (trailers_sender, trailers) = oneshot::channel();
+            let _ = sender.send(response.map(|_| ResponseBody {
+                body,
+                trailers: Some(trailers),
+            }));
 
-            self.state = State::Streaming(body_sender);
+            self.state = State::Streaming(body_sender, trailers_sender);
         } else {
-            let _ = sender.send(response.map(|_| Body::empty()));
+            let _ = sender.send(response.map(|_| ResponseBody::empty()));
             self.state = State::Done;
         }
 
         Ok(())
     }
 
-    pub async fn send_data<T>(&mut self, data: T, is_end_stream: bool) -> hyper::Result<()>
+    /// Takes the connection upgraded by the `101 Switching Protocols` response.
+    ///
+    /// The returned `Upgraded` implements `AsyncRead` and `AsyncWrite`, and
+    /// the application communicates with the client over it in the new protocol.
+    /// The read timeouts of the server are suspended until `App::call` returns,
+    /// so the upgraded connection should be driven to the end within it.
+    ///
+    /// It returns `None` if the connection has not been upgraded.
+    pub fn into_upgraded(self) -> Option<Upgraded> {
+        match self.state {
+            State::Upgraded(upgraded) => Some(upgraded),
+            _ => None,
+        }
+    }
+
+    /// Waits until the connection is ready to accept the next chunk of the response body.
+    ///
+    /// It returns an error if the client has gone away.
+    pub async fn ready(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            State::Streaming(sender, _) => {
+                poll_fn(|cx| sender.poll_ready(cx)).await?;
+                self.queued = None;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends a chunk of the response body.
+    ///
+    /// It returns an error if the response header has not been sent or
+    /// the response has already been completed.
+    pub async fn send_data<T>(&mut self, data: T, is_end_stream: bool) -> Result<(), Error>
     where
         T: Into<Chunk>,
     {
         match &mut self.state {
-            State::Streaming(sender) => {
-                sender.send_data(data.into()).await?;
+            State::Streaming(sender, _) => {
+                let mut data = data.into().into_bytes();
+                while !data.is_empty() {
+                    let len = std::cmp::min(data.len(), self.max_chunk_size);
+                    sender.send_data(data.split_to(len).into()).await?;
+                    // The previous chunk has been taken by the connection
+                    // since `send_data` waits for the channel to be ready.
+                    self.queued = self
+                        .memory_gauge
+                        .as_ref()
+                        .map(|gauge| gauge.track_response(len));
+                }
+            }
+            State::Discarding => {}
+            State::Init => {
+                return Err(Error::invalid_response_state(
+                    InvalidResponseState::not_started(),
+                ))
+            }
+            State::Upgraded(..) | State::Done => {
+                return Err(Error::invalid_response_state(
+                    InvalidResponseState::finished(),
+                ))
             }
-            _ => panic!("unexpected call"),
         }
 
         if is_end_stream {
@@ -128,13 +1279,66 @@ impl Events<'_> {
 
         Ok(())
     }
+
+    /// Sends the trailers and completes the response.
+    ///
+    /// hyper writes the trailers only on HTTP/2, and they are discarded on
+    /// HTTP/1.x. It returns an error if the response header has not been sent
+    /// or the response has already been completed.
+    pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Error> {
+        match std::mem::replace(&mut self.state, State::Done) {
+            State::Streaming(_, trailers_sender) => {
+                let _ = trailers_sender.send(trailers);
+                Ok(())
+            }
+            State::Discarding => Ok(()),
+            state => {
+                let err = match state {
+                    State::Init => InvalidResponseState::not_started(),
+                    _ => InvalidResponseState::finished(),
+                };
+                self.state = state;
+                Err(Error::invalid_response_state(err))
+            }
+        }
+    }
+}
+
+/// The error type returned from `Events`.
+pub type Error = EventsError<hyper::Error>;
+
+/// The error returned when the request body is requested after the upgrade.
+fn upgraded() -> Error {
+    Error::invalid_response_state(InvalidResponseState::upgraded())
+}
+
+/// Returns whether the error was caused by the connection closed unexpectedly.
+fn is_unexpected_eof(err: &hyper::Error) -> bool {
+    let mut source = error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return err.kind() == io::ErrorKind::UnexpectedEof;
+        }
+        source = err.source();
+    }
+    false
 }
 
 #[async_trait]
 #[allow(clippy::needless_lifetimes)]
 impl<'a> izanami::Events for Events<'a> {
     type Data = Chunk;
-    type Error = hyper::Error;
+    type Error = Error;
+
+    #[inline]
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+
+    #[inline]
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr()
+    }
 
     #[inline]
     async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
@@ -168,44 +1372,178 @@ impl<'a> izanami::Events for Events<'a> {
     async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
         self.send_trailers(trailers).await
     }
+
+    #[inline]
+    async fn ready(&mut self) -> Result<(), Self::Error> {
+        self.ready().await
+    }
+}
+
+struct AppService<T, E> {
+    app: T,
+    conn_id: ConnectionId,
+    extensions: ConnectionExtensions,
+    next_sequence: u64,
+    cancel: CancelToken,
+    read_timer: Option<Arc<ReadTimer>>,
+    request_timeout: Option<Duration>,
+    body_idle_timeout: Option<Duration>,
+    max_chunk_size: usize,
+    max_response_header_bytes: usize,
+    memory_gauge: Option<MemoryGauge>,
+    strict_content_length: bool,
+    header_finalizers: HeaderFinalizers,
+    executor: E,
+    tracker: TaskTracker,
 }
 
-struct AppService<T>(T);
+/// Completes when the request has timed out.
+///
+/// The timer is stopped when `upgraded` is notified, i.e. the connection
+/// has been upgraded.
+fn request_deadline(
+    timeout: Duration,
+    upgraded: Option<oneshot::Receiver<()>>,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    let delay = delay_for(timeout);
+    match upgraded {
+        Some(upgraded) => Box::pin(async move {
+            match future::select(delay, upgraded).await {
+                Either::Left(..) => {}
+                Either::Right((Ok(()), _)) => future::pending().await,
+                // The application has dropped `Events` without upgrading.
+                Either::Right((Err(..), delay)) => delay.await,
+            }
+        }),
+        None => Box::pin(delay),
+    }
+}
 
-impl<T> AppService<T>
+impl<T, E> AppService<T, E>
 where
     T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+    E: Executor,
 {
-    fn spawn_background(&self, request: Request<Body>) -> oneshot::Receiver<Response<Body>> {
-        let (parts, req_body) = request.into_parts();
-        let app = self.0.clone();
+    fn spawn_background(
+        &mut self,
+        request: Request<Body>,
+        cancel: CancelToken,
+    ) -> oneshot::Receiver<Response<ResponseBody>> {
+        let (mut parts, req_body) = request.into_parts();
+        parts.extensions.insert(self.conn_id);
+        parts.extensions.insert(RequestSequence(self.next_sequence));
+        self.next_sequence += 1;
+        self.extensions.insert_into(&mut parts.extensions);
+        let remote_addr = self.extensions.remote_addr();
+        let local_addr = self.extensions.local_addr();
+        parts.extensions.insert(cancel.clone());
+        parts.extensions.insert(match parts.version {
+            Version::HTTP_2 => Protocol::Http2 { is_tls: false },
+            _ => Protocol::Http1 { is_tls: false },
+        });
+        parts
+            .extensions
+            .insert(SuggestedChunkSize(self.max_chunk_size));
+
+        let is_head = parts.method == Method::HEAD;
+        let is_http10 = parts.version == Version::HTTP_10;
+        let upgrade_requested =
+            parts.version == Version::HTTP_11 && parts.headers.contains_key(UPGRADE);
+        let body_idle_timeout = self.body_idle_timeout;
+        let max_chunk_size = self.max_chunk_size;
+        let max_response_header_bytes = self.max_response_header_bytes;
+        let memory_gauge = self.memory_gauge.clone();
+        let header_finalizers = self.header_finalizers.clone();
+        let request_head = if header_finalizers.is_empty() {
+            None
+        } else {
+            Some(request_head(&parts))
+        };
+        let content_length = if self.strict_content_length {
+            content_length(&parts.headers)
+        } else {
+            None
+        };
+
+        let app = self.app.clone();
         let (tx, rx) = oneshot::channel();
-        tokio::spawn(async move {
-            if let Err(err) = app
-                .call(Request::from_parts(
-                    parts,
-                    Events {
-                        req_body: Some(req_body),
-                        response_sender: Some(tx),
-                        state: State::Init,
-                        _marker: PhantomData,
-                    },
-                ))
-                .await
-            {
-                eprintln!("app error: {}", err.into());
+        let conn_id = self.conn_id;
+        let in_flight = self.read_timer.as_ref().map(ReadTimer::start_request);
+        let (on_upgrade, upgraded) = match self.request_timeout {
+            Some(..) if upgrade_requested => {
+                let (tx, rx) = oneshot::channel();
+                (Some(tx), Some(rx))
             }
-        });
+            _ => (None, None),
+        };
+        let deadline = self
+            .request_timeout
+            .map(|timeout| request_deadline(timeout, upgraded));
+        let tracked = self.tracker.track();
+        let spawned = self.executor.spawn(Box::pin(async move {
+            let _tracked = tracked;
+            let _in_flight = in_flight;
+            let call = AssertUnwindSafe(app.call(Request::from_parts(
+                parts,
+                Events {
+                    req_body: Some(req_body),
+                    response_sender: Some(tx),
+                    state: State::Init,
+                    remote_addr,
+                    local_addr,
+                    is_head,
+                    is_http10,
+                    upgrade_requested,
+                    on_upgrade,
+                    body_idle_timeout,
+                    max_chunk_size,
+                    max_response_header_bytes,
+                    memory_gauge,
+                    queued: None,
+                    header_finalizers,
+                    request_head,
+                    content_length,
+                    received: 0,
+                    recv_state: RecvState::Data,
+                    _marker: PhantomData,
+                },
+            )))
+            .catch_unwind();
+            let result = match deadline {
+                Some(deadline) => match future::select(call, deadline).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(..) => {
+                        tracing::warn!("the request on the connection {} timed out", conn_id.0);
+                        cancel.cancel();
+                        return;
+                    }
+                },
+                None => call.await,
+            };
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::error!("app error: {}", err.into()),
+                Err(panic) => tracing::error!(
+                    "the request task for the connection {} panicked: {}",
+                    conn_id.0,
+                    panic_message(&*panic)
+                ),
+            }
+        }));
+        if let Err(err) = spawned {
+            tracing::error!("failed to spawn the request task: {}", err);
+        }
         rx
     }
 }
 
-impl<T> Service<Request<Body>> for AppService<T>
+impl<T, E> Service<Request<Body>> for AppService<T, E>
 where
     T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+    E: Executor,
 {
-    type Response = Response<Body>;
-    type Error = hyper::Error;
+    type Response = Response<ResponseBody>;
+    type Error = Box<dyn error::Error + Send + Sync>;
     #[allow(clippy::type_complexity)]
     type Future =
         Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
@@ -215,7 +1553,30 @@ where
     }
 
     fn call(&mut self, request: Request<hyper::Body>) -> Self::Future {
-        let rx = self.spawn_background(request);
-        Box::pin(async move { Ok(rx.await.unwrap()) })
+        let cancel = self.cancel.child_token();
+        let rx = self.spawn_background(request, cancel.clone());
+        Box::pin(async move {
+            // hyper drops this future when the client has gone away before
+            // the response is sent, e.g. by resetting the stream.
+            let mut guard = CancelOnDrop(Some(cancel));
+            // The sender is dropped without a response when the request task
+            // has failed, and the connection is closed by hyper.
+            let response = rx
+                .await
+                .map_err(|_| "the request task ended without sending a response")?;
+            guard.0 = None;
+            Ok(response)
+        })
+    }
+}
+
+/// Cancels the token when dropped, unless it has been taken out.
+struct CancelOnDrop(Option<CancelToken>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            cancel.cancel();
+        }
     }
 }
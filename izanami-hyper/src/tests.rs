@@ -0,0 +1,920 @@
+use super::*;
+use futures::future::BoxFuture;
+use http::header::HeaderValue;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+type Handler = for<'a> fn(Events<'a>) -> BoxFuture<'a, Result<(), Error>>;
+
+#[derive(Clone)]
+struct TestApp(Handler);
+
+#[async_trait]
+impl<'a> App<Events<'a>> for TestApp {
+    type Error = Error;
+
+    async fn call(&self, req: Request<Events<'a>>) -> Result<(), Self::Error> {
+        (self.0)(req.into_body()).await
+    }
+}
+
+/// Starts a server running `handler`, sends the raw request to it, and
+/// returns the raw response head (without the date) and body.
+async fn roundtrip(handler: Handler, request: &str) -> (String, String) {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    roundtrip_with(server, handler, request).await
+}
+
+/// Same as `roundtrip`, but with the configured server.
+async fn roundtrip_with(server: Server, handler: Handler, request: &str) -> (String, String) {
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+
+    let response = String::from_utf8(response).unwrap();
+    let end_of_head = response.find("\r\n\r\n").expect("incomplete response head");
+    let head = response[..end_of_head]
+        .split("\r\n")
+        .filter(|line| !line.starts_with("date:"))
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    (head, response[end_of_head + 4..].to_owned())
+}
+
+fn response_with_length(status: StatusCode) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+    response
+}
+
+#[tokio::test]
+async fn head_keeps_content_length_without_body() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::OK), false)
+                .await?;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let (head, body) = roundtrip(
+        handler,
+        "HEAD / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 200 OK\r\ncontent-length: 5");
+    assert_eq!(body, "");
+}
+
+#[tokio::test]
+async fn no_content_drops_content_length_with_end_of_stream() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::NO_CONTENT), true)
+                .await
+        }
+        .boxed()
+    }
+
+    let (head, body) = roundtrip(
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 204 No Content");
+    assert_eq!(body, "");
+}
+
+#[tokio::test]
+async fn no_content_drops_content_length_and_body() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::NO_CONTENT), false)
+                .await?;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let (head, body) = roundtrip(
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 204 No Content");
+    assert_eq!(body, "");
+}
+
+#[tokio::test]
+async fn not_modified_drops_content_length_and_body() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::NOT_MODIFIED), false)
+                .await?;
+            events.send_data("hello", false).await?;
+            events.send_trailers(HeaderMap::new()).await
+        }
+        .boxed()
+    }
+
+    let (head, body) = roundtrip(
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 304 Not Modified");
+    assert_eq!(body, "");
+}
+
+#[tokio::test]
+async fn send_response_drops_no_content_body_and_keeps_connection() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            let mut response = Response::new("hello");
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            events.send_response(response).await
+        }
+        .boxed()
+    }
+
+    let (head, body) = roundtrip(
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\n\r\n\
+         GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 204 No Content");
+    assert!(
+        body.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{:?}",
+        body
+    );
+    assert!(body.ends_with("\r\n\r\n"), "{:?}", body);
+    assert!(!body.contains("content-length"), "{:?}", body);
+}
+
+#[tokio::test]
+async fn request_timeout_stops_on_upgrade() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            let response = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "upgrade")
+                .header(UPGRADE, "echo")
+                .body(())
+                .unwrap();
+            events.start_send_response(response, false).await?;
+            let mut upgraded = events.into_upgraded().unwrap();
+            delay_for(Duration::from_millis(300)).await;
+            upgraded.write_all(b"still here").await.unwrap();
+            Ok(())
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .request_timeout(Duration::from_millis(100));
+    let (head, body) = roundtrip_with(
+        server,
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nconnection: upgrade\r\nupgrade: echo\r\n\r\n",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{:?}",
+        head
+    );
+    assert_eq!(body, "still here");
+}
+
+#[tokio::test]
+async fn upgraded_connection_exchanges_bytes_both_ways() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            let response = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "upgrade")
+                .header(UPGRADE, "echo")
+                .body(())
+                .unwrap();
+            events.start_send_response(response, false).await?;
+
+            // the request body is no longer available as HTTP.
+            let err = events.data().await.unwrap().unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            let err = events.trailers().await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+
+            let mut upgraded = events.into_upgraded().unwrap();
+            let mut buf = [0; 4];
+            upgraded.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            upgraded.write_all(b"pong").await.unwrap();
+            Ok(())
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: upgrade\r\nupgrade: echo\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{:?}",
+        head
+    );
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"pong");
+}
+
+#[tokio::test]
+async fn request_timeout_aborts_slow_requests() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events.start_send_response(Response::new(()), false).await?;
+            delay_for(Duration::from_millis(300)).await;
+            events.send_data("too late", true).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .request_timeout(Duration::from_millis(100));
+    let (head, body) = roundtrip_with(
+        server,
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked");
+    assert!(!body.contains("too late"), "{:?}", body);
+}
+
+#[tokio::test]
+async fn misordered_response_calls_return_errors() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            let err = events.send_data("data", false).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            let err = events.send_trailers(HeaderMap::new()).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+
+            events
+                .start_send_response(response_with_length(StatusCode::OK), false)
+                .await?;
+            let err = events
+                .start_send_response(Response::new(()), true)
+                .await
+                .unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+
+            events.send_data("hello", true).await?;
+            let err = events.send_data("data", true).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            let err = events.send_trailers(HeaderMap::new()).await.unwrap_err();
+            assert!(err.is_invalid_response_state(), "{}", err);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // The connection is still usable after the misuses.
+    let (head, body) = roundtrip(
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\n\r\n\
+         GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 200 OK\r\ncontent-length: 5");
+    assert!(body.starts_with("hello"), "{:?}", body);
+    assert!(body.ends_with("\r\n\r\nhello"), "{:?}", body);
+}
+
+#[tokio::test]
+async fn stalled_request_body_times_out() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            assert_eq!(events.data().await.unwrap().unwrap().as_ref(), b"hello");
+            let err = events.data().await.unwrap().unwrap_err();
+            assert!(err.is_body_timed_out(), "{}", err);
+            let mut response = Response::new("timed out");
+            *response.status_mut() = StatusCode::REQUEST_TIMEOUT;
+            events.send_response(response).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .request_body_idle_timeout(Duration::from_millis(100));
+    // Only the half of the body is sent, and the connection is kept open.
+    let (head, body) = roundtrip_with(
+        server,
+        handler,
+        "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhello",
+    )
+    .await;
+    assert!(
+        head.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{:?}",
+        head
+    );
+    assert_eq!(body, "timed out");
+}
+
+/// Reads the request body and responds with its length, or with the kind
+/// of the error.
+fn read_body(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+    async move {
+        let mut len = 0;
+        let body = loop {
+            match events.data().await {
+                Some(Ok(chunk)) => len += chunk.len(),
+                Some(Err(ref err)) if err.is_incomplete_body() => break "incomplete".into(),
+                Some(Err(..)) => break "error".into(),
+                None => break len.to_string(),
+            }
+        };
+        events.send_response(Response::new(body)).await
+    }
+    .boxed()
+}
+
+/// Sends the raw request, closes the writing half and returns the response body.
+async fn send_and_shutdown(server: Server, request: &str) -> String {
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(read_body)).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let end_of_head = response.find("\r\n\r\n").expect("incomplete response head");
+    response[end_of_head + 4..].to_owned()
+}
+
+#[tokio::test]
+async fn truncated_request_body_is_incomplete() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let body = send_and_shutdown(
+        server,
+        "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhello",
+    )
+    .await;
+    assert_eq!(body, "incomplete");
+}
+
+#[tokio::test]
+async fn truncated_request_body_is_not_checked_unless_strict() {
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .strict_content_length(false);
+    let body = send_and_shutdown(
+        server,
+        "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 10\r\n\r\nhello",
+    )
+    .await;
+    assert_eq!(body, "error");
+}
+
+#[tokio::test]
+async fn request_body_with_exact_length_is_complete() {
+    let (_, body) = roundtrip(
+        read_body,
+        "POST / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\ncontent-length: 5\r\n\r\nhello",
+    )
+    .await;
+    assert_eq!(body, "5");
+}
+
+#[tokio::test]
+async fn chunked_request_body_is_not_checked() {
+    let (_, body) = roundtrip(
+        read_body,
+        "POST / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\
+         transfer-encoding: chunked\r\n\r\n\
+         5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n",
+    )
+    .await;
+    assert_eq!(body, "10");
+}
+
+#[tokio::test]
+async fn oversized_response_header_is_replaced_with_500() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            let response = Response::builder()
+                .header("x-large", "a".repeat(100))
+                .body(())
+                .unwrap();
+            events.start_send_response(response, false).await?;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_response_header_bytes(64);
+    let (head, body) = roundtrip_with(
+        server,
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(
+        head,
+        "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0"
+    );
+    assert_eq!(body, "");
+}
+
+#[tokio::test]
+async fn redirect_without_host_uses_the_local_address() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server
+            .serve(izanami::app::redirect_to_https(None))
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /path HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.0 301 Moved Permanently\r\n"),
+        "{:?}",
+        response
+    );
+    assert!(
+        response.contains("\r\nlocation: https://127.0.0.1/path\r\n"),
+        "{:?}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn handler_fn_limits_the_body_on_a_keep_alive_connection() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        let app = izanami::app::handler_fn(|request: Request<bytes::Bytes>| {
+            futures::future::ok::<_, Error>(Response::new(request.into_body()))
+        })
+        .max_body_size(5);
+        server.serve(app).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 5\r\n\r\nhello\
+              POST / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\
+              content-length: 10\r\n\r\nhelloworld",
+        )
+        .await
+        .unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+
+    let second = response
+        .find("HTTP/1.1 413 Payload Too Large\r\n")
+        .expect("the second response should be sent on the same connection");
+    let (first, second) = response.split_at(second);
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", first);
+    assert!(first.contains("\r\ncontent-length: 5\r\n"), "{:?}", first);
+    assert!(first.ends_with("\r\n\r\nhello"), "{:?}", first);
+    assert!(second.contains("\r\ncontent-length: 0\r\n"), "{:?}", second);
+    assert!(second.ends_with("\r\n\r\n"), "{:?}", second);
+}
+
+/// Starts a server that shuts down when the returned sender fires, and
+/// returns a receiver that completes when `serve_with_shutdown` returns.
+fn serve_until_signaled(
+    server: Server,
+    handler: Handler,
+) -> (oneshot::Sender<()>, oneshot::Receiver<hyper::Result<()>>) {
+    let (signal_tx, signal_rx) = oneshot::channel();
+    let (served_tx, served_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let signal = signal_rx.map(|_| ());
+        let _ = served_tx.send(server.serve_with_shutdown(TestApp(handler), signal).await);
+    });
+    (signal_tx, served_rx)
+}
+
+#[tokio::test]
+async fn shutdown_completes_the_in_flight_response() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events
+                .start_send_response(response_with_length(StatusCode::OK), false)
+                .await?;
+            delay_for(Duration::from_millis(200)).await;
+            events.send_data("hello", true).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    let (signal, served) = serve_until_signaled(server, handler);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    signal.send(()).unwrap();
+
+    // The keep-alive connection is closed after the response.
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n"),
+        "{:?}",
+        response
+    );
+    assert!(response.ends_with("\r\n\r\nhello"), "{:?}", response);
+
+    Timeout::new(served, Duration::from_secs(1))
+        .await
+        .expect("the server should stop after the response")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_closes_the_connection_without_requests() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    let (signal, served) = serve_until_signaled(server, read_body);
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    signal.send(()).unwrap();
+
+    let mut response = vec![];
+    Timeout::new(stream.read_to_end(&mut response), Duration::from_secs(1))
+        .await
+        .expect("the idle connection should be closed by the shutdown")
+        .unwrap();
+    assert!(response.is_empty(), "{:?}", response);
+
+    Timeout::new(served, Duration::from_secs(1))
+        .await
+        .expect("the server should stop after closing the connection")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn trailers_are_sent_after_the_body_on_http2() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("hello", false).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+            events.send_trailers(trailers).await
+        }
+        .boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, conn) = h2::client::handshake(stream).await.unwrap();
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let mut client = client.ready().await.unwrap();
+    let request = Request::builder()
+        .uri("http://localhost/")
+        .body(())
+        .unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let (parts, mut body) = response.await.unwrap().into_parts();
+    assert_eq!(parts.status, StatusCode::OK);
+
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(data, b"hello");
+    let trailers = body.trailers().await.unwrap().expect("missing trailers");
+    assert_eq!(trailers["x-checksum"], "abc");
+}
+
+#[tokio::test]
+async fn trailers_are_dropped_on_http1() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("hello", false).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+            events.send_trailers(trailers).await
+        }
+        .boxed()
+    }
+
+    let (head, body) = roundtrip(
+        handler,
+        "GET / HTTP/1.1\r\nhost: localhost\r\nte: trailers\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(head, "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked");
+    assert_eq!(body, "5\r\nhello\r\n0\r\n\r\n");
+}
+
+const KEEP_ALIVE_REQUEST: &[u8] = b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n";
+
+/// Reads a response whose body is `ok`.
+async fn read_ok_response(stream: &mut TcpStream) -> String {
+    let mut response = vec![];
+    let mut buf = [0; 1024];
+    while !response.ends_with(b"\r\n\r\nok") {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "the connection was closed: {:?}", response);
+        response.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test]
+async fn connection_limit_accepts_the_second_connection_after_the_first_closes() {
+    fn handler(mut events: Events<'_>) -> BoxFuture<'_, Result<(), Error>> {
+        async move { events.send_response(Response::new("ok")).await }.boxed()
+    }
+
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .max_connections(1);
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(TestApp(handler)).await.unwrap();
+    });
+
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    first.write_all(KEEP_ALIVE_REQUEST).await.unwrap();
+    let response = read_ok_response(&mut first).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n"),
+        "{:?}",
+        response
+    );
+
+    // The first connection is kept alive, so the second one is not accepted yet.
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    second.write_all(KEEP_ALIVE_REQUEST).await.unwrap();
+    let pending = Timeout::new(read_ok_response(&mut second), Duration::from_millis(200)).await;
+    assert!(pending.is_err(), "the second connection was served");
+
+    drop(first);
+    let response = Timeout::new(read_ok_response(&mut second), Duration::from_secs(5))
+        .await
+        .expect("the second connection was not served after the first closed");
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n"),
+        "{:?}",
+        response
+    );
+}
+
+/// An application that responds with a description of the request extensions.
+#[derive(Clone)]
+struct Describe(fn(&http::Extensions) -> String);
+
+#[async_trait]
+impl<'a> App<Events<'a>> for Describe {
+    type Error = Error;
+
+    async fn call(&self, req: Request<Events<'a>>) -> Result<(), Self::Error> {
+        let description = (self.0)(req.extensions());
+        let mut events = req.into_body();
+        events.send_response(Response::new(description)).await
+    }
+}
+
+/// Starts a server running `app`, and returns its address.
+fn serve_app<T>(server: Server, app: T) -> SocketAddr
+where
+    T: for<'a> App<Events<'a>> + Clone + Send + Sync + 'static,
+{
+    let addr = server.local_addrs()[0];
+    tokio::spawn(async move {
+        server.serve(app).await.unwrap();
+    });
+    addr
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeerPort(u16);
+
+#[tokio::test]
+async fn request_extension_reaches_every_request_on_the_connection() {
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .with_request_extension(|addr| {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+            PeerPort(addr.port())
+        });
+    let addr = serve_app(
+        server,
+        Describe(|extensions| format!("{:?}", extensions.get::<PeerPort>())),
+    );
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n\
+              GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+
+    let expected = format!("{:?}", Some(PeerPort(stream.local_addr().unwrap().port())));
+    assert_eq!(response.matches(&*expected).count(), 2, "{:?}", response);
+    assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn failed_request_extension_closes_the_connection() {
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    let server = Server::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .try_with_request_extension(|_| {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+            Err::<PeerPort, _>("rejected")
+        });
+    let addr = serve_app(server, Describe(|_| panic!("the request was handled")));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut response = vec![];
+    Timeout::new(stream.read_to_end(&mut response), Duration::from_secs(1))
+        .await
+        .expect("the connection was not closed")
+        .unwrap();
+    assert!(response.is_empty(), "{:?}", response);
+    assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+}
+
+/// Describes the connection-level extensions as `id|sequence|protocol;`.
+fn describe_connection(extensions: &http::Extensions) -> String {
+    format!(
+        "{}|{}|{:?};",
+        extensions.get::<ConnectionId>().unwrap().0,
+        extensions.get::<RequestSequence>().unwrap().0,
+        extensions.get::<Protocol>().unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn requests_on_an_http1_connection_share_the_connection_id() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = serve_app(server, Describe(describe_connection));
+
+    let mut descriptions = vec![];
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n\
+                  GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        // The body of each response follows its head and ends with `;`.
+        descriptions.push(
+            response
+                .split("\r\n\r\n")
+                .skip(1)
+                .map(|rest| rest[..=rest.find(';').unwrap()].to_owned())
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let id = descriptions[0][0].split('|').next().unwrap().to_owned();
+    let protocol = "Http1 { is_tls: false };";
+    assert_eq!(
+        descriptions[0],
+        [
+            format!("{}|1|{}", id, protocol),
+            format!("{}|2|{}", id, protocol)
+        ]
+    );
+    // The next connection has a different identifier and starts over.
+    let next_id = descriptions[1][0].split('|').next().unwrap();
+    assert_ne!(next_id, id);
+    assert_eq!(
+        descriptions[1],
+        [
+            format!("{}|1|{}", next_id, protocol),
+            format!("{}|2|{}", next_id, protocol)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn requests_on_an_http2_connection_share_the_connection_id() {
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let addr = serve_app(server, Describe(describe_connection));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, conn) = h2::client::handshake(stream).await.unwrap();
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let mut descriptions = vec![];
+    for _ in 0..2 {
+        let mut client = client.clone().ready().await.unwrap();
+        let request = Request::get("http://localhost/").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let mut body = response.await.unwrap().into_body();
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        descriptions.push(String::from_utf8(data).unwrap());
+    }
+
+    let id = descriptions[0].split('|').next().unwrap();
+    assert_eq!(
+        descriptions,
+        [
+            format!("{}|1|Http2 {{ is_tls: false }};", id),
+            format!("{}|2|Http2 {{ is_tls: false }};", id),
+        ]
+    );
+}
@@ -0,0 +1,298 @@
+use crate::TcpConfig;
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    time::Duration,
+};
+use tokio::timer::delay_for;
+use tokio_executor::blocking;
+
+/// The policy of retrying to bind a listener when the address is in use.
+///
+/// This is useful when the server is restarted and the previous process
+/// has not released the address yet.
+#[derive(Debug, Copy, Clone)]
+pub struct BindRetry {
+    attempts: u32,
+    initial_delay: Duration,
+}
+
+impl BindRetry {
+    /// Creates a `BindRetry` that retries up to `attempts` times.
+    ///
+    /// The delay before the first retry is `initial_delay`, and it is doubled
+    /// for each subsequent retry.
+    pub fn new(attempts: u32, initial_delay: Duration) -> Self {
+        Self {
+            attempts,
+            initial_delay,
+        }
+    }
+}
+
+/// Resolves the specified address on the blocking thread pool,
+/// so that a DNS lookup does not block the reactor.
+pub async fn resolve<A>(addr: A) -> io::Result<Vec<SocketAddr>>
+where
+    A: ToSocketAddrs + Send + 'static,
+{
+    blocking::run(move || addr.to_socket_addrs().map(Iterator::collect)).await
+}
+
+impl TcpConfig {
+    /// Creates a TCP listener bound to the first of `addrs` that can be bound.
+    ///
+    /// If none of the addresses can be bound, the returned error describes
+    /// the failure of every address. If `retry` is specified and any of the
+    /// addresses was in use, binding is retried after a delay.
+    pub async fn bind_any(
+        &self,
+        addrs: &[SocketAddr],
+        retry: Option<BindRetry>,
+    ) -> io::Result<TcpListener> {
+        let mut attempt = 0;
+        loop {
+            let mut errors = vec![];
+            for addr in addrs {
+                match self.bind(addr) {
+                    Ok(listener) => return Ok(listener),
+                    Err(err) => errors.push((*addr, err)),
+                }
+            }
+
+            match retry_delay(retry, attempt, &errors) {
+                Some(delay) => {
+                    tracing::debug!("the address is in use; retrying after {:?}", delay);
+                    delay_for(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(bind_error(errors)),
+            }
+        }
+    }
+}
+
+impl TcpConfig {
+    /// Creates TCP listeners bound to all of `addrs`.
+    ///
+    /// If the port of the addresses is zero, the port assigned to the first
+    /// listener is reused for the rest, so that all the listeners share the
+    /// same port. The addresses that cannot be bound are skipped with a warning
+    /// as long as at least one of them is bound; otherwise the returned error
+    /// describes the failure of every address. If `retry` is specified and any
+    /// of the addresses was in use, binding is retried after a delay.
+    pub async fn bind_all(
+        &self,
+        addrs: &[SocketAddr],
+        retry: Option<BindRetry>,
+    ) -> io::Result<Vec<TcpListener>> {
+        let mut attempt = 0;
+        loop {
+            let mut listeners = vec![];
+            let mut errors = vec![];
+            let mut assigned_port = None;
+            for addr in addrs {
+                let mut addr = *addr;
+                if let (0, Some(port)) = (addr.port(), assigned_port) {
+                    addr.set_port(port);
+                }
+                match self.bind(&addr).and_then(|listener| {
+                    let port = listener.local_addr()?.port();
+                    Ok((listener, port))
+                }) {
+                    Ok((listener, port)) => {
+                        assigned_port.get_or_insert(port);
+                        listeners.push(listener);
+                    }
+                    Err(err) => errors.push((addr, err)),
+                }
+            }
+
+            if !listeners.is_empty() {
+                for (addr, err) in errors {
+                    tracing::warn!("failed to bind {}: {}", addr, err);
+                }
+                return Ok(listeners);
+            }
+
+            match retry_delay(retry, attempt, &errors) {
+                Some(delay) => {
+                    tracing::debug!("the address is in use; retrying after {:?}", delay);
+                    delay_for(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(bind_error(errors)),
+            }
+        }
+    }
+}
+
+/// Returns the delay before retrying to bind, or `None` if binding should not be retried.
+fn retry_delay(
+    retry: Option<BindRetry>,
+    attempt: u32,
+    errors: &[(SocketAddr, io::Error)],
+) -> Option<Duration> {
+    let retry = retry?;
+    let in_use = errors
+        .iter()
+        .any(|(_, err)| err.kind() == io::ErrorKind::AddrInUse);
+    if in_use && attempt < retry.attempts {
+        Some(retry.initial_delay * 2u32.saturating_pow(attempt))
+    } else {
+        None
+    }
+}
+
+fn bind_error(errors: Vec<(SocketAddr, io::Error)>) -> io::Error {
+    if errors.is_empty() {
+        return io::Error::new(io::ErrorKind::InvalidInput, "no addresses to bind");
+    }
+
+    let kind = errors[0].1.kind();
+    let kind = if errors.iter().all(|(_, err)| err.kind() == kind) {
+        kind
+    } else {
+        io::ErrorKind::Other
+    };
+    let message = errors
+        .iter()
+        .map(|(addr, err)| format!("{}: {}", addr, err))
+        .collect::<Vec<_>>()
+        .join(", ");
+    io::Error::new(kind, format!("failed to bind the listener ({})", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn localhost_is_bound_to_every_resolved_address() {
+        let addrs = resolve("localhost:0").await.unwrap();
+        assert!(!addrs.is_empty());
+
+        let listeners = TcpConfig::new().bind_all(&addrs, None).await.unwrap();
+        let bound: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+
+        // The addresses that the host cannot bind (e.g. `::1` without IPv6) are skipped.
+        let bindable: HashSet<_> = addrs
+            .iter()
+            .filter(|addr| TcpConfig::new().bind(addr).is_ok())
+            .map(SocketAddr::ip)
+            .collect();
+        assert_eq!(
+            bound.iter().map(SocketAddr::ip).collect::<HashSet<_>>(),
+            bindable
+        );
+
+        let port = bound[0].port();
+        assert_ne!(port, 0);
+        assert!(bound.iter().all(|addr| addr.port() == port), "{:?}", bound);
+    }
+
+    #[tokio::test]
+    async fn error_describes_every_address_when_all_fail() {
+        let first = TcpConfig::new()
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let second = TcpConfig::new()
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addrs = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+        let err = TcpConfig::new().bind_all(&addrs, None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let message = err.to_string();
+        for addr in &addrs {
+            assert!(message.contains(&addr.to_string()), "{}", message);
+        }
+
+        let err = TcpConfig::new().bind_any(&addrs, None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn different_failures_are_reported_as_other() {
+        let in_use = TcpConfig::new()
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        // An address from TEST-NET-1, which is never assigned to the host.
+        let addrs = [in_use.local_addr().unwrap(), "192.0.2.1:0".parse().unwrap()];
+
+        let err = TcpConfig::new().bind_all(&addrs, None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other, "{}", err);
+    }
+
+    #[test]
+    fn retry_delay_doubles_until_the_attempts_run_out() {
+        let retry = Some(BindRetry::new(3, Duration::from_millis(10)));
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let in_use = [(addr, io::Error::from(io::ErrorKind::AddrInUse))];
+        let delays: Vec<_> = (0..4)
+            .map(|attempt| retry_delay(retry, attempt, &in_use))
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_millis(10)),
+                Some(Duration::from_millis(20)),
+                Some(Duration::from_millis(40)),
+                None,
+            ]
+        );
+
+        let denied = [(addr, io::Error::from(io::ErrorKind::PermissionDenied))];
+        assert_eq!(retry_delay(retry, 0, &denied), None);
+        assert_eq!(retry_delay(None, 0, &in_use), None);
+    }
+
+    #[tokio::test]
+    async fn retry_succeeds_after_the_port_is_released() {
+        let in_use = TcpConfig::new()
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = in_use.local_addr().unwrap();
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(50)).await;
+            drop(in_use);
+        });
+
+        let retry = BindRetry::new(10, Duration::from_millis(20));
+        let listener = TcpConfig::new()
+            .bind_any(&[addr], Some(retry))
+            .await
+            .unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_the_configured_attempts() {
+        let in_use = TcpConfig::new()
+            .bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = in_use.local_addr().unwrap();
+
+        let start = std::time::Instant::now();
+        let retry = BindRetry::new(2, Duration::from_millis(20));
+        let err = TcpConfig::new()
+            .bind_all(&[addr], Some(retry))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        // Two retries, after 20ms and 40ms.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(60), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn empty_addresses_are_rejected() {
+        let err = TcpConfig::new().bind_all(&[], None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
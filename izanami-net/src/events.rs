@@ -0,0 +1,338 @@
+use http::{
+    header::{HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING},
+    request, HeaderMap, Request, Response, StatusCode,
+};
+use izanami::{
+    body::{BodyNotConsumed, BodyTimedOut, FromBodyNotConsumed, IncompleteBody},
+    finalize::InvalidResponseState,
+};
+use std::{any::Any, error, fmt};
+
+/// The error type returned from the `Events` of the servers.
+///
+/// `E` is the error type of the underlying protocol implementation.
+#[derive(Debug)]
+pub struct EventsError<E> {
+    kind: ErrorKind<E>,
+}
+
+#[derive(Debug)]
+enum ErrorKind<E> {
+    Protocol(E),
+    BodyTimedOut(BodyTimedOut),
+    IncompleteBody(IncompleteBody),
+    BodyNotConsumed(BodyNotConsumed),
+    InvalidResponseState(InvalidResponseState),
+}
+
+impl<E> EventsError<E> {
+    /// Creates an error that represents the request body idle timeout.
+    pub fn body_timed_out() -> Self {
+        Self {
+            kind: ErrorKind::BodyTimedOut(BodyTimedOut::new()),
+        }
+    }
+
+    /// Returns whether this error was caused by the request body idle timeout.
+    pub fn is_body_timed_out(&self) -> bool {
+        matches!(self.kind, ErrorKind::BodyTimedOut(..))
+    }
+
+    /// Creates an error that represents the mismatch between the length of
+    /// the request body and `Content-Length`.
+    pub fn incomplete_body(expected: u64, received: u64) -> Self {
+        Self {
+            kind: ErrorKind::IncompleteBody(IncompleteBody::new(expected, received)),
+        }
+    }
+
+    /// Returns whether this error was caused by the mismatch between
+    /// the length of the request body and `Content-Length`.
+    pub fn is_incomplete_body(&self) -> bool {
+        matches!(self.kind, ErrorKind::IncompleteBody(..))
+    }
+
+    /// Creates an error that represents requesting the trailers before
+    /// the end of the request body.
+    pub fn body_not_consumed() -> Self {
+        Self {
+            kind: ErrorKind::BodyNotConsumed(BodyNotConsumed::new()),
+        }
+    }
+
+    /// Returns whether this error was caused by requesting the trailers
+    /// before the end of the request body.
+    pub fn is_body_not_consumed(&self) -> bool {
+        matches!(self.kind, ErrorKind::BodyNotConsumed(..))
+    }
+
+    /// Creates an error that represents sending the response in an invalid order.
+    pub fn invalid_response_state(err: InvalidResponseState) -> Self {
+        Self {
+            kind: ErrorKind::InvalidResponseState(err),
+        }
+    }
+
+    /// Returns whether this error was caused by sending the response
+    /// in an invalid order.
+    pub fn is_invalid_response_state(&self) -> bool {
+        matches!(self.kind, ErrorKind::InvalidResponseState(..))
+    }
+}
+
+impl<E> From<E> for EventsError<E> {
+    fn from(err: E) -> Self {
+        Self {
+            kind: ErrorKind::Protocol(err),
+        }
+    }
+}
+
+impl<E> FromBodyNotConsumed for EventsError<E> {
+    fn from_body_not_consumed(err: BodyNotConsumed) -> Self {
+        Self {
+            kind: ErrorKind::BodyNotConsumed(err),
+        }
+    }
+}
+
+impl<E> fmt::Display for EventsError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Protocol(err) => fmt::Display::fmt(err, f),
+            ErrorKind::BodyTimedOut(err) => fmt::Display::fmt(err, f),
+            ErrorKind::IncompleteBody(err) => fmt::Display::fmt(err, f),
+            ErrorKind::BodyNotConsumed(err) => fmt::Display::fmt(err, f),
+            ErrorKind::InvalidResponseState(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl<E> error::Error for EventsError<E>
+where
+    E: error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Protocol(err) => Some(err),
+            ErrorKind::BodyTimedOut(err) => Some(err),
+            ErrorKind::IncompleteBody(err) => Some(err),
+            ErrorKind::BodyNotConsumed(err) => Some(err),
+            ErrorKind::InvalidResponseState(err) => Some(err),
+        }
+    }
+}
+
+/// Replaces the response with an empty `500 Internal Server Error` if the
+/// total size of its header fields exceeds `max`.
+///
+/// It returns whether the response has been replaced.
+pub fn limit_header_size(response: &mut Response<()>, max: usize) -> bool {
+    let size: usize = response
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    if size <= max {
+        return false;
+    }
+
+    if let Some((name, value)) = response
+        .headers()
+        .iter()
+        .max_by_key(|(name, value)| name.as_str().len() + value.len())
+    {
+        tracing::error!(
+            "the response header ({} bytes) exceeds the limit ({} bytes); the largest field is `{}` ({} bytes)",
+            size,
+            max,
+            name,
+            value.len()
+        );
+    }
+    *response = Response::new(());
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+    true
+}
+
+/// Copies the head of the request passed to the header finalizers.
+pub fn request_head(parts: &request::Parts) -> Request<()> {
+    let mut head = Request::new(());
+    *head.method_mut() = parts.method.clone();
+    *head.uri_mut() = parts.uri.clone();
+    *head.version_mut() = parts.version;
+    *head.headers_mut() = parts.headers.clone();
+    head
+}
+
+/// Returns the value of `Content-Length` of the request, if any.
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Returns whether the response is allowed to have a message body (RFC 7230, Section 3.3).
+pub fn can_have_body(is_head: bool, status: StatusCode) -> bool {
+    !is_head && status != StatusCode::NO_CONTENT && status != StatusCode::NOT_MODIFIED
+}
+
+/// Removes the header fields that frame the body from a response that must not have one.
+///
+/// `Transfer-Encoding` is always removed. `Content-Length` is removed from `204`
+/// and `304` responses, but kept for `HEAD` since it describes the body the
+/// response to `GET` would have.
+pub fn strip_body_headers(response: &mut Response<()>) {
+    response.headers_mut().remove(TRANSFER_ENCODING);
+    let status = response.status();
+    if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        response.headers_mut().remove(CONTENT_LENGTH);
+    }
+}
+
+/// Extracts the message from the payload of a panic.
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&'static str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "Box<Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    #[test]
+    fn invalid_response_state_is_distinguished() {
+        let err = EventsError::<std::io::Error>::invalid_response_state(
+            InvalidResponseState::already_started(),
+        );
+        assert!(err.is_invalid_response_state());
+        assert!(!err.is_body_timed_out());
+        assert!(error::Error::source(&err).is_some());
+
+        let err = EventsError::from(std::io::Error::from(std::io::ErrorKind::Other));
+        assert!(!err.is_invalid_response_state());
+    }
+
+    #[test]
+    fn incomplete_body_is_found_in_the_source() {
+        let err = EventsError::<std::io::Error>::incomplete_body(10, 5);
+        assert!(err.is_incomplete_body());
+        let source = error::Error::source(&err)
+            .and_then(|source| source.downcast_ref::<IncompleteBody>())
+            .unwrap();
+        assert_eq!((source.expected(), source.received()), (10, 5));
+    }
+
+    #[test]
+    fn header_within_limit_is_kept() {
+        let mut response = Response::new(());
+        response
+            .headers_mut()
+            .insert("x-small", HeaderValue::from_static("value"));
+        assert!(!limit_header_size(&mut response, 64));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key("x-small"));
+    }
+
+    #[test]
+    fn oversized_header_is_replaced_with_500() {
+        let mut response = Response::new(());
+        response
+            .headers_mut()
+            .insert("x-large", HeaderValue::from_str(&"a".repeat(100)).unwrap());
+        assert!(limit_header_size(&mut response, 64));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!response.headers().contains_key("x-large"));
+        assert_eq!(response.headers()[CONTENT_LENGTH], "0");
+    }
+
+    #[test]
+    fn header_size_counts_names_values_and_separators() {
+        // "x-a" (3) + "bc" (2) + ": " and CRLF (4)
+        let response = || {
+            let mut response = Response::new(());
+            response
+                .headers_mut()
+                .insert("x-a", HeaderValue::from_static("bc"));
+            response
+        };
+        assert!(!limit_header_size(&mut response(), 9));
+        assert!(limit_header_size(&mut response(), 8));
+    }
+
+    #[test]
+    fn content_length_is_parsed() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(content_length(&headers), None);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("42"));
+        assert_eq!(content_length(&headers), Some(42));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("-1"));
+        assert_eq!(content_length(&headers), None);
+    }
+
+    #[test]
+    fn bodies_are_forbidden_for_head_204_and_304() {
+        assert!(can_have_body(false, StatusCode::OK));
+        assert!(!can_have_body(true, StatusCode::OK));
+        assert!(!can_have_body(false, StatusCode::NO_CONTENT));
+        assert!(!can_have_body(false, StatusCode::NOT_MODIFIED));
+    }
+
+    #[test]
+    fn body_headers_are_stripped() {
+        let response = |status| {
+            let mut response = Response::new(());
+            *response.status_mut() = status;
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+            headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+            response
+        };
+
+        let mut ok = response(StatusCode::OK);
+        strip_body_headers(&mut ok);
+        assert_eq!(ok.headers()[CONTENT_LENGTH], "5");
+        assert!(!ok.headers().contains_key(TRANSFER_ENCODING));
+
+        for &status in &[StatusCode::NO_CONTENT, StatusCode::NOT_MODIFIED] {
+            let mut response = response(status);
+            strip_body_headers(&mut response);
+            assert!(response.headers().is_empty(), "{}", status);
+        }
+    }
+
+    #[test]
+    fn request_head_copies_everything_but_the_body() {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/path?query")
+            .header("x-key", "value")
+            .body("body")
+            .unwrap();
+        let (parts, _) = request.into_parts();
+        let head = request_head(&parts);
+        assert_eq!(head.method(), Method::POST);
+        assert_eq!(head.uri(), "/path?query");
+        assert_eq!(head.headers()["x-key"], "value");
+    }
+
+    #[test]
+    fn panic_message_is_extracted() {
+        let payload: Box<dyn Any + Send> = Box::new("static");
+        assert_eq!(panic_message(&*payload), "static");
+        let payload: Box<dyn Any + Send> = Box::new(String::from("owned"));
+        assert_eq!(panic_message(&*payload), "owned");
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(panic_message(&*payload), "Box<Any>");
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A set of counters of the body data buffered by the server.
+///
+/// The request-side counter accounts for the received chunks held by the
+/// server or the application, and the response-side counter accounts for
+/// the chunks queued to be written to the sockets. The value can be cloned
+/// and shared with a metrics exporter.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryGauge {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    request: AtomicUsize,
+    response: AtomicUsize,
+}
+
+impl MemoryGauge {
+    /// Creates a new `MemoryGauge` with zero counters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of bytes of the buffered request body chunks.
+    pub fn request_bytes(&self) -> usize {
+        self.inner.request.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes of the buffered response body chunks.
+    pub fn response_bytes(&self) -> usize {
+        self.inner.response.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes of the buffered chunks.
+    pub fn current_buffered_bytes(&self) -> usize {
+        self.request_bytes() + self.response_bytes()
+    }
+
+    /// Accounts `len` bytes of a request body chunk until the returned guard is dropped.
+    pub fn track_request(&self, len: usize) -> GaugeGuard {
+        self.inner.request.fetch_add(len, Ordering::Relaxed);
+        GaugeGuard {
+            inner: self.inner.clone(),
+            side: Side::Request,
+            len,
+        }
+    }
+
+    /// Accounts `len` bytes of a response body chunk until the returned guard is dropped.
+    pub fn track_response(&self, len: usize) -> GaugeGuard {
+        self.inner.response.fetch_add(len, Ordering::Relaxed);
+        GaugeGuard {
+            inner: self.inner.clone(),
+            side: Side::Response,
+            len,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Side {
+    Request,
+    Response,
+}
+
+/// The bytes accounted by `MemoryGauge`, released when dropped.
+#[derive(Debug)]
+pub struct GaugeGuard {
+    inner: Arc<Inner>,
+    side: Side,
+    len: usize,
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        let counter = match self.side {
+            Side::Request => &self.inner.request,
+            Side::Response => &self.inner.response,
+        };
+        counter.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::RateLimit;
+use futures::{
+    future::poll_fn,
+    task::{self, Poll},
+};
+use std::{fmt, future::Future, io, net::SocketAddr, pin::Pin, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    timer::{delay_for, Delay},
+};
+use tokio_net::driver::Handle;
+
+type ErrorCallback = Box<dyn Fn(&io::Error) + Send + Sync + 'static>;
+type AcceptCallback = Box<dyn Fn(&SocketAddr) + Send + Sync + 'static>;
+
+/// A stream of incoming TCP connections.
+///
+/// In addition to accepting connections from the listeners, this type
+/// applies the socket options to the accepted connections and handles
+/// the errors returned from `accept`.
+pub struct Incoming {
+    listeners: Vec<TcpListener>,
+    local_addrs: Vec<SocketAddr>,
+    next: usize,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
+    sleep_on_errors: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    on_accept: Option<AcceptCallback>,
+    on_error: Option<ErrorCallback>,
+    on_resource_exhausted: Option<ErrorCallback>,
+    timeout: Option<Delay>,
+}
+
+impl fmt::Debug for Incoming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("listeners", &self.listeners)
+            .field("local_addrs", &self.local_addrs)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("sleep_on_errors", &self.sleep_on_errors)
+            .field("rate_limit", &self.rate_limit)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Incoming {
+    /// Creates an `Incoming` from a listener registered to the event loop.
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        Self::from_listeners(vec![listener])
+    }
+
+    /// Creates an `Incoming` that accepts the connections from all of the listeners.
+    ///
+    /// The listeners are polled in turn, so that a busy listener does
+    /// not starve the others. An error is returned if `listeners` is empty.
+    pub fn from_listeners(listeners: Vec<TcpListener>) -> io::Result<Self> {
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no listeners to accept connections from",
+            ));
+        }
+        let local_addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            listeners,
+            local_addrs,
+            next: 0,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            sleep_on_errors: Some(Duration::from_secs(1)),
+            rate_limit: None,
+            on_accept: None,
+            on_error: None,
+            on_resource_exhausted: None,
+            timeout: None,
+        })
+    }
+
+    /// Creates an `Incoming` from a listener of the standard library.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        Self::from_std_listeners(vec![listener])
+    }
+
+    /// Creates an `Incoming` from listeners of the standard library.
+    pub fn from_std_listeners(listeners: Vec<std::net::TcpListener>) -> io::Result<Self> {
+        let handle = Handle::default();
+        Self::from_listeners(
+            listeners
+                .into_iter()
+                .map(|listener| TcpListener::from_std(listener, &handle))
+                .collect::<io::Result<_>>()?,
+        )
+    }
+
+    /// Returns the local address that the first listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Returns the local addresses that the listeners are bound to.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Sets whether to set `TCP_NODELAY` on the accepted connections.
+    ///
+    /// The default value is `false`.
+    pub fn set_nodelay(&mut self, enabled: bool) -> &mut Self {
+        self.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Sets the keepalive interval of the accepted connections.
+    ///
+    /// If `None` is specified, `SO_KEEPALIVE` is not enabled.
+    /// The default value is `None`.
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) -> &mut Self {
+        self.tcp_keepalive = keepalive;
+        self
+    }
+
+    /// Sets the interval to sleep before retrying when accepting
+    /// an incoming connection fails due to a resource error,
+    /// such as running out of file descriptors (`EMFILE`).
+    ///
+    /// If `None` is specified, the error is returned to the caller.
+    /// Errors specific to a single connection (e.g. `ECONNABORTED`) are
+    /// always ignored and the next connection is accepted.
+    ///
+    /// The default value is 1 second.
+    pub fn set_sleep_on_errors(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.sleep_on_errors = interval;
+        self
+    }
+
+    /// Sets the limit of the rate of accepting connections.
+    ///
+    /// By default, there is no limit.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) -> &mut Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Registers a callback invoked with the peer address of every accepted connection.
+    pub fn set_on_accept<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&SocketAddr) + Send + Sync + 'static,
+    {
+        self.on_accept = Some(Box::new(f));
+        self
+    }
+
+    /// Registers a callback invoked with every error returned from `accept`,
+    /// before the error is skipped, slept on or returned.
+    pub fn set_on_error<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+
+    /// Registers a callback invoked when `accept` fails because the process
+    /// or the system has run out of file descriptors (`EMFILE`/`ENFILE`).
+    ///
+    /// The callback is invoked after the one registered by `set_on_error`,
+    /// and can be used to release the descriptors, e.g. by closing idle
+    /// connections.
+    pub fn set_on_resource_exhausted<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.on_resource_exhausted = Some(Box::new(f));
+        self
+    }
+
+    /// Polls to accept an incoming connection.
+    pub fn poll_accept(
+        &mut self,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        if let Some(timeout) = &mut self.timeout {
+            futures::ready!(Pin::new(timeout).poll(cx));
+            self.timeout = None;
+        }
+
+        if let Some(rate_limit) = &mut self.rate_limit {
+            futures::ready!(rate_limit.poll_ready(cx));
+        }
+
+        loop {
+            let result = futures::ready!(self.poll_accept_any(cx));
+
+            match result {
+                Ok((socket, addr)) => {
+                    if let Some(rate_limit) = &mut self.rate_limit {
+                        rate_limit.take();
+                    }
+                    if let Err(err) = socket.set_nodelay(self.tcp_nodelay) {
+                        tracing::debug!("failed to set TCP_NODELAY: {}", err);
+                    }
+                    if let Err(err) = socket.set_keepalive(self.tcp_keepalive) {
+                        tracing::debug!("failed to set SO_KEEPALIVE: {}", err);
+                    }
+                    if let Some(on_accept) = &self.on_accept {
+                        on_accept(&addr);
+                    }
+                    return Poll::Ready(Ok((socket, addr)));
+                }
+                Err(ref err) if is_connection_error(err) => {
+                    self.notify_error(err);
+                    tracing::debug!("accepted connection already errored: {}", err);
+                    continue;
+                }
+                Err(err) => {
+                    self.notify_error(&err);
+                    match self.sleep_on_errors {
+                        Some(interval) => {
+                            tracing::error!("accept error: {}", err);
+                            let mut timeout = delay_for(interval);
+                            match Pin::new(&mut timeout).poll(cx) {
+                                Poll::Ready(()) => continue,
+                                Poll::Pending => {
+                                    self.timeout = Some(timeout);
+                                    return Poll::Pending;
+                                }
+                            }
+                        }
+                        None => return Poll::Ready(Err(err)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accepts an incoming connection.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    fn poll_accept_any(
+        &mut self,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        let len = self.listeners.len();
+        for i in 0..len {
+            let index = (self.next + i) % len;
+            let accept = self.listeners[index].accept();
+            futures::pin_mut!(accept);
+            if let Poll::Ready(result) = accept.poll(cx) {
+                self.next = (index + 1) % len;
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    }
+
+    fn notify_error(&self, err: &io::Error) {
+        if let Some(on_error) = &self.on_error {
+            on_error(err);
+        }
+        if is_resource_exhausted(err) {
+            if let Some(on_resource_exhausted) = &self.on_resource_exhausted {
+                on_resource_exhausted(err);
+            }
+        }
+    }
+}
+
+/// Returns whether the error returned from `accept` is specific to
+/// the incoming connection, rather than to the listener itself.
+fn is_connection_error(err: &io::Error) -> bool {
+    let kind = err.kind();
+    kind == io::ErrorKind::ConnectionRefused
+        || kind == io::ErrorKind::ConnectionAborted
+        || kind == io::ErrorKind::ConnectionReset
+}
+
+/// Returns whether the error returned from `accept` is caused by running out of
+/// file descriptors (`EMFILE` or `ENFILE`).
+#[cfg(unix)]
+fn is_resource_exhausted(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => code == libc::EMFILE || code == libc::ENFILE,
+        None => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_resource_exhausted(_: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    #[test]
+    fn errors_of_a_single_connection_are_distinguished() {
+        for &kind in &[
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+        ] {
+            let err = io::Error::from(kind);
+            assert!(is_connection_error(&err), "{:?}", kind);
+            assert!(!is_resource_exhausted(&err), "{:?}", kind);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_out_of_file_descriptors_is_a_resource_error() {
+        for &code in &[libc::EMFILE, libc::ENFILE] {
+            let err = io::Error::from_raw_os_error(code);
+            assert!(is_resource_exhausted(&err), "{}", err);
+            assert!(!is_connection_error(&err), "{}", err);
+        }
+    }
+
+    #[test]
+    fn other_errors_are_neither() {
+        let errors = vec![
+            io::Error::from(io::ErrorKind::PermissionDenied),
+            io::Error::from(io::ErrorKind::Other),
+            #[cfg(unix)]
+            io::Error::from_raw_os_error(libc::ENOBUFS),
+        ];
+        for err in &errors {
+            assert!(!is_connection_error(err), "{}", err);
+            assert!(!is_resource_exhausted(err), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn on_accept_is_called_with_the_peer_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut incoming = Incoming::from_std(listener).unwrap();
+        let log = Log::default();
+        let accepted = log.clone();
+        incoming.set_on_accept(move |addr| accepted.lock().unwrap().push(addr.to_string()));
+
+        let client = TcpStream::connect(&incoming.local_addr()).await.unwrap();
+        let (_, addr) = incoming.accept().await.unwrap();
+        assert_eq!(addr, client.local_addr().unwrap());
+        assert_eq!(*log.lock().unwrap(), [addr.to_string()]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn error_callbacks_are_called_by_the_kind_of_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut incoming = Incoming::from_std(listener).unwrap();
+        let log = Log::default();
+        let (errors, exhausted) = (log.clone(), log.clone());
+        incoming
+            .set_on_error(move |err| {
+                errors
+                    .lock()
+                    .unwrap()
+                    .push(format!("error: {:?}", err.kind()))
+            })
+            .set_on_resource_exhausted(move |err| {
+                let code = err.raw_os_error().unwrap();
+                exhausted
+                    .lock()
+                    .unwrap()
+                    .push(format!("exhausted: {}", code));
+            });
+
+        incoming.notify_error(&io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(*log.lock().unwrap(), ["error: ConnectionReset"]);
+
+        log.lock().unwrap().clear();
+        let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+        incoming.notify_error(&emfile);
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                format!("error: {:?}", emfile.kind()),
+                format!("exhausted: {}", libc::EMFILE),
+            ]
+        );
+    }
+}
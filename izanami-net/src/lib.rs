@@ -0,0 +1,186 @@
+//! Networking utilities shared by the server implementations.
+
+#![deny(
+    missing_debug_implementations,
+    nonstandard_style,
+    rust_2018_idioms,
+    rust_2018_compatibility,
+    unused
+)]
+#![forbid(clippy::unimplemented)]
+#![cfg_attr(test, deny(warnings))]
+
+mod bind;
+mod events;
+mod gauge;
+mod incoming;
+mod limit;
+#[cfg(unix)]
+mod listen_fd;
+mod rate_limit;
+#[cfg(unix)]
+mod sharded;
+mod tracker;
+
+pub use crate::{
+    bind::{resolve, BindRetry},
+    events::{
+        can_have_body, content_length, limit_header_size, panic_message, request_head,
+        strip_body_headers, EventsError,
+    },
+    gauge::{GaugeGuard, MemoryGauge},
+    incoming::Incoming,
+    limit::{ConnectionGuard, ConnectionLimit},
+    rate_limit::RateLimit,
+    tracker::{TaskGuard, TaskTracker},
+};
+
+#[cfg(unix)]
+pub use crate::{listen_fd::listen_fd, sharded::run_sharded};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+
+/// The configuration of TCP listener sockets.
+///
+/// The options are applied to the socket before it starts listening,
+/// which is required for some of them (e.g. `SO_REUSEPORT`) to take effect.
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    reuse_addr: bool,
+    #[cfg(unix)]
+    reuse_port: bool,
+    backlog: i32,
+    only_v6: Option<bool>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpConfig {
+    /// Creates a new `TcpConfig` with the same defaults as `std::net::TcpListener::bind`.
+    pub fn new() -> Self {
+        Self {
+            reuse_addr: cfg!(unix),
+            #[cfg(unix)]
+            reuse_port: false,
+            backlog: 128,
+            only_v6: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+
+    /// Sets whether to set `SO_REUSEADDR` on the socket.
+    ///
+    /// The default value is `true` on Unix platforms and `false` otherwise.
+    pub fn reuse_addr(&mut self, enabled: bool) -> &mut Self {
+        self.reuse_addr = enabled;
+        self
+    }
+
+    /// Sets whether to set `SO_REUSEPORT` on the socket.
+    ///
+    /// Enabling this option allows multiple processes to bind the same address,
+    /// and the kernel distributes incoming connections between them.
+    ///
+    /// The default value is `false`.
+    #[cfg(unix)]
+    pub fn reuse_port(&mut self, enabled: bool) -> &mut Self {
+        self.reuse_port = enabled;
+        self
+    }
+
+    /// Sets the maximum length of the queue of pending connections.
+    ///
+    /// The default value is `128`.
+    pub fn backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Sets the value of `IPV6_V6ONLY`.
+    ///
+    /// This option is only applied to IPv6 addresses. If it is not set,
+    /// the platform default is used.
+    pub fn only_v6(&mut self, only_v6: bool) -> &mut Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Sets the size of the receive buffer (`SO_RCVBUF`).
+    ///
+    /// Accepted sockets inherit this value from the listener.
+    pub fn recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the send buffer (`SO_SNDBUF`).
+    ///
+    /// Accepted sockets inherit this value from the listener.
+    pub fn send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Creates a TCP listener bound to the specified address with this configuration.
+    pub fn bind(&self, addr: &SocketAddr) -> io::Result<TcpListener> {
+        let domain = match addr {
+            SocketAddr::V4(..) => Domain::ipv4(),
+            SocketAddr::V6(..) => Domain::ipv6(),
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+        if let (SocketAddr::V6(..), Some(only_v6)) = (addr, self.only_v6) {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket.set_reuse_address(self.reuse_addr)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(self.reuse_port)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        socket.bind(&(*addr).into())?;
+        socket.listen(self.backlog)?;
+
+        Ok(socket.into_tcp_listener())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_port_allows_two_listeners_on_one_port() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut config = TcpConfig::new();
+        config.reuse_port(true);
+        let first = config.bind(&addr).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = config.bind(&addr).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn port_in_use_is_rejected_without_reuse_port() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let first = TcpConfig::new().bind(&addr).unwrap();
+        let addr = first.local_addr().unwrap();
+        let err = TcpConfig::new().bind(&addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,104 @@
+use futures::{
+    future::poll_fn,
+    task::{self, AtomicWaker, Poll},
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A counter that limits the number of concurrent connections.
+///
+/// The accept loop acquires a `ConnectionGuard` before accepting each
+/// connection and hands it over to the connection task. The slot is
+/// released when the guard is dropped.
+///
+/// Only a single task (i.e. the accept loop) is expected to wait for
+/// the slots at a time.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max: usize,
+    active: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl ConnectionLimit {
+    /// Creates a new `ConnectionLimit` that allows up to `max` connections.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max` is zero.
+    pub fn new(max: usize) -> Self {
+        assert!(
+            max > 0,
+            "the maximum number of connections must be positive"
+        );
+        Self {
+            inner: Arc::new(Inner {
+                max,
+                active: AtomicUsize::new(0),
+                waker: AtomicWaker::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of the connections currently holding a slot.
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::Acquire)
+    }
+
+    /// Polls to acquire a slot for a new connection.
+    pub fn poll_acquire(&self, cx: &mut task::Context<'_>) -> Poll<ConnectionGuard> {
+        if let Some(guard) = self.try_acquire() {
+            return Poll::Ready(guard);
+        }
+        self.inner.waker.register(cx.waker());
+        match self.try_acquire() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Acquires a slot for a new connection.
+    pub async fn acquire(&self) -> ConnectionGuard {
+        poll_fn(|cx| self.poll_acquire(cx)).await
+    }
+
+    fn try_acquire(&self) -> Option<ConnectionGuard> {
+        let mut active = self.inner.active.load(Ordering::Acquire);
+        while active < self.inner.max {
+            match self.inner.active.compare_exchange_weak(
+                active,
+                active + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(..) => {
+                    return Some(ConnectionGuard {
+                        inner: self.inner.clone(),
+                    })
+                }
+                Err(current) => active = current,
+            }
+        }
+        None
+    }
+}
+
+/// A slot of `ConnectionLimit` held by an active connection.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.inner.active.fetch_sub(1, Ordering::AcqRel);
+        self.inner.waker.wake();
+    }
+}
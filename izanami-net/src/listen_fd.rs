@@ -0,0 +1,146 @@
+use std::{
+    env, io, mem,
+    net::TcpListener,
+    os::unix::io::{FromRawFd, RawFd},
+};
+
+/// The first file descriptor passed by the service manager (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes a listening TCP socket passed by systemd's socket activation.
+///
+/// `index` is the position of the socket among those passed to the process,
+/// i.e. the socket is the file descriptor `3 + index`. The `LISTEN_PID` and
+/// `LISTEN_FDS` environment variables are checked to ensure that the socket
+/// is passed to this process, and the descriptor is checked to be a listening
+/// TCP socket. The descriptor is marked close-on-exec so that it is not
+/// leaked to child processes.
+///
+/// The returned listener owns the descriptor, so this function should be called
+/// at most once for each index.
+pub fn listen_fd(index: usize) -> io::Result<TcpListener> {
+    let count = listen_fds()?;
+    if index >= count {
+        return Err(error(format!(
+            "LISTEN_FDS is {}, so there is no socket at index {}",
+            count, index
+        )));
+    }
+    let fd = LISTEN_FDS_START + index as RawFd;
+
+    check_listening_tcp_socket(fd)?;
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// Returns the number of sockets passed to this process.
+fn listen_fds() -> io::Result<usize> {
+    let pid = env_var("LISTEN_PID")?;
+    let pid: libc::pid_t = pid
+        .parse()
+        .map_err(|_| error(format!("LISTEN_PID is not a process ID: {:?}", pid)))?;
+    let current = unsafe { libc::getpid() };
+    if pid != current {
+        return Err(error(format!(
+            "LISTEN_PID ({}) does not match the current process ({})",
+            pid, current
+        )));
+    }
+
+    let count = env_var("LISTEN_FDS")?;
+    count
+        .parse()
+        .map_err(|_| error(format!("LISTEN_FDS is not a number: {:?}", count)))
+}
+
+fn env_var(name: &str) -> io::Result<String> {
+    env::var(name).map_err(|err| match err {
+        env::VarError::NotPresent => error(format!(
+            "{} is not set; the process is not socket-activated",
+            name
+        )),
+        env::VarError::NotUnicode(..) => error(format!("{} is not valid Unicode", name)),
+    })
+}
+
+fn check_listening_tcp_socket(fd: RawFd) -> io::Result<()> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+        let err = io::Error::last_os_error();
+        return Err(error(format!(
+            "file descriptor {} is not available: {}",
+            fd, err
+        )));
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(error(format!("file descriptor {} is not a socket", fd)));
+    }
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) }
+        == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET | libc::AF_INET6 => {}
+        libc::AF_UNIX => {
+            return Err(error(format!(
+                "file descriptor {} is a Unix domain socket, but only TCP sockets are supported",
+                fd
+            )));
+        }
+        family => {
+            return Err(error(format!(
+                "file descriptor {} has an unsupported address family ({})",
+                fd, family
+            )));
+        }
+    }
+
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        return Err(error(format!(
+            "file descriptor {} is not a stream socket",
+            fd
+        )));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(error(format!(
+                "file descriptor {} is not a listening socket",
+                fd
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn socket_option(fd: RawFd, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
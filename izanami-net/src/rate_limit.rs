@@ -0,0 +1,186 @@
+use futures::{
+    ready,
+    task::{self, Poll},
+};
+use std::{cmp, future::Future, pin::Pin, time::Duration};
+use tokio::{
+    clock,
+    timer::{delay, Delay},
+};
+
+/// A token bucket that limits the rate of accepting incoming connections.
+///
+/// The bucket holds at most `burst` tokens and is refilled with `rate` tokens
+/// per second. Each accepted connection consumes a token, and the server
+/// stops accepting while the bucket is empty. The pending connections are kept
+/// in the backlog of the listener socket in the meantime.
+#[derive(Debug)]
+pub struct RateLimit {
+    period: Duration,
+    burst: u32,
+    tokens: u32,
+    last_refill: std::time::Instant,
+    delay: Option<Delay>,
+}
+
+impl RateLimit {
+    /// Creates a new `RateLimit` that allows `rate` connections per second
+    /// with bursts of up to `burst` connections.
+    ///
+    /// A `rate` higher than one connection per nanosecond is treated as
+    /// one connection per nanosecond.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `rate` or `burst` is zero.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        assert!(rate > 0, "the rate must be positive");
+        assert!(burst > 0, "the burst size must be positive");
+        Self {
+            period: cmp::max(Duration::from_secs(1) / rate, Duration::from_nanos(1)),
+            burst,
+            tokens: burst,
+            last_refill: clock::now(),
+            delay: None,
+        }
+    }
+
+    /// Polls whether a token is available, without taking it.
+    pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        loop {
+            self.refill();
+            if self.tokens > 0 {
+                self.delay = None;
+                return Poll::Ready(());
+            }
+
+            let deadline = self.last_refill + self.period;
+            let delay = self.delay.get_or_insert_with(|| delay(deadline));
+            ready!(Pin::new(delay).poll(cx));
+            self.delay = None;
+        }
+    }
+
+    /// Takes a token from the bucket.
+    ///
+    /// This method should be called after `poll_ready` returns `Ready`.
+    /// If the bucket is empty, it does nothing.
+    pub fn take(&mut self) {
+        self.refill();
+        self.tokens = self.tokens.saturating_sub(1);
+    }
+
+    fn refill(&mut self) {
+        let now = clock::now();
+        if self.tokens == self.burst {
+            self.last_refill = now;
+            return;
+        }
+
+        let elapsed = now.duration_since(self.last_refill);
+        let count = elapsed.as_nanos() / self.period.as_nanos();
+        if count > 0 {
+            let count = cmp::min(count, u128::from(self.burst - self.tokens)) as u32;
+            self.tokens += count;
+            self.last_refill += self.period * count;
+            if self.tokens == self.burst {
+                self.last_refill = now;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::poll_fn;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+    use tokio_timer::clock::{self, Clock, Now};
+
+    #[derive(Clone)]
+    struct MockNow(Arc<Mutex<Instant>>);
+
+    impl Now for MockNow {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    impl MockNow {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    /// Takes all the available tokens and returns their number.
+    fn accept_all(limit: &mut RateLimit, queued: &mut usize) -> usize {
+        let mut accepted = 0;
+        while *queued > 0 {
+            limit.refill();
+            if limit.tokens == 0 {
+                break;
+            }
+            limit.take();
+            *queued -= 1;
+            accepted += 1;
+        }
+        accepted
+    }
+
+    #[test]
+    fn queued_connections_are_accepted_at_the_rate() {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        clock::with_default(&Clock::new_with_now(now.clone()), || {
+            let mut limit = RateLimit::new(2, 2);
+            let mut queued = 5;
+
+            // The burst is accepted immediately, and then one every 500ms.
+            assert_eq!(accept_all(&mut limit, &mut queued), 2);
+            now.advance(Duration::from_millis(499));
+            assert_eq!(accept_all(&mut limit, &mut queued), 0);
+            for _ in 0..3 {
+                now.advance(Duration::from_millis(1));
+                assert_eq!(accept_all(&mut limit, &mut queued), 1);
+                now.advance(Duration::from_millis(499));
+            }
+            assert_eq!(queued, 0);
+        });
+    }
+
+    #[test]
+    fn tokens_do_not_exceed_the_burst() {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        clock::with_default(&Clock::new_with_now(now.clone()), || {
+            let mut limit = RateLimit::new(2, 2);
+            let mut queued = 10;
+            assert_eq!(accept_all(&mut limit, &mut queued), 2);
+            now.advance(Duration::from_secs(60));
+            assert_eq!(accept_all(&mut limit, &mut queued), 2);
+        });
+    }
+
+    #[test]
+    fn very_high_rate_does_not_panic() {
+        let now = MockNow(Arc::new(Mutex::new(Instant::now())));
+        clock::with_default(&Clock::new_with_now(now.clone()), || {
+            let mut limit = RateLimit::new(u32::MAX, 1);
+            assert_eq!(limit.period, Duration::from_nanos(1));
+            let mut queued = 2;
+            assert_eq!(accept_all(&mut limit, &mut queued), 1);
+            now.advance(Duration::from_nanos(1));
+            assert_eq!(accept_all(&mut limit, &mut queued), 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn poll_ready_waits_for_the_next_token() {
+        let mut limit = RateLimit::new(20, 1);
+        limit.take();
+        let start = Instant::now();
+        poll_fn(|cx| limit.poll_ready(cx)).await;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,91 @@
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    pin_mut,
+};
+use std::{
+    error,
+    future::Future,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc},
+    thread,
+};
+use tokio::runtime::current_thread::Runtime;
+
+/// Runs `num_workers` servers, each on its own thread with a single-threaded runtime.
+///
+/// The function `f` is called on each worker thread with the index of the
+/// worker, and the returned future is driven to completion on the runtime of
+/// that thread. Each server is expected to bind its own listener on the same
+/// address with `TcpConfig::reuse_port` enabled, so that the kernel distributes
+/// the incoming connections between the workers.
+///
+/// When any of the workers stops (either by an error, a panic, or a normal
+/// completion), the other workers are stopped as well and this function returns
+/// the result of the first stopped worker.
+///
+/// # Panics
+///
+/// This function panics if `num_workers` is zero.
+pub fn run_sharded<F, Fut, E>(num_workers: usize, f: F) -> io::Result<()>
+where
+    F: Fn(usize) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>>,
+    E: Into<Box<dyn error::Error + Send + Sync>>,
+{
+    assert!(num_workers > 0, "the number of workers must be positive");
+
+    let f = Arc::new(f);
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    let mut workers = Vec::with_capacity(num_workers);
+    for index in 0..num_workers {
+        let f = f.clone();
+        let stopped_tx = stopped_tx.clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let handle = thread::Builder::new()
+            .name(format!("izanami-worker-{}", index))
+            .spawn(move || {
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| run_worker(index, &*f, shutdown_rx)))
+                        .unwrap_or_else(|_| Err(io::Error::other("the worker panicked")));
+                let _ = stopped_tx.send((index, result));
+            })?;
+        workers.push((shutdown_tx, handle));
+    }
+    drop(stopped_tx);
+
+    let result = match stopped_rx.recv() {
+        Ok((index, result)) => {
+            if let Err(ref err) = result {
+                tracing::error!("worker {} stopped with an error: {}", index, err);
+            }
+            result
+        }
+        Err(..) => Ok(()),
+    };
+
+    for (shutdown_tx, handle) in workers {
+        let _ = shutdown_tx.send(());
+        let _ = handle.join();
+    }
+
+    result
+}
+
+fn run_worker<F, Fut, E>(index: usize, f: &F, shutdown: oneshot::Receiver<()>) -> io::Result<()>
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Into<Box<dyn error::Error + Send + Sync>>,
+{
+    let mut runtime = Runtime::new()?;
+    runtime.block_on(async move {
+        let serve = f(index);
+        pin_mut!(serve);
+        match future::select(serve, shutdown).await {
+            Either::Left((result, _)) => result.map_err(io::Error::other),
+            Either::Right(..) => Ok(()),
+        }
+    })
+}
@@ -0,0 +1,78 @@
+use futures::{
+    future::poll_fn,
+    task::{self, AtomicWaker, Poll},
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A counter of the tasks spawned by a server, used to wait for them on shutdown.
+///
+/// Each task holds a `TaskGuard` while it is running, and the server waits
+/// until all the guards have been dropped.
+///
+/// Only a single task (i.e. the accept loop) is expected to wait for
+/// the tasks at a time.
+#[derive(Debug, Clone, Default)]
+pub struct TaskTracker {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    active: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl TaskTracker {
+    /// Creates a new `TaskTracker` with no active tasks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of the tasks currently holding a guard.
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::Acquire)
+    }
+
+    /// Registers a new task.
+    pub fn track(&self) -> TaskGuard {
+        self.inner.active.fetch_add(1, Ordering::AcqRel);
+        TaskGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Polls whether all the tasks have finished.
+    pub fn poll_idle(&self, cx: &mut task::Context<'_>) -> Poll<()> {
+        if self.active() == 0 {
+            return Poll::Ready(());
+        }
+        self.inner.waker.register(cx.waker());
+        if self.active() == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Waits until all the tasks have finished.
+    pub async fn wait_idle(&self) {
+        poll_fn(|cx| self.poll_idle(cx)).await
+    }
+}
+
+/// A registration of a running task in `TaskTracker`.
+#[derive(Debug)]
+pub struct TaskGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.inner.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.inner.waker.wake();
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Ready-made applications.
+
+use crate::{body::Aggregate, App, Events};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use http::{
+    header::{CONTENT_LENGTH, HOST, LOCATION},
+    uri::Authority,
+    Request, Response, StatusCode,
+};
+use std::{collections::HashMap, fmt, future::Future, net::IpAddr};
+
+/// Creates an application that redirects every request to the equivalent `https://` URL.
+///
+/// The host of the redirect target is taken from the authority of the request URI
+/// or the `Host` header, and the path and query are preserved. If `https_port` is
+/// specified and is not the default port (443), it is appended to the host.
+///
+/// If the request has no host at all (e.g. an HTTP/1.0 request), the address
+/// of the listener that accepted the connection (`Events::local_addr`) is used
+/// instead. A request with an invalid `Host` header, or without a host when
+/// the local address is not available, is answered with `400 Bad Request`.
+///
+/// The application is typically served on port 80 alongside the main server.
+pub fn redirect_to_https(https_port: Option<u16>) -> RedirectToHttps {
+    RedirectToHttps { https_port }
+}
+
+/// An application that responds `301 Moved Permanently` to the `https://` URL.
+///
+/// The value of this type is created by `redirect_to_https`.
+#[derive(Debug, Clone)]
+pub struct RedirectToHttps {
+    https_port: Option<u16>,
+}
+
+impl RedirectToHttps {
+    fn location<E: Events>(&self, request: &Request<E>) -> Option<String> {
+        let has_host =
+            request.uri().authority_part().is_some() || request.headers().contains_key(HOST);
+        let host = if has_host {
+            request_host(request)?
+        } else {
+            match request.body().local_addr()?.ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{}]", ip),
+            }
+        };
+
+        let mut location = format!("https://{}", host);
+        match self.https_port {
+            Some(443) | None => {}
+            Some(port) => location += &format!(":{}", port),
+        }
+        match request.uri().path_and_query() {
+            Some(path_and_query) => location += path_and_query.as_str(),
+            None => location += "/",
+        }
+
+        Some(location)
+    }
+}
+
+#[async_trait]
+impl<E> App<E> for RedirectToHttps
+where
+    E: Events + Send,
+    E::Data: Send,
+{
+    type Error = E::Error;
+
+    async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+    where
+        E: 'async_trait,
+    {
+        let response = match self.location(&request) {
+            Some(location) => Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(LOCATION, location)
+                .body(()),
+            None => Response::builder().status(StatusCode::BAD_REQUEST).body(()),
+        }
+        .expect("should be a valid response");
+
+        let mut events = request.into_body();
+        events.start_send_response(response, true).await
+    }
+}
+
+/// An application that dispatches requests to the inner applications
+/// based on the host name of the request.
+///
+/// The host name is taken from the authority of the request URI (i.e.
+/// `:authority` in HTTP/2) or the `Host` header, and is compared
+/// case-insensitively without the port. A pattern starting with `*.`
+/// matches any subdomain of the rest of the pattern. Exact patterns are
+/// preferred over wildcard patterns, and wildcard patterns are tried in
+/// the order they were added.
+///
+/// If no application matches and no fallback application is set, the
+/// request is answered with `421 Misdirected Request`.
+///
+/// All inner applications must have the same type. Applications of
+/// different types can be combined by using a boxed trait object
+/// (e.g. `Box<dyn for<'a> App<izanami_hyper::Events<'a>, Error = ...>>`)
+/// as `T`.
+#[derive(Debug)]
+pub struct VirtualHosts<T> {
+    exact: HashMap<String, T>,
+    wildcards: Vec<(String, T)>,
+    fallback: Option<T>,
+}
+
+impl<T> Default for VirtualHosts<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> VirtualHosts<T> {
+    /// Creates an empty `VirtualHosts`.
+    pub fn new() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Registers an application that handles the requests to the specified host.
+    pub fn add(mut self, host: &str, app: T) -> Self {
+        let host = host.to_ascii_lowercase();
+        if host.starts_with("*.") {
+            self.wildcards.push((host[1..].to_owned(), app));
+        } else {
+            self.exact.insert(host, app);
+        }
+        self
+    }
+
+    /// Sets the application that handles the requests not matching any hosts.
+    pub fn fallback(mut self, app: T) -> Self {
+        self.fallback = Some(app);
+        self
+    }
+
+    fn find<E>(&self, request: &Request<E>) -> Option<&T> {
+        let host = request_host(request).map(|host| host.to_ascii_lowercase());
+        host.and_then(|host| {
+            self.exact.get(&host).or_else(|| {
+                self.wildcards
+                    .iter()
+                    .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(&**suffix))
+                    .map(|(_, app)| app)
+            })
+        })
+        .or(self.fallback.as_ref())
+    }
+}
+
+#[async_trait]
+impl<T, E> App<E> for VirtualHosts<T>
+where
+    T: App<E> + Send + Sync,
+    T::Error: From<E::Error>,
+    E: Events + Send,
+    E::Data: Send,
+{
+    type Error = T::Error;
+
+    async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+    where
+        E: 'async_trait,
+    {
+        if let Some(app) = self.find(&request) {
+            return app.call(request).await;
+        }
+
+        let response = Response::builder()
+            .status(StatusCode::MISDIRECTED_REQUEST)
+            .body(())
+            .expect("should be a valid response");
+        let mut events = request.into_body();
+        events.start_send_response(response, true).await?;
+        Ok(())
+    }
+}
+
+fn request_host<T>(request: &Request<T>) -> Option<String> {
+    match request.uri().authority_part() {
+        Some(authority) => Some(authority.host().to_owned()),
+        None => {
+            let host = request.headers().get(HOST)?.to_str().ok()?;
+            let authority = host.parse::<Authority>().ok()?;
+            Some(authority.host().to_owned())
+        }
+    }
+}
+
+/// Creates an application from a function that maps a request to a response.
+///
+/// The request body is collected into `Bytes` before calling the function.
+/// If the function returns an error, the error is logged and the client
+/// receives an empty `500 Internal Server Error` response instead, so the
+/// error type only needs to implement `Display`. The response is sent with
+/// `Events::send_response`, so `content-length` is set from the body unless
+/// the function sets it or the status is `204` or `304`.
+///
+/// The connection-level values (e.g. `conn::RemoteAddr`) are available
+/// from the extensions of the request.
+///
+/// The size of the request body is unlimited by default. Use
+/// `HandlerFn::max_body_size` to reject the large requests before they
+/// are buffered.
+pub fn handler_fn<F, Fut, B, Err>(f: F) -> HandlerFn<F>
+where
+    F: Fn(Request<Bytes>) -> Fut,
+    Fut: Future<Output = Result<Response<B>, Err>>,
+    B: Into<Bytes>,
+    Err: fmt::Display,
+{
+    HandlerFn {
+        f,
+        max_body_size: None,
+    }
+}
+
+/// An application created by `handler_fn`.
+#[derive(Debug, Clone)]
+pub struct HandlerFn<F> {
+    f: F,
+    max_body_size: Option<u64>,
+}
+
+impl<F> HandlerFn<F> {
+    /// Sets the maximum length of the request body in bytes.
+    ///
+    /// The request is answered with `413 Payload Too Large` without calling
+    /// the function if `Content-Length` exceeds the limit, or as soon as the
+    /// received chunks exceed it. The rest of the request body is not read.
+    pub fn max_body_size(mut self, max: u64) -> Self {
+        self.max_body_size = Some(max);
+        self
+    }
+
+    fn exceeds_max_body_size(&self, len: u64) -> bool {
+        matches!(self.max_body_size, Some(max) if len > max)
+    }
+}
+
+#[async_trait]
+impl<F, Fut, B, Err, E> App<E> for HandlerFn<F>
+where
+    F: Fn(Request<Bytes>) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Response<B>, Err>> + Send,
+    B: Into<Bytes>,
+    Err: fmt::Display,
+    E: Events + Send,
+    E::Data: Into<Bytes> + Send,
+{
+    type Error = E::Error;
+
+    async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+    where
+        E: 'async_trait,
+    {
+        let (parts, mut events) = request.into_parts();
+
+        let content_length = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        if self.exceeds_max_body_size(content_length.unwrap_or(0)) {
+            return payload_too_large(&mut events).await;
+        }
+
+        let mut body = Aggregate::new();
+        loop {
+            let chunk = match events.data().await {
+                Some(chunk) => chunk?,
+                None => break,
+            };
+            body.push(chunk);
+            if self.exceeds_max_body_size(body.remaining() as u64) {
+                return payload_too_large(&mut events).await;
+            }
+        }
+        let request = Request::from_parts(parts, body.to_bytes());
+
+        let response = match (self.f)(request).await {
+            Ok(response) => response.map(Into::into),
+            Err(err) => {
+                tracing::error!("handler error: {}", err);
+                crate::response::status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+
+        events.send_response(response).await
+    }
+}
+
+async fn payload_too_large<E>(events: &mut E) -> Result<(), E::Error>
+where
+    E: Events + Send,
+    E::Data: Send,
+{
+    let response = crate::response::status(StatusCode::PAYLOAD_TOO_LARGE);
+    events.send_response(response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockEvents;
+    use futures::executor::block_on;
+
+    fn call<T>(app: T, events: &mut MockEvents) -> Response<()>
+    where
+        T: for<'a> App<&'a mut MockEvents>,
+    {
+        call_with(app, Request::new(()), events)
+    }
+
+    fn call_with<T>(app: T, request: Request<()>, events: &mut MockEvents) -> Response<()>
+    where
+        T: for<'a> App<&'a mut MockEvents>,
+    {
+        let request = request.map(|()| &mut *events);
+        block_on(app.call(request)).map_err(Into::into).unwrap();
+        assert!(events.finished);
+        events.response.take().unwrap()
+    }
+
+    fn redirect(https_port: Option<u16>, request: Request<()>) -> Response<()> {
+        call_with(
+            redirect_to_https(https_port),
+            request,
+            &mut MockEvents::default(),
+        )
+    }
+
+    /// An application that responds with its name.
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl<E> App<E> for Named
+    where
+        E: Events + Send,
+        E::Data: Send,
+    {
+        type Error = E::Error;
+
+        async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+        where
+            E: 'async_trait,
+        {
+            request
+                .into_body()
+                .send_response(Response::new(self.0))
+                .await
+        }
+    }
+
+    fn vhosts() -> VirtualHosts<Named> {
+        VirtualHosts::new()
+            .add("api.example.com", Named("api"))
+            .add("*.example.com", Named("wildcard"))
+            .add("*.static.example.com", Named("static"))
+    }
+
+    /// Dispatches a request with the `Host` header, and returns the status and the body.
+    fn dispatch(app: &VirtualHosts<Named>, host: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::get("/");
+        if let Some(host) = host {
+            request.header(HOST, host);
+        }
+        let mut events = MockEvents::default();
+        let response = call_with(app, request.body(()).unwrap(), &mut events);
+        let body = String::from_utf8(events.response_body).unwrap();
+        (response.status(), body)
+    }
+
+    #[test]
+    fn vhosts_dispatch_exact_hosts_case_insensitively() {
+        let app = vhosts();
+        assert_eq!(dispatch(&app, Some("api.example.com")).1, "api");
+        assert_eq!(dispatch(&app, Some("API.Example.COM:8080")).1, "api");
+    }
+
+    #[test]
+    fn vhosts_dispatch_wildcard_hosts_in_order() {
+        let app = vhosts();
+        assert_eq!(dispatch(&app, Some("www.example.com")).1, "wildcard");
+        // `*.example.com` was added first, so it takes precedence.
+        assert_eq!(dispatch(&app, Some("a.static.example.com")).1, "wildcard");
+    }
+
+    #[test]
+    fn vhosts_wildcard_does_not_match_the_bare_domain() {
+        let app = VirtualHosts::new().add("*.example.com", Named("wildcard"));
+        let (status, body) = dispatch(&app, Some("example.com"));
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+        assert_eq!(body, "");
+        let (status, _) = dispatch(&app, Some("badexample.com"));
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    #[test]
+    fn vhosts_use_the_authority_of_the_uri() {
+        let app = vhosts();
+        let request = Request::get("https://api.example.com/")
+            .header(HOST, "www.example.com")
+            .body(())
+            .unwrap();
+        let mut events = MockEvents::default();
+        call_with(&app, request, &mut events);
+        assert_eq!(events.response_body, b"api");
+    }
+
+    #[test]
+    fn vhosts_dispatch_unmatched_requests_to_the_fallback() {
+        let app = vhosts().fallback(Named("fallback"));
+        assert_eq!(dispatch(&app, Some("example.org")).1, "fallback");
+        assert_eq!(dispatch(&app, None).1, "fallback");
+    }
+
+    #[test]
+    fn vhosts_respond_421_without_fallback() {
+        let app = vhosts();
+        assert_eq!(
+            dispatch(&app, Some("example.org")).0,
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        assert_eq!(dispatch(&app, None).0, StatusCode::MISDIRECTED_REQUEST);
+    }
+
+    #[test]
+    fn redirect_preserves_the_path_and_query() {
+        let request = Request::get("/path?query=1")
+            .header(HOST, "example.com")
+            .body(())
+            .unwrap();
+        let response = redirect(None, request);
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://example.com/path?query=1"
+        );
+    }
+
+    #[test]
+    fn redirect_replaces_the_port() {
+        let request = || {
+            Request::get("/")
+                .header(HOST, "example.com:8080")
+                .body(())
+                .unwrap()
+        };
+        let location = |port| redirect(port, request()).headers()[LOCATION].clone();
+        assert_eq!(location(None), "https://example.com/");
+        assert_eq!(location(Some(443)), "https://example.com/");
+        assert_eq!(location(Some(8443)), "https://example.com:8443/");
+    }
+
+    #[test]
+    fn redirect_prefers_the_authority_of_the_uri() {
+        let request = Request::get("http://example.com/path")
+            .header(HOST, "other.example.com")
+            .body(())
+            .unwrap();
+        let response = redirect(None, request);
+        assert_eq!(response.headers()[LOCATION], "https://example.com/path");
+    }
+
+    #[test]
+    fn redirect_without_host_falls_back_to_the_local_address() {
+        let location = |https_port, local_addr: &str| {
+            let mut events = MockEvents {
+                local_addr: Some(local_addr.parse().unwrap()),
+                ..MockEvents::default()
+            };
+            let request = Request::get("/path").body(()).unwrap();
+            let response = call_with(redirect_to_https(https_port), request, &mut events);
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+            response.headers()[LOCATION].clone()
+        };
+        assert_eq!(location(None, "192.0.2.1:80"), "https://192.0.2.1/path");
+        assert_eq!(
+            location(Some(8443), "192.0.2.1:80"),
+            "https://192.0.2.1:8443/path"
+        );
+        assert_eq!(
+            location(None, "[2001:db8::1]:80"),
+            "https://[2001:db8::1]/path"
+        );
+    }
+
+    #[test]
+    fn redirect_without_host_nor_local_address_is_bad_request() {
+        let response = redirect(None, Request::get("/").body(()).unwrap());
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!response.headers().contains_key(LOCATION));
+    }
+
+    #[test]
+    fn redirect_with_invalid_host_is_bad_request() {
+        let mut events = MockEvents {
+            local_addr: Some("192.0.2.1:80".parse().unwrap()),
+            ..MockEvents::default()
+        };
+        let request = Request::get("/")
+            .header(HOST, "exa mple.com")
+            .body(())
+            .unwrap();
+        let response = call_with(redirect_to_https(None), request, &mut events);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn handler_fn_sends_the_collected_body() {
+        let app = handler_fn(|request: Request<Bytes>| async move {
+            Ok::<_, String>(Response::new(request.into_body()))
+        });
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        let response = call(app, &mut events);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        assert_eq!(events.response_body, b"hello");
+    }
+
+    #[test]
+    fn handler_fn_omits_content_length_for_no_content() {
+        let app = handler_fn(|_: Request<Bytes>| async move {
+            Ok::<_, String>(crate::response::no_content())
+        });
+        let mut events = MockEvents::default();
+        let response = call(app, &mut events);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert!(events.response_body.is_empty());
+    }
+
+    #[test]
+    fn handler_fn_responds_500_on_error() {
+        let app = handler_fn(|_: Request<Bytes>| async move { Err::<Response<Bytes>, _>("oops") });
+        let mut events = MockEvents::default();
+        let response = call(app, &mut events);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "0");
+        assert!(events.response_body.is_empty());
+    }
+
+    async fn echo(request: Request<Bytes>) -> Result<Response<Bytes>, String> {
+        Ok(Response::new(request.into_body()))
+    }
+
+    #[test]
+    fn handler_fn_accepts_the_body_within_the_limit() {
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        let response = call(handler_fn(echo).max_body_size(5), &mut events);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(events.response_body, b"hello");
+    }
+
+    #[test]
+    fn handler_fn_rejects_the_large_content_length() {
+        let request = Request::builder()
+            .header(CONTENT_LENGTH, "6")
+            .body(())
+            .unwrap();
+        let mut events = MockEvents::new(vec!["hel", "lo!"]);
+        let response = call_with(handler_fn(echo).max_body_size(5), request, &mut events);
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "0");
+        assert!(events.response_body.is_empty());
+        // the request body is not read at all.
+        assert_eq!(events.request_body.len(), 2);
+    }
+
+    #[test]
+    fn handler_fn_rejects_the_large_body_while_receiving() {
+        let mut events = MockEvents::new(vec!["hel", "lo!", "more"]);
+        let response = call(handler_fn(echo).max_body_size(5), &mut events);
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(events.response_body.is_empty());
+        // the chunks after the limit are not read.
+        assert_eq!(events.request_body.len(), 1);
+    }
+}
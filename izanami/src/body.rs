@@ -0,0 +1,962 @@
+//! Utilities for handling request bodies.
+
+use crate::Events;
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use http::{HeaderMap, Response};
+use iovec::IoVec;
+use std::{
+    collections::{vec_deque, VecDeque},
+    error, fmt,
+    iter::FromIterator,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+};
+
+/// A buffer that holds the received chunks without concatenating them.
+///
+/// Each chunk is kept as the original `Bytes`, so collecting a request body
+/// into this buffer does not copy the data when the chunk type of `Events`
+/// is convertible to `Bytes`. The contiguous representation can be obtained
+/// by `to_bytes` when it is actually needed.
+#[derive(Debug, Default, Clone)]
+pub struct Aggregate {
+    chunks: VecDeque<Bytes>,
+    remaining: usize,
+}
+
+impl Aggregate {
+    /// Creates an empty `Aggregate`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a chunk to the end of this buffer.
+    pub fn push<T>(&mut self, chunk: T)
+    where
+        T: Into<Bytes>,
+    {
+        let chunk = chunk.into();
+        if !chunk.is_empty() {
+            self.remaining += chunk.len();
+            self.chunks.push_back(chunk);
+        }
+    }
+
+    /// Returns the number of chunks held by this buffer.
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Returns the remaining data as a contiguous `Bytes`.
+    ///
+    /// The data is copied only if this buffer holds more than one chunk.
+    pub fn to_bytes(&self) -> Bytes {
+        match self.chunks.len() {
+            0 => Bytes::new(),
+            1 => self.chunks[0].clone(),
+            _ => {
+                let mut buf = BytesMut::with_capacity(self.remaining);
+                for chunk in &self.chunks {
+                    buf.extend_from_slice(chunk);
+                }
+                buf.freeze()
+            }
+        }
+    }
+}
+
+impl Buf for Aggregate {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.chunks.front().map_or(&[], |chunk| &chunk[..])
+    }
+
+    fn bytes_vec<'a>(&'a self, dst: &mut [&'a IoVec]) -> usize {
+        let mut n = 0;
+        for (dst, chunk) in dst.iter_mut().zip(&self.chunks) {
+            *dst = (&chunk[..]).into();
+            n += 1;
+        }
+        n
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(
+            cnt <= self.remaining,
+            "cannot advance past the end of buffer"
+        );
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let front = self.chunks.front_mut().expect("the buffer is not empty");
+            if cnt < front.len() {
+                front.advance(cnt);
+                break;
+            }
+            cnt -= front.len();
+            self.chunks.pop_front();
+        }
+    }
+}
+
+impl IntoIterator for Aggregate {
+    type Item = Bytes;
+    type IntoIter = vec_deque::IntoIter<Bytes>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.chunks.into_iter()
+    }
+}
+
+impl<T> Extend<T> for Aggregate
+where
+    T: Into<Bytes>,
+{
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        for chunk in iter {
+            self.push(chunk);
+        }
+    }
+}
+
+impl<T> FromIterator<T> for Aggregate
+where
+    T: Into<Bytes>,
+{
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut aggregate = Self::new();
+        aggregate.extend(iter);
+        aggregate
+    }
+}
+
+/// The error that the server reports when the client stops sending
+/// the request body for longer than the configured idle timeout.
+///
+/// The servers typically wrap this value into their own error type, and it
+/// can be found by traversing `Error::source`. The application should respond
+/// with `408 Request Timeout` when receiving this error, if possible.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BodyTimedOut(());
+
+impl BodyTimedOut {
+    /// Creates a new `BodyTimedOut`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Display for BodyTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out while waiting for the request body")
+    }
+}
+
+impl error::Error for BodyTimedOut {}
+
+/// The error that the server reports when the length of the request body
+/// does not match the value of `Content-Length`.
+///
+/// The servers typically wrap this value into their own error type, and it
+/// can be found by traversing `Error::source`. The application must not treat
+/// the received data as a complete request body when receiving this error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IncompleteBody {
+    expected: u64,
+    received: u64,
+}
+
+impl IncompleteBody {
+    /// Creates a new `IncompleteBody`.
+    pub fn new(expected: u64, received: u64) -> Self {
+        Self { expected, received }
+    }
+
+    /// Returns the length declared by `Content-Length`.
+    pub fn expected(&self) -> u64 {
+        self.expected
+    }
+
+    /// Returns the number of bytes actually received.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+impl fmt::Display for IncompleteBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the request body has {} bytes while Content-Length is {}",
+            self.received, self.expected
+        )
+    }
+}
+
+impl error::Error for IncompleteBody {}
+
+/// The error that the server reports when `Events::trailers` is called
+/// before `Events::data` returns `None`.
+///
+/// The servers typically wrap this value into their own error type, and it
+/// can be found by traversing `Error::source`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BodyNotConsumed(());
+
+impl BodyNotConsumed {
+    /// Creates a new `BodyNotConsumed`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Display for BodyNotConsumed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the trailers were requested before the end of the request body")
+    }
+}
+
+impl error::Error for BodyNotConsumed {}
+
+/// The error types that can be created from `BodyNotConsumed`.
+///
+/// The adaptors that hold back a part of the request body, such as `Replayable`,
+/// use this trait to report `BodyNotConsumed` by the error type of the
+/// underlying `Events`.
+pub trait FromBodyNotConsumed {
+    /// Creates an error value from `BodyNotConsumed`.
+    fn from_body_not_consumed(err: BodyNotConsumed) -> Self;
+}
+
+impl FromBodyNotConsumed for Box<dyn error::Error + Send + Sync> {
+    fn from_body_not_consumed(err: BodyNotConsumed) -> Self {
+        err.into()
+    }
+}
+
+impl FromBodyNotConsumed for Box<dyn error::Error> {
+    fn from_body_not_consumed(err: BodyNotConsumed) -> Self {
+        err.into()
+    }
+}
+
+/// Receives the remaining chunks of the request body and collects them into an `Aggregate`.
+pub async fn aggregate<E>(events: &mut E) -> Result<Aggregate, E::Error>
+where
+    E: Events + ?Sized,
+    E::Data: Into<Bytes>,
+{
+    let mut aggregate = Aggregate::new();
+    while let Some(chunk) = events.data().await {
+        aggregate.push(chunk?);
+    }
+    Ok(aggregate)
+}
+
+/// The reason why the callback registered by `on_drop` is invoked.
+#[derive(Debug)]
+pub enum BodyDropReason<'a, E> {
+    /// The response has been sent to the end of stream.
+    Completed,
+
+    /// The events were dropped before reaching the end of the response stream,
+    /// e.g. the client disconnected and the server stopped the application.
+    Cancelled,
+
+    /// An error occurred while sending the response.
+    Errored(&'a E),
+}
+
+/// An extension trait that provides the adaptors for `Events`.
+pub trait EventsExt: Events + Sized {
+    /// Registers a callback that is invoked exactly once when the response
+    /// stream is terminated.
+    ///
+    /// The callback is called on the first error while sending the response,
+    /// or when the returned value is dropped. It can be used to release the
+    /// resources associated with the response (database cursors, metric gauges,
+    /// etc.) even if the client disconnects in the middle of the stream.
+    fn on_drop<F>(self, f: F) -> WithCleanup<Self, F>
+    where
+        F: FnOnce(BodyDropReason<'_, Self::Error>),
+    {
+        WithCleanup {
+            events: self,
+            callback: Some(f),
+            completed: false,
+        }
+    }
+}
+
+impl<E: Events> EventsExt for E {}
+
+/// An `Events` that invokes a callback when the response stream is terminated.
+///
+/// The value of this type is created by `EventsExt::on_drop`.
+pub struct WithCleanup<E, F>
+where
+    E: Events,
+    F: FnOnce(BodyDropReason<'_, E::Error>),
+{
+    events: E,
+    callback: Option<F>,
+    completed: bool,
+}
+
+impl<E, F> fmt::Debug for WithCleanup<E, F>
+where
+    E: Events + fmt::Debug,
+    F: FnOnce(BodyDropReason<'_, E::Error>),
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WithCleanup")
+            .field("events", &self.events)
+            .field("completed", &self.completed)
+            .finish()
+    }
+}
+
+impl<E, F> WithCleanup<E, F>
+where
+    E: Events,
+    F: FnOnce(BodyDropReason<'_, E::Error>),
+{
+    /// Returns a reference to the underlying `Events`.
+    pub fn get_ref(&self) -> &E {
+        &self.events
+    }
+
+    /// Returns a mutable reference to the underlying `Events`.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.events
+    }
+
+    fn fire(&mut self, reason: BodyDropReason<'_, E::Error>) {
+        if let Some(callback) = self.callback.take() {
+            if panic::catch_unwind(AssertUnwindSafe(move || callback(reason))).is_err() {
+                tracing::error!("the cleanup callback of the response body panicked");
+            }
+        }
+    }
+
+    fn on_send_result(&mut self, result: &Result<(), E::Error>, end_of_stream: bool) {
+        match result {
+            Ok(()) => self.completed |= end_of_stream,
+            Err(err) => self.fire(BodyDropReason::Errored(err)),
+        }
+    }
+}
+
+impl<E, F> Drop for WithCleanup<E, F>
+where
+    E: Events,
+    F: FnOnce(BodyDropReason<'_, E::Error>),
+{
+    fn drop(&mut self) {
+        if self.completed {
+            self.fire(BodyDropReason::Completed);
+        } else {
+            self.fire(BodyDropReason::Cancelled);
+        }
+    }
+}
+
+#[async_trait]
+impl<E, F> Events for WithCleanup<E, F>
+where
+    E: Events + Send,
+    E::Data: Send,
+    F: FnOnce(BodyDropReason<'_, E::Error>) + Send,
+{
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.events.remote_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.events.local_addr()
+    }
+
+    async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        self.events.data().await
+    }
+
+    async fn trailers(&mut self) -> Result<Option<HeaderMap>, Self::Error> {
+        self.events.trailers().await
+    }
+
+    async fn start_send_response(
+        &mut self,
+        response: Response<()>,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        let result = self
+            .events
+            .start_send_response(response, end_of_stream)
+            .await;
+        self.on_send_result(&result, end_of_stream);
+        result
+    }
+
+    async fn send_data(
+        &mut self,
+        data: Self::Data,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        let result = self.events.send_data(data, end_of_stream).await;
+        self.on_send_result(&result, end_of_stream);
+        result
+    }
+
+    async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
+        let result = self.events.send_trailers(trailers).await;
+        self.on_send_result(&result, true);
+        result
+    }
+
+    async fn ready(&mut self) -> Result<(), Self::Error> {
+        let result = self.events.ready().await;
+        if let Err(ref err) = result {
+            self.fire(BodyDropReason::Errored(err));
+        }
+        result
+    }
+}
+
+/// An `Events` that records the received chunks so that the request body
+/// can be delivered again from the beginning.
+///
+/// This is useful for the middleware that needs to inspect a part of the
+/// request body (e.g. to verify a signature) before passing the request to
+/// the handler. The value can be inserted into a request by `Request::map`.
+///
+/// The recorded chunks are held until the value is dropped. If the total
+/// length of the chunks exceeds `max_buffer`, the recording stops and
+/// `rewind` fails afterwards. The trailers are recorded as well, and
+/// `trailers` returns them again once the replayed chunks have been
+/// received after `rewind`.
+#[derive(Debug)]
+pub struct Replayable<E> {
+    events: E,
+    buffered: Vec<Bytes>,
+    buffered_len: usize,
+    max_buffer: usize,
+    overflowed: bool,
+    position: usize,
+    finished: bool,
+    trailers: Option<Option<HeaderMap>>,
+    trailers_taken: bool,
+}
+
+impl<E> Replayable<E>
+where
+    E: Events,
+    E::Data: Into<Bytes>,
+{
+    /// Creates a `Replayable` that records up to `max_buffer` bytes of the request body.
+    pub fn new(events: E, max_buffer: usize) -> Self {
+        Self {
+            events,
+            buffered: vec![],
+            buffered_len: 0,
+            max_buffer,
+            overflowed: false,
+            position: 0,
+            finished: false,
+            trailers: None,
+            trailers_taken: false,
+        }
+    }
+
+    /// Restarts the delivery of the request body from the first chunk.
+    ///
+    /// After calling this method, `data` returns the recorded chunks followed by
+    /// the chunks that have not been received yet, and `trailers` returns the
+    /// recorded trailers again. As with the server, `trailers` fails with
+    /// `BodyNotConsumed` until `data` has returned all of the recorded chunks.
+    /// An error is returned if the received data has exceeded the limit of
+    /// the buffer.
+    pub fn rewind(&mut self) -> Result<(), RewindError> {
+        if self.overflowed {
+            return Err(RewindError(()));
+        }
+        self.position = 0;
+        self.trailers_taken = false;
+        Ok(())
+    }
+
+    /// Returns a reference to the underlying `Events`.
+    pub fn get_ref(&self) -> &E {
+        &self.events
+    }
+
+    /// Returns a mutable reference to the underlying `Events`.
+    ///
+    /// The chunks received directly from the underlying `Events` are not recorded.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.events
+    }
+
+    /// Consumes itself and returns the underlying `Events`.
+    pub fn into_inner(self) -> E {
+        self.events
+    }
+
+    fn record(&mut self, chunk: &Bytes) {
+        if self.overflowed {
+            return;
+        }
+        if self.buffered_len + chunk.len() > self.max_buffer {
+            self.overflowed = true;
+            self.buffered = vec![];
+            self.buffered_len = 0;
+            return;
+        }
+        self.buffered_len += chunk.len();
+        self.buffered.push(chunk.clone());
+        self.position = self.buffered.len();
+    }
+}
+
+#[async_trait]
+impl<E> Events for Replayable<E>
+where
+    E: Events + Send,
+    E::Data: Into<Bytes> + Send,
+    E::Error: FromBodyNotConsumed,
+{
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.events.remote_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.events.local_addr()
+    }
+
+    async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        if let Some(chunk) = self.buffered.get(self.position) {
+            self.position += 1;
+            return Some(Ok(chunk.clone().into()));
+        }
+        if self.finished {
+            return None;
+        }
+
+        match self.events.data().await {
+            Some(Ok(chunk)) => {
+                let chunk = chunk.into();
+                self.record(&chunk);
+                Some(Ok(chunk.into()))
+            }
+            Some(Err(err)) => Some(Err(err)),
+            None => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+
+    async fn trailers(&mut self) -> Result<Option<HeaderMap>, Self::Error> {
+        if self.position < self.buffered.len() {
+            return Err(E::Error::from_body_not_consumed(BodyNotConsumed::new()));
+        }
+        if self.trailers.is_none() {
+            let trailers = self.events.trailers().await?;
+            self.trailers = Some(trailers);
+        }
+        if self.trailers_taken {
+            return Ok(None);
+        }
+        self.trailers_taken = true;
+        Ok(self.trailers.clone().flatten())
+    }
+
+    async fn start_send_response(
+        &mut self,
+        response: Response<()>,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        self.events
+            .start_send_response(response, end_of_stream)
+            .await
+    }
+
+    async fn send_data(
+        &mut self,
+        data: Self::Data,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        self.events.send_data(data, end_of_stream).await
+    }
+
+    async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
+        self.events.send_trailers(trailers).await
+    }
+
+    async fn ready(&mut self) -> Result<(), Self::Error> {
+        self.events.ready().await
+    }
+}
+
+/// The error returned from `Replayable::rewind` when the received data
+/// has exceeded the limit of the buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RewindError(());
+
+impl fmt::Display for RewindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the request body has exceeded the limit of the rewind buffer")
+    }
+}
+
+impl error::Error for RewindError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockEvents;
+    use futures::executor::block_on;
+    use http::HeaderValue;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn aggregate_skips_empty_chunks() {
+        let mut buf = Aggregate::new();
+        buf.push("hel");
+        buf.push("");
+        buf.push(Bytes::from_static(b"lo"));
+        assert_eq!(buf.num_chunks(), 2);
+        assert_eq!(buf.remaining(), 5);
+        assert_eq!(buf.to_bytes(), "hello");
+    }
+
+    #[test]
+    fn aggregate_to_bytes() {
+        assert_eq!(Aggregate::new().to_bytes(), "");
+
+        let single: Aggregate = vec!["hello"].into_iter().collect();
+        assert_eq!(single.to_bytes(), "hello");
+
+        let multi: Aggregate = vec!["he", "ll", "o"].into_iter().collect();
+        assert_eq!(multi.to_bytes(), "hello");
+        assert_eq!(multi.num_chunks(), 3);
+    }
+
+    #[test]
+    fn aggregate_advances_across_chunks() {
+        let mut buf: Aggregate = vec!["hel", "lo", ", ", "world"].into_iter().collect();
+        assert_eq!(buf.bytes(), b"hel");
+
+        buf.advance(2);
+        assert_eq!(buf.bytes(), b"l");
+        assert_eq!(buf.remaining(), 10);
+
+        buf.advance(4);
+        assert_eq!(buf.bytes(), b" ");
+        assert_eq!(buf.num_chunks(), 2);
+        assert_eq!(buf.to_bytes(), " world");
+
+        let mut dst = [<&IoVec>::from(&b"-"[..]); 4];
+        assert_eq!(buf.bytes_vec(&mut dst), 2);
+        assert_eq!(&dst[0][..], b" ");
+        assert_eq!(&dst[1][..], b"world");
+
+        buf.advance(6);
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(buf.bytes(), b"");
+        assert_eq!(buf.num_chunks(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot advance past the end of buffer")]
+    fn aggregate_cannot_advance_past_the_end() {
+        let mut buf: Aggregate = vec!["hel", "lo"].into_iter().collect();
+        buf.advance(6);
+    }
+
+    #[test]
+    fn aggregate_collects_the_remaining_chunks() {
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        block_on(async {
+            let first = events.data().await.transpose()?.map(Bytes::from);
+            assert_eq!(first, Some("hel".into()));
+
+            let body = aggregate(&mut events).await?;
+            assert_eq!(body.num_chunks(), 1);
+            assert_eq!(body.to_bytes(), "lo");
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+    }
+
+    type Reasons = Arc<Mutex<Vec<String>>>;
+
+    fn with_cleanup(
+        events: MockEvents,
+    ) -> (
+        WithCleanup<MockEvents, impl FnOnce(BodyDropReason<'_, crate::testing::Error>)>,
+        Reasons,
+    ) {
+        let reasons = Arc::new(Mutex::new(vec![]));
+        let events = events.on_drop({
+            let reasons = reasons.clone();
+            move |reason| {
+                let reason = match reason {
+                    BodyDropReason::Completed => "completed".into(),
+                    BodyDropReason::Cancelled => "cancelled".into(),
+                    BodyDropReason::Errored(err) => format!("errored: {}", err),
+                };
+                reasons.lock().unwrap().push(reason);
+            }
+        });
+        (events, reasons)
+    }
+
+    #[test]
+    fn on_drop_reports_completed() {
+        let (mut events, reasons) = with_cleanup(MockEvents::default());
+        block_on(async {
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("hello".into(), true).await?;
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+        assert!(reasons.lock().unwrap().is_empty());
+
+        drop(events);
+        assert_eq!(*reasons.lock().unwrap(), vec!["completed"]);
+    }
+
+    #[test]
+    fn on_drop_reports_completed_after_trailers() {
+        let (mut events, reasons) = with_cleanup(MockEvents::default());
+        block_on(async {
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("hello".into(), false).await?;
+            events.send_trailers(HeaderMap::new()).await?;
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+        drop(events);
+        assert_eq!(*reasons.lock().unwrap(), vec!["completed"]);
+    }
+
+    #[test]
+    fn on_drop_reports_cancelled() {
+        let (mut events, reasons) = with_cleanup(MockEvents::default());
+        block_on(async {
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("hel".into(), false).await?;
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+        drop(events);
+        assert_eq!(*reasons.lock().unwrap(), vec!["cancelled"]);
+    }
+
+    #[test]
+    fn on_drop_reports_errored_only_once() {
+        let (mut events, reasons) = with_cleanup(MockEvents::default());
+        block_on(async {
+            let err = events.send_data("hello".into(), true).await.unwrap_err();
+            assert!(err.is::<crate::finalize::InvalidResponseState>());
+        });
+        assert_eq!(reasons.lock().unwrap().len(), 1);
+        assert!(reasons.lock().unwrap()[0].starts_with("errored: "));
+
+        drop(events);
+        assert_eq!(reasons.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn on_drop_catches_the_panic_of_the_callback() {
+        let events = MockEvents::default().on_drop(|_| panic!("explicit panic"));
+        drop(events);
+    }
+
+    #[test]
+    fn replayable_returns_the_body_and_trailers_again() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        events.request_trailers = Some(trailers.clone());
+        let mut events = Replayable::new(events, 1024);
+
+        block_on(async {
+            let mut body = Aggregate::new();
+            while let Some(chunk) = events.data().await {
+                body.push(chunk?);
+            }
+            assert_eq!(body.to_bytes(), "hello");
+            assert_eq!(events.trailers().await?, Some(trailers.clone()));
+            assert_eq!(events.trailers().await?, None);
+
+            events.rewind().unwrap();
+            let mut body = Aggregate::new();
+            while let Some(chunk) = events.data().await {
+                body.push(chunk?);
+            }
+            assert_eq!(body.to_bytes(), "hello");
+            assert_eq!(events.trailers().await?, Some(trailers.clone()));
+            assert_eq!(events.trailers().await?, None);
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn replayable_without_trailers() {
+        let mut events = Replayable::new(MockEvents::new(vec!["a"]), 1024);
+        block_on(async {
+            while let Some(chunk) = events.data().await {
+                chunk?;
+            }
+            assert_eq!(events.trailers().await?, None);
+            events.rewind().unwrap();
+            assert_eq!(
+                events.data().await.transpose()?.map(Bytes::from),
+                Some("a".into())
+            );
+            assert_eq!(events.trailers().await?, None);
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn replayable_cannot_rewind_after_overflow() {
+        let mut events = Replayable::new(MockEvents::new(vec!["hel", "lo"]), 4);
+        block_on(async {
+            while let Some(chunk) = events.data().await {
+                chunk?;
+            }
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+        assert_eq!(events.rewind(), Err(RewindError(())));
+    }
+
+    async fn collect<E>(events: &mut E) -> Result<Bytes, E::Error>
+    where
+        E: Events + ?Sized,
+        E::Data: Into<Bytes>,
+    {
+        aggregate(events).await.map(|body| body.to_bytes())
+    }
+
+    fn checksum_trailers(value: &'static str) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static(value));
+        trailers
+    }
+
+    #[test]
+    fn replayable_trailers_require_the_replay_to_be_drained() {
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        events.request_trailers = Some(checksum_trailers("abc"));
+        let mut events = Replayable::new(events, 1024);
+
+        block_on(async {
+            assert_eq!(collect(&mut events).await?, "hello");
+            events.rewind().unwrap();
+
+            let err = events.trailers().await.unwrap_err();
+            assert!(err.is::<BodyNotConsumed>());
+
+            assert_eq!(
+                events.data().await.transpose()?.map(Bytes::from),
+                Some("hel".into())
+            );
+            let err = events.trailers().await.unwrap_err();
+            assert!(err.is::<BodyNotConsumed>());
+
+            assert_eq!(collect(&mut events).await?, "lo");
+            assert_eq!(events.trailers().await?, Some(checksum_trailers("abc")));
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn replayable_can_rewind_twice() {
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        events.request_trailers = Some(checksum_trailers("abc"));
+        let mut events = Replayable::new(events, 1024);
+
+        block_on(async {
+            assert_eq!(collect(&mut events).await?, "hello");
+            assert_eq!(events.trailers().await?, Some(checksum_trailers("abc")));
+
+            events.rewind().unwrap();
+            assert_eq!(
+                events.data().await.transpose()?.map(Bytes::from),
+                Some("hel".into())
+            );
+
+            // rewinding in the middle of the replay starts over again.
+            events.rewind().unwrap();
+            assert_eq!(collect(&mut events).await?, "hello");
+            assert_eq!(events.trailers().await?, Some(checksum_trailers("abc")));
+            assert_eq!(events.trailers().await?, None);
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn replayable_verifies_the_signature_before_the_handler() {
+        async fn verify<E>(events: &mut Replayable<E>) -> Result<bool, crate::testing::Error>
+        where
+            E: Events<Error = crate::testing::Error> + Send,
+            E::Data: Into<Bytes> + Send,
+        {
+            let body = collect(events).await?;
+            let trailers = events.trailers().await?.unwrap_or_default();
+            let expected = trailers.get("x-checksum").map(|v| v.as_bytes().len());
+            events.rewind()?;
+            Ok(expected == Some(body.len()))
+        }
+
+        async fn handle<E>(events: &mut E) -> Result<(), crate::testing::Error>
+        where
+            E: Events<Error = crate::testing::Error> + ?Sized,
+            E::Data: Into<Bytes> + From<Bytes>,
+        {
+            let body = collect(events).await?;
+            let trailers = events.trailers().await?;
+            assert!(trailers.is_some());
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data(body.into(), true).await?;
+            Ok(())
+        }
+
+        let mut events = MockEvents::new(vec!["hel", "lo"]);
+        events.request_trailers = Some(checksum_trailers("12345"));
+        let mut events = Replayable::new(events, 1024);
+
+        block_on(async {
+            assert!(verify(&mut events).await?);
+            handle(&mut events).await?;
+            Ok::<_, crate::testing::Error>(())
+        })
+        .unwrap();
+
+        let events = events.into_inner();
+        assert_eq!(events.response_body, b"hello");
+        assert!(events.finished);
+    }
+}
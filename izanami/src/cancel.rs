@@ -0,0 +1,241 @@
+//! Cancellation of the work associated with a request.
+//!
+//! The servers insert a `CancelToken` into the extensions of each request.
+//! The token is cancelled when the request can no longer be answered, e.g.
+//! the connection has been closed or the request has timed out, and the
+//! application can use it to stop the subtasks spawned for the request.
+
+use http::Request;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Waker},
+};
+
+/// A token that signals the cancellation of a request.
+///
+/// The clones of a token share the same state, so the token can be moved
+/// into the subtasks and cancelled from anywhere.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    wakers: HashMap<usize, Waker>,
+    next_key: usize,
+    children: Vec<Weak<Inner>>,
+}
+
+impl CancelToken {
+    /// Creates a new token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the token associated with the specified request, if any.
+    pub fn get<T>(request: &Request<T>) -> Option<&Self> {
+        request.extensions().get()
+    }
+
+    /// Creates a token that is cancelled together with this token.
+    ///
+    /// Cancelling the returned token does not affect this token.
+    pub fn child_token(&self) -> Self {
+        let child = Self::new();
+        let mut state = self.inner.state.lock().unwrap();
+        if self.is_cancelled() {
+            drop(state);
+            child.cancel();
+        } else {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    /// Cancels this token and the tokens created from it by `child_token`.
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    /// Returns whether this token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Returns a future that completes when this token is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            token: self.clone(),
+            key: None,
+        }
+    }
+}
+
+impl Inner {
+    fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let state = std::mem::take(&mut *self.state.lock().unwrap());
+        for (_, waker) in state.wakers {
+            waker.wake();
+        }
+        for child in state.children {
+            if let Some(child) = child.upgrade() {
+                child.cancel();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// A future that completes when the associated `CancelToken` is cancelled.
+///
+/// The value of this type is created by `CancelToken::cancelled`.
+/// The waker registered by this future is removed from the token when
+/// it is dropped.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Cancelled {
+    token: CancelToken,
+    key: Option<usize>,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let me = self.get_mut();
+        let inner = &me.token.inner;
+        if inner.cancelled.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        let mut state = inner.state.lock().unwrap();
+        // The flag is checked again while holding the lock, since `cancel`
+        // takes the wakers after setting it.
+        if inner.cancelled.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        match me.key.and_then(|key| state.wakers.get_mut(&key)) {
+            Some(waker) => {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let key = state.next_key;
+                state.next_key = state.next_key.wrapping_add(1);
+                state.wakers.insert(key, cx.waker().clone());
+                me.key = Some(key);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            if let Ok(mut state) = self.token.inner.state.lock() {
+                state.wakers.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        future::FutureExt,
+        task::{waker, ArcWake},
+    };
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct CountWakes(AtomicUsize);
+
+    impl ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn registered_wakers(token: &CancelToken) -> usize {
+        token.inner.state.lock().unwrap().wakers.len()
+    }
+
+    #[test]
+    fn dropped_future_removes_its_waker() {
+        let token = CancelToken::new();
+        let counter = Arc::new(CountWakes::default());
+        let waker = waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut first = token.cancelled();
+        let mut second = token.cancelled();
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        assert_eq!(registered_wakers(&token), 2);
+
+        drop(first);
+        assert_eq!(registered_wakers(&token), 1);
+
+        token.cancel();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(second.poll_unpin(&mut cx).is_ready());
+        drop(second);
+        assert_eq!(registered_wakers(&token), 0);
+    }
+
+    #[test]
+    fn child_tokens_are_cancelled_with_the_parent() {
+        let parent = CancelToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+
+        child.cancel();
+        assert!(!parent.is_cancelled());
+        assert!(grandchild.is_cancelled());
+
+        let other = parent.child_token();
+        parent.cancel();
+        assert!(other.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn dropped_children_are_released() {
+        let parent = CancelToken::new();
+        for _ in 0..10 {
+            let child = parent.child_token();
+            let _ = child.cancelled().now_or_never();
+        }
+        let _child = parent.child_token();
+        assert_eq!(parent.inner.state.lock().unwrap().children.len(), 1);
+        assert_eq!(registered_wakers(&parent), 0);
+    }
+}
@@ -0,0 +1,172 @@
+//! Connection-level information exposed to applications.
+//!
+//! The values in this module are inserted into the extensions of each
+//! request by the server, and the application can retrieve them via
+//! `request.extensions().get::<T>()`.
+
+use http::Extensions;
+use std::{error, fmt, net::SocketAddr, sync::Arc};
+
+/// An identifier of the connection on which the request arrived.
+///
+/// The value is assigned by the server at accept time and is unique
+/// within the server. Requests received on the same connection share
+/// the same identifier.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u64);
+
+/// The 1-based position of the request among those received on its connection.
+///
+/// The first request on a connection has the sequence number `1`, and the
+/// value increases by one for each subsequent request (or stream, on HTTP/2).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestSequence(pub u64);
+
+/// The size of the response body chunks that the server can write efficiently.
+///
+/// The application producing a large response body (e.g. reading a file)
+/// can use this value as the size of each chunk passed to `send_data`.
+/// It is a hint derived from the transport and is not enforced.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SuggestedChunkSize(pub usize);
+
+/// The protocol used by the connection on which the request arrived.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// HTTP/1.x.
+    Http1 { is_tls: bool },
+
+    /// HTTP/2.
+    Http2 { is_tls: bool },
+}
+
+impl Protocol {
+    /// Returns whether the connection is secured by TLS.
+    pub fn is_tls(self) -> bool {
+        match self {
+            Protocol::Http1 { is_tls } | Protocol::Http2 { is_tls } => is_tls,
+        }
+    }
+}
+
+/// The address of the client that sent the request.
+///
+/// The server inserts the address of the peer of the connection. When the
+/// request arrives through trusted reverse proxies, the value may be replaced
+/// with the address of the originating client (see `izanami::forwarded`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// The address of the immediate peer of the connection, kept when
+/// `RemoteAddr` has been replaced by the address of the originating client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DirectPeerAddr(pub SocketAddr);
+
+type BoxError = Box<dyn error::Error + Send + Sync + 'static>;
+type MakeInserter = dyn Fn(&SocketAddr) -> Result<Inserter, BoxError> + Send + Sync + 'static;
+type Inserter = Box<dyn Fn(&mut Extensions) + Send + Sync + 'static>;
+
+/// A set of functions that derive values from each connection, to be
+/// inserted into the extensions of every request on that connection.
+///
+/// The servers hold this value and call `for_connection` once per
+/// accepted connection.
+#[derive(Clone, Default)]
+pub struct RequestExtensions {
+    makers: Vec<Arc<MakeInserter>>,
+}
+
+impl RequestExtensions {
+    /// Creates an empty `RequestExtensions`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a function that derives a value from the remote address of the connection.
+    ///
+    /// If the function returns an error, the connection is closed
+    /// without processing any requests.
+    pub fn push<F, T, E>(&mut self, f: F)
+    where
+        F: Fn(&SocketAddr) -> Result<T, E> + Send + Sync + 'static,
+        T: Clone + Send + Sync + 'static,
+        E: Into<BoxError>,
+    {
+        self.makers.push(Arc::new(move |remote_addr| {
+            let value = f(remote_addr).map_err(Into::into)?;
+            Ok(Box::new(move |extensions: &mut Extensions| {
+                extensions.insert(value.clone());
+            }) as Inserter)
+        }));
+    }
+
+    /// Calls the functions for a newly accepted connection.
+    pub fn for_connection(
+        &self,
+        remote_addr: &SocketAddr,
+    ) -> Result<ConnectionExtensions, BoxError> {
+        let inserters = self
+            .makers
+            .iter()
+            .map(|make| make(remote_addr))
+            .collect::<Result<_, _>>()?;
+        Ok(ConnectionExtensions {
+            remote_addr: *remote_addr,
+            local_addr: None,
+            inserters: Arc::new(inserters),
+        })
+    }
+}
+
+impl fmt::Debug for RequestExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestExtensions")
+            .field("len", &self.makers.len())
+            .finish()
+    }
+}
+
+/// The values derived from a connection by `RequestExtensions`.
+#[derive(Clone)]
+pub struct ConnectionExtensions {
+    remote_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    inserters: Arc<Vec<Inserter>>,
+}
+
+impl ConnectionExtensions {
+    /// Returns the remote address of the connection.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Sets the local address of the connection.
+    pub fn set_local_addr(&mut self, local_addr: SocketAddr) {
+        self.local_addr = Some(local_addr);
+    }
+
+    /// Returns the local address of the connection, if it has been set.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Inserts the values into the extensions of a request.
+    ///
+    /// `RemoteAddr` is always inserted before the values derived by the functions.
+    pub fn insert_into(&self, extensions: &mut Extensions) {
+        extensions.insert(RemoteAddr(self.remote_addr));
+        for insert in self.inserters.iter() {
+            insert(extensions);
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionExtensions")
+            .field("remote_addr", &self.remote_addr)
+            .field("local_addr", &self.local_addr)
+            .field("len", &self.inserters.len())
+            .finish()
+    }
+}
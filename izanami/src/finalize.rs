@@ -0,0 +1,131 @@
+//! Components used by the servers to finalize the responses.
+//!
+//! `HeaderFinalizers` modifies the response headers just before they are
+//! sent, and `InvalidResponseState` is reported when the methods for sending
+//! the response are called in an invalid order.
+
+use http::{Request, Response};
+use std::{error, fmt, sync::Arc};
+
+/// The error that the server reports when the methods of `Events` for
+/// sending the response are called in an invalid order.
+///
+/// The servers typically wrap this value into their own error type, and it
+/// can be found by traversing `Error::source`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidResponseState(ResponseStateKind);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ResponseStateKind {
+    NotStarted,
+    AlreadyStarted,
+    Finished,
+    Upgraded,
+}
+
+impl InvalidResponseState {
+    /// Creates an error indicating that the response body or trailers
+    /// were sent before the response header.
+    pub fn not_started() -> Self {
+        InvalidResponseState(ResponseStateKind::NotStarted)
+    }
+
+    /// Creates an error indicating that the response header was sent twice.
+    pub fn already_started() -> Self {
+        InvalidResponseState(ResponseStateKind::AlreadyStarted)
+    }
+
+    /// Creates an error indicating that the response body or trailers
+    /// were sent after the end of the response.
+    pub fn finished() -> Self {
+        InvalidResponseState(ResponseStateKind::Finished)
+    }
+
+    /// Creates an error indicating that the request body or trailers were
+    /// requested after the connection has been upgraded to another protocol.
+    pub fn upgraded() -> Self {
+        InvalidResponseState(ResponseStateKind::Upgraded)
+    }
+
+    /// Returns whether the response header has not been sent yet.
+    pub fn is_not_started(&self) -> bool {
+        self.0 == ResponseStateKind::NotStarted
+    }
+
+    /// Returns whether the response header has already been sent.
+    pub fn is_already_started(&self) -> bool {
+        self.0 == ResponseStateKind::AlreadyStarted
+    }
+
+    /// Returns whether the response has already been completed.
+    pub fn is_finished(&self) -> bool {
+        self.0 == ResponseStateKind::Finished
+    }
+
+    /// Returns whether the connection has been upgraded to another protocol.
+    pub fn is_upgraded(&self) -> bool {
+        self.0 == ResponseStateKind::Upgraded
+    }
+}
+
+impl fmt::Display for InvalidResponseState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            ResponseStateKind::NotStarted => "the response header has not been sent yet",
+            ResponseStateKind::AlreadyStarted => "the response header has already been sent",
+            ResponseStateKind::Finished => "the response has already been completed",
+            ResponseStateKind::Upgraded => "the connection has been upgraded",
+        })
+    }
+}
+
+impl error::Error for InvalidResponseState {}
+
+type Finalizer = dyn Fn(&Request<()>, &mut Response<()>) + Send + Sync + 'static;
+
+/// An ordered list of callbacks that modify the response header just before it is sent.
+///
+/// The callbacks receive the head of the corresponding request and are
+/// called in the order of registration, exactly once for each response
+/// header passed to the server. When the server replaces the response with
+/// its own one (e.g. a `500 Internal Server Error` for oversized header
+/// fields), the callbacks are called for the replaced response as well.
+#[derive(Clone, Default)]
+pub struct HeaderFinalizers {
+    finalizers: Vec<Arc<Finalizer>>,
+}
+
+impl HeaderFinalizers {
+    /// Creates an empty `HeaderFinalizers`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a callback to the end of the list.
+    pub fn push<F>(&mut self, f: F)
+    where
+        F: Fn(&Request<()>, &mut Response<()>) + Send + Sync + 'static,
+    {
+        self.finalizers.push(Arc::new(f));
+    }
+
+    /// Returns whether no callbacks have been registered.
+    pub fn is_empty(&self) -> bool {
+        self.finalizers.is_empty()
+    }
+
+    /// Calls the registered callbacks in order.
+    pub fn apply(&self, request: &Request<()>, response: &mut Response<()>) {
+        for finalize in &self.finalizers {
+            finalize(request, response);
+        }
+    }
+}
+
+impl fmt::Debug for HeaderFinalizers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeaderFinalizers")
+            .field("len", &self.finalizers.len())
+            .finish()
+    }
+}
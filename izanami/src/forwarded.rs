@@ -0,0 +1,453 @@
+//! Resolution of the client address behind trusted reverse proxies.
+
+use crate::{
+    conn::{DirectPeerAddr, RemoteAddr},
+    App, Events,
+};
+use async_trait::async_trait;
+use http::{HeaderMap, Request};
+use std::{
+    error, fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+/// A range of IP addresses, written as `address/prefix_len`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Creates a `Cidr` from the network address and the length of the prefix.
+    ///
+    /// It returns an error if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, InvalidCidr> {
+        let max_len = match addr {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidCidr(()));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Returns whether the specified address is within this range.
+    ///
+    /// IPv4-mapped IPv6 addresses are compared as IPv4 addresses.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonicalize(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    /// Parses a string such as `10.0.0.0/8` or `fd00::/8`.
+    ///
+    /// A single address without the prefix length is treated as a range
+    /// containing only that address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .and_then(|addr| addr.parse().ok())
+            .ok_or(InvalidCidr(()))?;
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|_| InvalidCidr(()))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+/// The error returned when creating a `Cidr` fails.
+#[derive(Debug)]
+pub struct InvalidCidr(());
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid CIDR notation")
+    }
+}
+
+impl error::Error for InvalidCidr {}
+
+/// An application that replaces `RemoteAddr` with the address of the originating
+/// client when the request arrives through trusted reverse proxies.
+///
+/// If the peer of the connection is trusted, the chain of the proxies is read
+/// from the `Forwarded` header (RFC 7239), or from `X-Forwarded-For` when
+/// `Forwarded` is absent. The chain is walked from the nearest hop, skipping
+/// the trusted addresses, and the first untrusted address is taken as the
+/// client. The original value is kept as `DirectPeerAddr`.
+///
+/// The forwarded addresses usually lack the port, in which case the port of
+/// the new `RemoteAddr` is `0`. If the headers are malformed, a warning is
+/// logged and the request is passed to the inner application unchanged.
+#[derive(Debug, Clone)]
+pub struct ForwardedFor<T> {
+    app: T,
+    trusted: Vec<Cidr>,
+}
+
+impl<T> ForwardedFor<T> {
+    /// Wraps the specified application, without any trusted proxies.
+    pub fn new(app: T) -> Self {
+        Self {
+            app,
+            trusted: vec![],
+        }
+    }
+
+    /// Adds a range of the addresses of the trusted proxies.
+    pub fn trust(mut self, cidr: Cidr) -> Self {
+        self.trusted.push(cidr);
+        self
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    /// Returns the address of the originating client, or `None` if
+    /// it cannot be determined from the headers.
+    fn resolve(&self, headers: &HeaderMap) -> Result<Option<SocketAddr>, MalformedHeader> {
+        let hops = if headers.contains_key("forwarded") {
+            parse_forwarded(headers)?
+        } else if headers.contains_key("x-forwarded-for") {
+            parse_x_forwarded_for(headers)?
+        } else {
+            return Ok(None);
+        };
+
+        let mut client = None;
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(addr) if self.is_trusted(addr.ip()) => client = Some(addr),
+                hop => return Ok(hop),
+            }
+        }
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl<T, E> App<E> for ForwardedFor<T>
+where
+    T: App<E> + Send + Sync,
+    E: Events + Send,
+{
+    type Error = T::Error;
+
+    async fn call(&self, mut request: Request<E>) -> Result<(), Self::Error>
+    where
+        E: 'async_trait,
+    {
+        let peer = request.extensions().get::<RemoteAddr>().map(|addr| addr.0);
+        if let Some(peer) = peer.filter(|peer| self.is_trusted(peer.ip())) {
+            match self.resolve(request.headers()) {
+                Ok(Some(client)) => {
+                    let extensions = request.extensions_mut();
+                    extensions.insert(DirectPeerAddr(peer));
+                    extensions.insert(RemoteAddr(client));
+                }
+                Ok(None) => {}
+                Err(MalformedHeader) => {
+                    tracing::warn!("malformed forwarding header from {}", peer);
+                }
+            }
+        }
+
+        self.app.call(request).await
+    }
+}
+
+#[derive(Debug)]
+struct MalformedHeader;
+
+/// Parses the `for` parameters of the `Forwarded` header, in the order of the hops.
+///
+/// Unknown or obfuscated nodes are represented as `None`.
+fn parse_forwarded(headers: &HeaderMap) -> Result<Vec<Option<SocketAddr>>, MalformedHeader> {
+    let mut hops = vec![];
+    for value in headers.get_all("forwarded") {
+        let value = value.to_str().map_err(|_| MalformedHeader)?;
+        for element in split_unquoted(value, ',') {
+            let mut node = None;
+            for pair in split_unquoted(element, ';') {
+                let pair = pair.trim();
+                if pair.is_empty() {
+                    continue;
+                }
+                let eq = pair.find('=').ok_or(MalformedHeader)?;
+                if pair[..eq].trim().eq_ignore_ascii_case("for") {
+                    node = Some(parse_node(unquote(pair[eq + 1..].trim())?)?);
+                }
+            }
+            hops.push(node.unwrap_or(None));
+        }
+    }
+    Ok(hops)
+}
+
+/// Parses the `X-Forwarded-For` header, in the order of the hops.
+fn parse_x_forwarded_for(headers: &HeaderMap) -> Result<Vec<Option<SocketAddr>>, MalformedHeader> {
+    let mut hops = vec![];
+    for value in headers.get_all("x-forwarded-for") {
+        let value = value.to_str().map_err(|_| MalformedHeader)?;
+        for node in value.split(',') {
+            let node = node.trim();
+            let addr = match node.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, 0),
+                Err(..) => node.parse::<SocketAddr>().map_err(|_| MalformedHeader)?,
+            };
+            hops.push(Some(addr));
+        }
+    }
+    Ok(hops)
+}
+
+/// Parses a node identifier of `Forwarded` (RFC 7239, Section 6).
+fn parse_node(node: &str) -> Result<Option<SocketAddr>, MalformedHeader> {
+    if node.eq_ignore_ascii_case("unknown") || node.starts_with('_') {
+        return Ok(None);
+    }
+
+    let (ip, port) = if node.starts_with('[') {
+        let end = node.find(']').ok_or(MalformedHeader)?;
+        let ip = node[1..end]
+            .parse::<Ipv6Addr>()
+            .map_err(|_| MalformedHeader)?;
+        (IpAddr::V6(ip), &node[end + 1..])
+    } else {
+        let (ip, port) = match node.find(':') {
+            Some(colon) => (&node[..colon], &node[colon..]),
+            None => (node, ""),
+        };
+        let ip = ip.parse::<Ipv4Addr>().map_err(|_| MalformedHeader)?;
+        (IpAddr::V4(ip), port)
+    };
+
+    let port = match port {
+        "" => 0,
+        port if port.starts_with(":_") => 0,
+        port if port.starts_with(':') => port[1..].parse().map_err(|_| MalformedHeader)?,
+        _ => return Err(MalformedHeader),
+    };
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn unquote(s: &str) -> Result<&str, MalformedHeader> {
+    if s.starts_with('"') {
+        if s.len() < 2 || !s.ends_with('"') {
+            return Err(MalformedHeader);
+        }
+        Ok(&s[1..s.len() - 1])
+    } else {
+        Ok(s)
+    }
+}
+
+/// Splits the string by `sep`, ignoring the separators in quoted strings.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn canonicalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        addr => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::handler_fn, testing::MockEvents};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use http::{HeaderValue, Response};
+    use std::sync::{Arc, Mutex};
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn cidr_contains_the_addresses_in_the_range() {
+        let private = cidr("10.0.0.0/8");
+        assert!(private.contains("10.1.2.3".parse().unwrap()));
+        assert!(!private.contains("11.0.0.1".parse().unwrap()));
+        assert!(private.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!private.contains("fd00::1".parse().unwrap()));
+
+        let single = cidr("192.0.2.1");
+        assert!(single.contains("192.0.2.1".parse().unwrap()));
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.1".parse().unwrap()));
+        assert!(cidr("fd00::/8").contains("fd12::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_rejects_invalid_notation() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn x_forwarded_for_is_walked_from_the_nearest_hop() {
+        let app = ForwardedFor::new(()).trust(cidr("10.0.0.0/8"));
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.1, 198.51.100.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(app.resolve(&headers).unwrap(), Some(addr("198.51.100.1:0")));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded_for() {
+        let app = ForwardedFor::new(()).trust(cidr("10.0.0.0/8"));
+        let headers = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ]);
+        assert_eq!(
+            app.resolve(&headers).unwrap(),
+            Some(addr("[2001:db8::1]:4711"))
+        );
+    }
+
+    #[test]
+    fn unknown_hop_stops_the_resolution() {
+        let app = ForwardedFor::new(()).trust(cidr("10.0.0.0/8"));
+        let headers = headers(&[("forwarded", "for=203.0.113.1, for=_hidden, for=10.0.0.2")]);
+        assert_eq!(app.resolve(&headers).unwrap(), None);
+    }
+
+    #[test]
+    fn all_trusted_hops_resolve_to_the_farthest() {
+        let app = ForwardedFor::new(()).trust(cidr("10.0.0.0/8"));
+        let headers = headers(&[("x-forwarded-for", "10.0.0.1, 10.0.0.2")]);
+        assert_eq!(app.resolve(&headers).unwrap(), Some(addr("10.0.0.1:0")));
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let app = ForwardedFor::new(());
+        for &(name, value) in &[
+            ("x-forwarded-for", "not an address"),
+            ("forwarded", "for"),
+            ("forwarded", "for=\"192.0.2.1"),
+            ("forwarded", "for=[2001:db8::1"),
+            ("forwarded", "for=192.0.2.1:port"),
+        ] {
+            let headers = headers(&[(name, value)]);
+            assert!(app.resolve(&headers).is_err(), "{}: {}", name, value);
+        }
+    }
+
+    /// Calls `ForwardedFor` trusting `10.0.0.0/8` with the peer address and the headers,
+    /// and returns the `RemoteAddr` and `DirectPeerAddr` seen by the inner application.
+    fn call(
+        peer: &str,
+        pairs: &[(&'static str, &'static str)],
+    ) -> (Option<RemoteAddr>, Option<DirectPeerAddr>) {
+        let seen = Arc::new(Mutex::new(None));
+        let app = ForwardedFor::new(handler_fn({
+            let seen = seen.clone();
+            move |request: Request<Bytes>| {
+                let extensions = request.extensions();
+                *seen.lock().unwrap() = Some((
+                    extensions.get::<RemoteAddr>().copied(),
+                    extensions.get::<DirectPeerAddr>().copied(),
+                ));
+                async { Ok::<_, String>(Response::new("")) }
+            }
+        }))
+        .trust(cidr("10.0.0.0/8"));
+
+        let mut events = MockEvents::default();
+        let mut request = Request::new(&mut events);
+        *request.headers_mut() = headers(pairs);
+        request.extensions_mut().insert(RemoteAddr(addr(peer)));
+        block_on(app.call(request)).unwrap();
+
+        let seen = seen.lock().unwrap().take();
+        seen.expect("the inner application was not called")
+    }
+
+    #[test]
+    fn remote_addr_is_replaced_behind_trusted_proxies() {
+        let (remote, direct) = call("10.0.0.1:50000", &[("x-forwarded-for", "203.0.113.1")]);
+        assert_eq!(remote, Some(RemoteAddr(addr("203.0.113.1:0"))));
+        assert_eq!(direct, Some(DirectPeerAddr(addr("10.0.0.1:50000"))));
+    }
+
+    #[test]
+    fn headers_from_untrusted_peers_are_ignored() {
+        let (remote, direct) = call("192.0.2.1:50000", &[("x-forwarded-for", "203.0.113.1")]);
+        assert_eq!(remote, Some(RemoteAddr(addr("192.0.2.1:50000"))));
+        assert_eq!(direct, None);
+    }
+
+    #[test]
+    fn malformed_headers_leave_the_request_unchanged() {
+        let (remote, direct) = call("10.0.0.1:50000", &[("forwarded", "for")]);
+        assert_eq!(remote, Some(RemoteAddr(addr("10.0.0.1:50000"))));
+        assert_eq!(direct, None);
+    }
+}
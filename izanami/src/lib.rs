@@ -11,10 +11,26 @@
 #![forbid(clippy::unimplemented)]
 #![cfg_attr(test, deny(warnings))]
 
+pub mod app;
+pub mod body;
+pub mod cancel;
+pub mod conn;
+pub mod finalize;
+pub mod forwarded;
+pub mod prelude;
+pub mod range;
+pub mod record;
+pub mod request_id;
+pub mod response;
+pub mod uri;
+
+#[cfg(test)]
+mod testing;
+
 use async_trait::async_trait;
-use bytes::Buf;
-use http::{HeaderMap, Request, Response};
-use std::{error, future::Future, pin::Pin};
+use bytes::{Buf, Bytes};
+use http::{header::CONTENT_LENGTH, HeaderMap, Request, Response, StatusCode};
+use std::{error, future::Future, net::SocketAddr, pin::Pin};
 
 type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
@@ -120,23 +136,128 @@ where
 /// Asynchronous object that exchanges the events with the client.
 #[async_trait]
 pub trait Events {
-    type Data: Buf;
+    /// The type of chunks exchanged with the client.
+    ///
+    /// The conversions from the common byte containers are required so that
+    /// the application can construct the chunk without depending on the
+    /// concrete type provided by the server.
+    type Data: Buf
+        + From<Bytes>
+        + From<&'static str>
+        + From<&'static [u8]>
+        + From<String>
+        + From<Vec<u8>>;
     type Error: Into<Box<dyn error::Error + Send + Sync + 'static>>;
 
+    /// Returns the remote address of the connection, if available.
+    ///
+    /// Unlike the `conn::RemoteAddr` extension, the returned value is not
+    /// affected by the applications that rewrite the request.
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Returns the local address of the connection (i.e. the address of
+    /// the listener that accepted it), if available.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Receives the next chunk of the request body.
+    ///
+    /// Once this method returns `None`, the subsequent calls also return `None`.
     async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>>;
 
+    /// Receives the trailers of the request.
+    ///
+    /// This method must be called after `data` returns `None`; otherwise
+    /// the server returns an error caused by `body::BodyNotConsumed`.
+    /// The trailers are returned only once, and the subsequent calls return `None`.
     async fn trailers(&mut self) -> Result<Option<HeaderMap>, Self::Error>;
 
+    /// Sends the response header to the client.
+    ///
+    /// This method must be called exactly once, before sending the response
+    /// body and trailers. The server returns an error caused by
+    /// `finalize::InvalidResponseState` when the methods for sending the
+    /// response are called in an invalid order.
     async fn start_send_response(
         &mut self,
         response: Response<()>,
         end_of_stream: bool,
     ) -> Result<(), Self::Error>;
 
+    /// Sends a chunk of the response body.
+    ///
+    /// The servers wait for the client to consume the previous data before
+    /// accepting the chunk, so the amount of the data buffered for a slow
+    /// client is bounded.
     async fn send_data(&mut self, data: Self::Data, end_of_stream: bool)
         -> Result<(), Self::Error>;
 
+    /// Sends the trailers and completes the response.
+    ///
+    /// The trailers are delivered only on HTTP/2. HTTP/1.1 servers complete
+    /// the response without them and do not report an error, so the
+    /// application must not rely on the trailers to carry information the
+    /// client needs (e.g. the status of gRPC) unless the protocol is HTTP/2.
     async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error>;
+
+    /// Waits until the server is ready to accept the next chunk of the response body.
+    ///
+    /// The application can use this method to avoid producing the next chunk
+    /// while the client is not reading the response. The default implementation
+    /// returns immediately.
+    fn ready<'l1, 'async_trait>(&'l1 mut self) -> BoxFuture<'async_trait, Result<(), Self::Error>>
+    where
+        'l1: 'async_trait,
+    {
+        // Not written as an `async fn`, which would require `Self: Send`
+        // and prevent `&mut E` and `Box<E>` from forwarding this method.
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Sends a chunk of the response body, converting it into `Self::Data`.
+    ///
+    /// This is a shortcut of `send_data(data.into(), end_of_stream)`.
+    async fn send<T>(&mut self, data: T, end_of_stream: bool) -> Result<(), Self::Error>
+    where
+        T: Into<Self::Data> + Send,
+        Self: Send,
+    {
+        self.send_data(data.into(), end_of_stream).await
+    }
+
+    /// Sends a complete response whose body is held in memory.
+    ///
+    /// The header is sent with `end_of_stream` set if the body is empty,
+    /// and the body is sent as a single chunk otherwise. `Content-Length` is
+    /// set to the length of the body unless the response already has it or
+    /// the status is `204` or `304`.
+    async fn send_response<B>(&mut self, response: Response<B>) -> Result<(), Self::Error>
+    where
+        B: Into<Self::Data> + Send,
+        Self::Data: Send,
+        Self: Send,
+    {
+        let (mut parts, body) = response.into_parts();
+        let body = body.into();
+        let len = body.remaining();
+        if !parts.headers.contains_key(CONTENT_LENGTH)
+            && parts.status != StatusCode::NO_CONTENT
+            && parts.status != StatusCode::NOT_MODIFIED
+        {
+            parts.headers.insert(CONTENT_LENGTH, (len as u64).into());
+        }
+
+        let end_of_stream = len == 0;
+        self.start_send_response(Response::from_parts(parts, ()), end_of_stream)
+            .await?;
+        if !end_of_stream {
+            self.send_data(body, true).await?;
+        }
+        Ok(())
+    }
 }
 
 impl<'a, E: ?Sized> Events for &'a mut E
@@ -146,6 +267,16 @@ where
     type Data = E::Data;
     type Error = E::Error;
 
+    #[inline]
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        (**self).remote_addr()
+    }
+
+    #[inline]
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
     #[inline]
     fn data<'l1, 'async_trait>(
         &'l1 mut self,
@@ -200,6 +331,14 @@ where
     {
         (**self).send_trailers(trailers)
     }
+
+    #[inline]
+    fn ready<'l1, 'async_trait>(&'l1 mut self) -> BoxFuture<'async_trait, Result<(), Self::Error>>
+    where
+        'l1: 'async_trait,
+    {
+        (**self).ready()
+    }
 }
 
 impl<E: ?Sized> Events for Box<E>
@@ -209,6 +348,16 @@ where
     type Data = E::Data;
     type Error = E::Error;
 
+    #[inline]
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        (**self).remote_addr()
+    }
+
+    #[inline]
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
     #[inline]
     fn data<'l1, 'async_trait>(
         &'l1 mut self,
@@ -263,4 +412,45 @@ where
     {
         (**self).send_trailers(trailers)
     }
+
+    #[inline]
+    fn ready<'l1, 'async_trait>(&'l1 mut self) -> BoxFuture<'async_trait, Result<(), Self::Error>>
+    where
+        'l1: 'async_trait,
+    {
+        (**self).ready()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockEvents;
+    use futures::executor::block_on;
+
+    fn assert_events<E: Events + ?Sized>() {}
+
+    #[allow(dead_code)]
+    fn wrappers_do_not_require_send<E: Events + ?Sized>() {
+        assert_events::<&mut E>();
+        assert_events::<Box<E>>();
+    }
+
+    #[test]
+    fn wrappers_forward_the_events() {
+        let mut events = MockEvents::default();
+        block_on(async {
+            let mut by_ref = &mut events;
+            Events::start_send_response(&mut by_ref, Response::new(()), false).await?;
+            Events::ready(&mut by_ref).await?;
+            Events::send_data(&mut by_ref, "hello, ".into(), false).await?;
+
+            let mut boxed = Box::new(by_ref);
+            Events::ready(&mut boxed).await?;
+            Events::send_data(&mut boxed, "world".into(), true).await
+        })
+        .unwrap();
+        assert_eq!(events.response_body, b"hello, world");
+        assert!(events.finished);
+    }
 }
@@ -0,0 +1,12 @@
+//! A prelude for writing applications.
+//!
+//! The traits re-exported by this module are needed to call the methods
+//! on the values passed to applications, so it is convenient to import
+//! them all at once:
+//!
+//! ```
+//! use izanami::prelude::*;
+//! ```
+
+#[doc(no_inline)]
+pub use crate::{body::EventsExt, response::ResponseExt, App, Events};
@@ -0,0 +1,521 @@
+//! Support for range requests (RFC 7233).
+
+use crate::Events;
+use bytes::Bytes;
+use http::{
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, RANGE},
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The maximum number of ranges in a `Range` header.
+///
+/// The `Range` header is ignored if it has more ranges than this, so that
+/// a request with many small or overlapping ranges cannot make the server
+/// produce a response much larger than the representation (RFC 7233, Section 6.1).
+pub const MAX_RANGES: usize = 16;
+
+/// Determines the response to a request for a resource of `full_len` bytes
+/// based on its `Range` header.
+///
+/// `validator` is the current `ETag` or `Last-Modified` of the representation,
+/// which is compared with `If-Range` (RFC 7233, Section 3.2). An entity tag
+/// matches only if both tags are strong and identical, and a date matches only
+/// if it is identical to `Last-Modified`. If `If-Range` does not match, or the
+/// validator is not given, the `Range` header is ignored.
+///
+/// `make_body` is called with each of the satisfiable byte ranges (or the
+/// whole range) to create the body of the corresponding part.
+///
+/// The overlapping or adjacent ranges are merged into one, and the parts are
+/// sent in ascending order. The `Range` header is ignored if it is malformed,
+/// uses a unit other than `bytes` or has more than `MAX_RANGES` ranges.
+pub fn serve_ranges<B, F>(
+    headers: &HeaderMap,
+    validator: Option<&HeaderValue>,
+    full_len: u64,
+    make_body: F,
+) -> RangedResponse<B>
+where
+    F: Fn(Range<u64>) -> B,
+{
+    if let Some(if_range) = headers.get(IF_RANGE) {
+        if !if_range_matches(if_range, validator) {
+            return RangedResponse::Full {
+                full_len,
+                body: make_body(0..full_len),
+            };
+        }
+    }
+
+    let specs = match headers
+        .get(RANGE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(parse_range)
+    {
+        Some(specs) => specs,
+        None => {
+            return RangedResponse::Full {
+                full_len,
+                body: make_body(0..full_len),
+            }
+        }
+    };
+
+    let mut ranges = coalesce(
+        specs
+            .into_iter()
+            .filter_map(|spec| spec.to_range(full_len))
+            .collect(),
+    );
+
+    match ranges.len() {
+        0 => RangedResponse::NotSatisfiable { full_len },
+        1 => {
+            let range = ranges.pop().expect("the length is 1");
+            RangedResponse::Partial {
+                full_len,
+                body: make_body(range.clone()),
+                range,
+            }
+        }
+        _ => RangedResponse::Multipart {
+            full_len,
+            boundary: generate_boundary(),
+            parts: ranges
+                .into_iter()
+                .map(|range| (range.clone(), make_body(range)))
+                .collect(),
+        },
+    }
+}
+
+/// Returns whether the value of `If-Range` matches the current validator.
+fn if_range_matches(if_range: &HeaderValue, validator: Option<&HeaderValue>) -> bool {
+    let validator = match validator {
+        Some(validator) => validator.as_bytes(),
+        None => return false,
+    };
+    let if_range = if_range.as_bytes();
+    if if_range.starts_with(b"W/") || validator.starts_with(b"W/") {
+        // Weak entity tags are never used for ranges.
+        return false;
+    }
+    if_range == validator
+}
+
+/// The response to a range request, created by `serve_ranges`.
+#[derive(Debug)]
+pub enum RangedResponse<B> {
+    /// The whole representation (`200 OK`).
+    Full { full_len: u64, body: B },
+
+    /// A single part of the representation (`206 Partial Content`).
+    Partial {
+        full_len: u64,
+        range: Range<u64>,
+        body: B,
+    },
+
+    /// Multiple parts of the representation, sent as `multipart/byteranges`
+    /// (`206 Partial Content`).
+    Multipart {
+        full_len: u64,
+        boundary: String,
+        parts: Vec<(Range<u64>, B)>,
+    },
+
+    /// None of the requested ranges are satisfiable (`416 Range Not Satisfiable`).
+    NotSatisfiable { full_len: u64 },
+}
+
+impl<B> RangedResponse<B> {
+    /// Returns the status code of this response.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RangedResponse::Full { .. } => StatusCode::OK,
+            RangedResponse::Partial { .. } | RangedResponse::Multipart { .. } => {
+                StatusCode::PARTIAL_CONTENT
+            }
+            RangedResponse::NotSatisfiable { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+        }
+    }
+
+    /// Creates the response header.
+    ///
+    /// `content_type` is the media type of the whole representation. For
+    /// multipart responses, it is sent in the header of each part.
+    pub fn response(&self, content_type: Option<&HeaderValue>) -> Response<()> {
+        let mut response = Response::new(());
+        *response.status_mut() = self.status();
+
+        let headers = response.headers_mut();
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        match self {
+            RangedResponse::Full { full_len, .. } => {
+                headers.insert(CONTENT_LENGTH, (*full_len).into());
+                if let Some(content_type) = content_type {
+                    headers.insert(CONTENT_TYPE, content_type.clone());
+                }
+            }
+            RangedResponse::Partial {
+                full_len, range, ..
+            } => {
+                headers.insert(CONTENT_LENGTH, (range.end - range.start).into());
+                headers.insert(CONTENT_RANGE, content_range(range, *full_len));
+                if let Some(content_type) = content_type {
+                    headers.insert(CONTENT_TYPE, content_type.clone());
+                }
+            }
+            RangedResponse::Multipart {
+                full_len,
+                boundary,
+                parts,
+            } => {
+                let len = parts.iter().fold(0, |acc, (range, _)| {
+                    acc + part_header(boundary, content_type, range, *full_len).len() as u64
+                        + (range.end - range.start)
+                }) + closing_delimiter(boundary).len() as u64;
+                headers.insert(CONTENT_LENGTH, len.into());
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                        .expect("the boundary should be a valid header value"),
+                );
+            }
+            RangedResponse::NotSatisfiable { full_len } => {
+                headers.insert(CONTENT_LENGTH, 0.into());
+                headers.insert(
+                    CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes */{}", full_len))
+                        .expect("should be a valid header value"),
+                );
+            }
+        }
+
+        response
+    }
+
+    /// Sends this response to the client, including the multipart framing if needed.
+    pub async fn send<E>(
+        self,
+        events: &mut E,
+        content_type: Option<&HeaderValue>,
+    ) -> Result<(), E::Error>
+    where
+        E: Events + ?Sized,
+        B: Into<E::Data>,
+    {
+        let response = self.response(content_type);
+        match self {
+            RangedResponse::Full { body, .. } | RangedResponse::Partial { body, .. } => {
+                events.start_send_response(response, false).await?;
+                events.send_data(body.into(), true).await?;
+            }
+            RangedResponse::Multipart {
+                full_len,
+                boundary,
+                parts,
+            } => {
+                events.start_send_response(response, false).await?;
+                for (range, body) in parts {
+                    let header = part_header(&boundary, content_type, &range, full_len);
+                    events.send_data(header.into(), false).await?;
+                    events.send_data(body.into(), false).await?;
+                }
+                let closing = closing_delimiter(&boundary);
+                events.send_data(closing.into(), true).await?;
+            }
+            RangedResponse::NotSatisfiable { .. } => {
+                events.start_send_response(response, true).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RangeSpec {
+    /// `first-last` or `first-`
+    FromTo(u64, Option<u64>),
+    /// `-suffix_len`
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    fn to_range(self, full_len: u64) -> Option<Range<u64>> {
+        match self {
+            RangeSpec::FromTo(first, _) if first >= full_len => None,
+            RangeSpec::FromTo(first, last) => {
+                let end = last.map_or(full_len, |last| {
+                    std::cmp::min(last.saturating_add(1), full_len)
+                });
+                Some(first..end)
+            }
+            RangeSpec::Suffix(0) => None,
+            RangeSpec::Suffix(len) => Some(full_len.saturating_sub(len)..full_len),
+        }
+        .filter(|range| range.start < range.end)
+    }
+}
+
+fn parse_range(s: &str) -> Option<Vec<RangeSpec>> {
+    let s = s.trim();
+    if s.len() < 6 || !s[..6].eq_ignore_ascii_case("bytes=") {
+        return None;
+    }
+
+    let mut specs = vec![];
+    for spec in s[6..].split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        if specs.len() == MAX_RANGES {
+            return None;
+        }
+        let dash = spec.find('-')?;
+        let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+        if first.is_empty() {
+            specs.push(RangeSpec::Suffix(last.parse().ok()?));
+        } else {
+            let first = first.parse().ok()?;
+            let last = match last {
+                "" => None,
+                last => Some(last.parse().ok()?),
+            };
+            match last {
+                Some(last) if last < first => return None,
+                _ => {}
+            }
+            specs.push(RangeSpec::FromTo(first, last));
+        }
+    }
+
+    if specs.is_empty() {
+        return None;
+    }
+    Some(specs)
+}
+
+/// Sorts the ranges and merges the ones that overlap or are adjacent.
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => {
+                last.end = std::cmp::max(last.end, range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+fn content_range(range: &Range<u64>, full_len: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "bytes {}-{}/{}",
+        range.start,
+        range.end - 1,
+        full_len
+    ))
+    .expect("should be a valid header value")
+}
+
+fn part_header(
+    boundary: &str,
+    content_type: Option<&HeaderValue>,
+    range: &Range<u64>,
+    full_len: u64,
+) -> Bytes {
+    let mut header = format!("\r\n--{}\r\n", boundary).into_bytes();
+    if let Some(content_type) = content_type {
+        header.extend_from_slice(b"content-type: ");
+        header.extend_from_slice(content_type.as_bytes());
+        header.extend_from_slice(b"\r\n");
+    }
+    header.extend_from_slice(b"content-range: ");
+    header.extend_from_slice(content_range(range, full_len).as_bytes());
+    header.extend_from_slice(b"\r\n\r\n");
+    header.into()
+}
+
+fn closing_delimiter(boundary: &str) -> Bytes {
+    format!("\r\n--{}--\r\n", boundary).into()
+}
+
+fn generate_boundary() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("izanami-{:08x}{:08x}", nanos, count as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockEvents;
+    use futures::executor::block_on;
+
+    const DATA: &str = "0123456789";
+
+    fn body_of(range: Range<u64>) -> &'static str {
+        &DATA[range.start as usize..range.end as usize]
+    }
+
+    fn ranges(range: &str) -> RangedResponse<&'static str> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_str(range).unwrap());
+        serve_ranges(&headers, None, DATA.len() as u64, body_of)
+    }
+
+    fn send(response: RangedResponse<&'static str>) -> (Response<()>, String) {
+        let mut events = MockEvents::default();
+        let content_type = HeaderValue::from_static("text/plain");
+        block_on(response.send(&mut events, Some(&content_type))).unwrap();
+        assert!(events.finished);
+        (
+            events.response.unwrap(),
+            String::from_utf8(events.response_body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn last_position_does_not_overflow() {
+        match ranges("bytes=2-18446744073709551615") {
+            RangedResponse::Partial { range, body, .. } => {
+                assert_eq!(range, 2..10);
+                assert_eq!(body, "23456789");
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        match ranges("bytes=0-4, 2-6, 7-7, 3-3") {
+            RangedResponse::Partial { range, .. } => assert_eq!(range, 0..8),
+            response => panic!("unexpected response: {:?}", response),
+        }
+        match ranges("bytes=1-2,1-2,-2") {
+            RangedResponse::Multipart { parts, .. } => {
+                assert_eq!(parts, vec![(1..3, "12"), (8..10, "89")]);
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_are_ignored() {
+        let many = (0..=MAX_RANGES)
+            .map(|_| "0-0")
+            .collect::<Vec<_>>()
+            .join(",");
+        match ranges(&format!("bytes={}", many)) {
+            RangedResponse::Full { body, .. } => assert_eq!(body, DATA),
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let (response, body) = send(ranges(&format!("bytes={}", many)));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+        assert_eq!(body, DATA);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        let (response, body) = send(ranges("bytes=10-,-0"));
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "0");
+        assert_eq!(body, "");
+    }
+
+    #[test]
+    fn partial_response_is_sent() {
+        let (response, body) = send(ranges("bytes=-3"));
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "3");
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(body, "789");
+    }
+
+    #[test]
+    fn multipart_response_is_sent() {
+        let response = RangedResponse::Multipart {
+            full_len: 10,
+            boundary: "B".into(),
+            parts: vec![(0..2, "01"), (6..8, "67")],
+        };
+        let (response, body) = send(response);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "multipart/byteranges; boundary=B"
+        );
+        assert_eq!(
+            body,
+            "\r\n--B\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/10\r\n\r\n01\
+             \r\n--B\r\ncontent-type: text/plain\r\ncontent-range: bytes 6-7/10\r\n\r\n67\
+             \r\n--B--\r\n"
+        );
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            body.len().to_string().as_str()
+        );
+    }
+
+    fn conditional(if_range: &'static str, validator: Option<&'static str>) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static("bytes=0-1"));
+        headers.insert(IF_RANGE, HeaderValue::from_static(if_range));
+        let validator = validator.map(HeaderValue::from_static);
+        serve_ranges(&headers, validator.as_ref(), DATA.len() as u64, body_of).status()
+    }
+
+    #[test]
+    fn if_range_with_matching_entity_tag() {
+        assert_eq!(
+            conditional("\"v1\"", Some("\"v1\"")),
+            StatusCode::PARTIAL_CONTENT
+        );
+        assert_eq!(conditional("\"v1\"", Some("\"v2\"")), StatusCode::OK);
+        assert_eq!(conditional("W/\"v1\"", Some("W/\"v1\"")), StatusCode::OK);
+        assert_eq!(conditional("\"v1\"", Some("W/\"v1\"")), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_range_with_matching_date() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(conditional(date, Some(date)), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            conditional(date, Some("Thu, 22 Oct 2015 07:28:00 GMT")),
+            StatusCode::OK
+        );
+        assert_eq!(conditional(date, Some("\"v1\"")), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_range_without_validator_is_not_matched() {
+        assert_eq!(conditional("\"v1\"", None), StatusCode::OK);
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        for range in &["bytes=5-2", "bytes=a-", "items=0-1", "bytes=", "bytes=-"] {
+            match ranges(range) {
+                RangedResponse::Full { .. } => {}
+                response => panic!("unexpected response to {:?}: {:?}", range, response),
+            }
+        }
+    }
+}
@@ -0,0 +1,554 @@
+//! A hook for observing the completed responses.
+
+use crate::{
+    conn::{Protocol, RemoteAddr},
+    App, Events,
+};
+use async_trait::async_trait;
+use bytes::Buf;
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use std::{borrow::Cow, fmt, net::SocketAddr, sync::Arc, time::Instant};
+use tracing::Level;
+
+/// A summary of an exchange of a request and its response.
+#[derive(Debug, Clone)]
+pub struct ResponseRecord {
+    /// The method of the request.
+    pub method: Method,
+
+    /// The path of the request URI.
+    pub path: String,
+
+    /// The status code of the response, or `None` if the response
+    /// header has not been sent.
+    pub status: Option<StatusCode>,
+
+    /// The total length of the names and values of the request header fields.
+    pub request_header_bytes: usize,
+
+    /// The number of bytes of the response body passed to the server.
+    pub response_body_bytes: u64,
+
+    /// The time when the request was passed to the application.
+    pub start: Instant,
+
+    /// The time when the response was completed or abandoned.
+    pub end: Instant,
+
+    /// The address of the client, if known.
+    pub remote_addr: Option<SocketAddr>,
+
+    /// The protocol of the connection, if known.
+    pub protocol: Option<Protocol>,
+
+    /// Whether the response was sent to the end of stream.
+    ///
+    /// This is `false` if the client disconnected, an error occurred while
+    /// sending the response, or the application returned without finishing it.
+    pub completed: bool,
+
+    /// The class of the response given by the `StatusClassifier`.
+    ///
+    /// The value is set when the response is completed or abandoned.
+    pub class: Cow<'static, str>,
+
+    /// The level at which the response should be logged, given by the `StatusClassifier`.
+    ///
+    /// The value is set when the response is completed or abandoned.
+    pub level: Level,
+}
+
+/// A trait for classifying the responses in metrics and access logs.
+///
+/// The classifier set on `OnResponse` is applied to every record, including
+/// the responses that were abandoned before the header was sent, so the
+/// consumers of the record share the same classification.
+pub trait StatusClassifier: Send + Sync + 'static {
+    /// Returns the name of the class that the response belongs to.
+    fn classify(&self, record: &ResponseRecord) -> Cow<'static, str>;
+
+    /// Returns the level at which the response should be logged.
+    ///
+    /// By default, server errors are logged at `ERROR`, client errors and
+    /// the responses that were not completed at `WARN`, and others at `INFO`.
+    fn level(&self, record: &ResponseRecord) -> Level {
+        match record.status {
+            Some(status) if status.is_server_error() => Level::ERROR,
+            Some(status) if status.is_client_error() => Level::WARN,
+            Some(..) if record.completed => Level::INFO,
+            _ => Level::WARN,
+        }
+    }
+}
+
+/// The default `StatusClassifier`, which groups the responses by the class
+/// of the status code defined in RFC 7231 (`"2xx"`, `"4xx"`, etc.).
+///
+/// The responses abandoned before the header was sent are classified as `"aborted"`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DefaultClassifier(());
+
+impl DefaultClassifier {
+    /// Creates a new `DefaultClassifier`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StatusClassifier for DefaultClassifier {
+    fn classify(&self, record: &ResponseRecord) -> Cow<'static, str> {
+        let class = match record.status.map(|status| status.as_u16() / 100) {
+            None => "aborted",
+            Some(1) => "1xx",
+            Some(2) => "2xx",
+            Some(3) => "3xx",
+            Some(4) => "4xx",
+            Some(5) => "5xx",
+            Some(..) => "other",
+        };
+        Cow::Borrowed(class)
+    }
+}
+
+type Callback = Arc<dyn Fn(&ResponseRecord) + Send + Sync + 'static>;
+
+/// An application that calls a function with a `ResponseRecord`
+/// after each response is completed or abandoned.
+///
+/// The callback is invoked exactly once per request, when the `Events`
+/// passed to the inner application is dropped. It is intended for building
+/// custom metrics or access logs and should return quickly.
+pub struct OnResponse<T> {
+    app: T,
+    callback: Callback,
+    classifier: Arc<dyn StatusClassifier>,
+}
+
+impl<T> OnResponse<T> {
+    /// Wraps the specified application with the callback.
+    pub fn new<F>(app: T, callback: F) -> Self
+    where
+        F: Fn(&ResponseRecord) + Send + Sync + 'static,
+    {
+        Self {
+            app,
+            callback: Arc::new(callback),
+            classifier: Arc::new(DefaultClassifier::new()),
+        }
+    }
+
+    /// Sets the classifier that fills `ResponseRecord::class` and `ResponseRecord::level`.
+    ///
+    /// The default value is `DefaultClassifier`.
+    pub fn classifier<C>(self, classifier: C) -> Self
+    where
+        C: StatusClassifier,
+    {
+        Self {
+            classifier: Arc::new(classifier),
+            ..self
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnResponse<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnResponse")
+            .field("app", &self.app)
+            .finish()
+    }
+}
+
+impl<T: Clone> Clone for OnResponse<T> {
+    fn clone(&self) -> Self {
+        Self {
+            app: self.app.clone(),
+            callback: self.callback.clone(),
+            classifier: self.classifier.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, E> App<E> for OnResponse<T>
+where
+    T: App<RecordEvents<E>> + Send + Sync,
+    E: Events + Send,
+    E::Data: Send,
+{
+    type Error = T::Error;
+
+    async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+    where
+        E: 'async_trait,
+    {
+        let (parts, events) = request.into_parts();
+        let start = Instant::now();
+        let record = ResponseRecord {
+            method: parts.method.clone(),
+            path: parts.uri.path().to_owned(),
+            status: None,
+            request_header_bytes: parts
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum(),
+            response_body_bytes: 0,
+            start,
+            end: start,
+            remote_addr: parts.extensions.get::<RemoteAddr>().map(|addr| addr.0),
+            protocol: parts.extensions.get::<Protocol>().cloned(),
+            completed: false,
+            class: Cow::Borrowed(""),
+            level: Level::INFO,
+        };
+        let events = RecordEvents {
+            events,
+            record,
+            callback: self.callback.clone(),
+            classifier: self.classifier.clone(),
+        };
+        self.app.call(Request::from_parts(parts, events)).await
+    }
+}
+
+/// An `Events` that fills a `ResponseRecord` while sending the response.
+///
+/// The value of this type is passed to the application wrapped by `OnResponse`.
+pub struct RecordEvents<E> {
+    events: E,
+    record: ResponseRecord,
+    callback: Callback,
+    classifier: Arc<dyn StatusClassifier>,
+}
+
+impl<E> RecordEvents<E> {
+    /// Returns the record collected so far.
+    pub fn record(&self) -> &ResponseRecord {
+        &self.record
+    }
+
+    /// Returns a reference to the underlying `Events`.
+    pub fn get_ref(&self) -> &E {
+        &self.events
+    }
+
+    /// Returns a mutable reference to the underlying `Events`.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.events
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for RecordEvents<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordEvents")
+            .field("events", &self.events)
+            .field("record", &self.record)
+            .finish()
+    }
+}
+
+impl<E> Drop for RecordEvents<E> {
+    fn drop(&mut self) {
+        self.record.end = Instant::now();
+        self.record.class = self.classifier.classify(&self.record);
+        self.record.level = self.classifier.level(&self.record);
+        (self.callback)(&self.record);
+    }
+}
+
+#[async_trait]
+impl<E> Events for RecordEvents<E>
+where
+    E: Events + Send,
+    E::Data: Send,
+{
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.events.remote_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.events.local_addr()
+    }
+
+    async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        self.events.data().await
+    }
+
+    async fn trailers(&mut self) -> Result<Option<HeaderMap>, Self::Error> {
+        self.events.trailers().await
+    }
+
+    async fn start_send_response(
+        &mut self,
+        response: Response<()>,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        let status = response.status();
+        self.events
+            .start_send_response(response, end_of_stream)
+            .await?;
+        self.record.status = Some(status);
+        self.record.completed = end_of_stream;
+        Ok(())
+    }
+
+    async fn send_data(
+        &mut self,
+        data: Self::Data,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        let len = data.remaining() as u64;
+        self.events.send_data(data, end_of_stream).await?;
+        self.record.response_body_bytes += len;
+        self.record.completed = end_of_stream;
+        Ok(())
+    }
+
+    async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
+        self.events.send_trailers(trailers).await?;
+        self.record.completed = true;
+        Ok(())
+    }
+
+    async fn ready(&mut self) -> Result<(), Self::Error> {
+        self.events.ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::handler_fn, testing::MockEvents};
+    use bytes::Bytes;
+    use futures::{executor::block_on, future, FutureExt};
+    use std::sync::Mutex;
+
+    type Records = Arc<Mutex<Vec<ResponseRecord>>>;
+
+    fn collect<T>(app: T) -> (OnResponse<T>, Records) {
+        let records = Records::default();
+        let app = OnResponse::new(app, {
+            let records = records.clone();
+            move |record: &ResponseRecord| records.lock().unwrap().push(record.clone())
+        });
+        (app, records)
+    }
+
+    fn request(events: &mut MockEvents) -> Request<&mut MockEvents> {
+        let mut request = Request::post("/upload?query")
+            .header("x-key", "value")
+            .body(events)
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(RemoteAddr("192.0.2.1:50000".parse().unwrap()));
+        request
+            .extensions_mut()
+            .insert(Protocol::Http1 { is_tls: false });
+        request
+    }
+
+    /// An application that starts a streaming response, sends a chunk,
+    /// and then never finishes it.
+    struct Unfinished {
+        hang: bool,
+    }
+
+    #[async_trait]
+    impl<E> App<E> for Unfinished
+    where
+        E: Events + Send,
+        E::Data: Send,
+    {
+        type Error = E::Error;
+
+        async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+        where
+            E: 'async_trait,
+        {
+            let mut events = request.into_body();
+            events.start_send_response(Response::new(()), false).await?;
+            events.send_data("abc".into(), false).await?;
+            if self.hang {
+                future::pending::<()>().await;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn completed_response_is_recorded() {
+        let (app, records) = collect(handler_fn(|_: Request<Bytes>| async {
+            let mut response = Response::new("hello");
+            *response.status_mut() = StatusCode::CREATED;
+            Ok::<_, String>(response)
+        }));
+        let before = Instant::now();
+        let mut events = MockEvents::default();
+        block_on(app.call(request(&mut events))).unwrap();
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.method, Method::POST);
+        assert_eq!(record.path, "/upload");
+        assert_eq!(record.status, Some(StatusCode::CREATED));
+        assert_eq!(record.request_header_bytes, "x-key".len() + "value".len());
+        assert_eq!(record.response_body_bytes, 5);
+        assert!(before <= record.start && record.start <= record.end);
+        assert_eq!(record.remote_addr, Some("192.0.2.1:50000".parse().unwrap()));
+        assert_eq!(record.protocol, Some(Protocol::Http1 { is_tls: false }));
+        assert!(record.completed);
+        assert_eq!(record.class, "2xx");
+        assert_eq!(record.level, Level::INFO);
+    }
+
+    #[test]
+    fn unfinished_response_is_not_completed() {
+        let (app, records) = collect(Unfinished { hang: false });
+        let mut events = MockEvents::default();
+        block_on(app.call(request(&mut events))).unwrap();
+
+        let record = &records.lock().unwrap()[0];
+        assert_eq!(record.status, Some(StatusCode::OK));
+        assert_eq!(record.response_body_bytes, 3);
+        assert!(!record.completed);
+        assert_eq!(record.level, Level::WARN);
+    }
+
+    #[test]
+    fn cancelled_response_is_recorded_on_drop() {
+        let (app, records) = collect(Unfinished { hang: true });
+        let mut events = MockEvents::default();
+        // The server drops the future of the application when the request is cancelled.
+        assert!(app.call(request(&mut events)).now_or_never().is_none());
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.response_body_bytes, 3);
+        assert!(!record.completed);
+        assert!(record.start <= record.end);
+    }
+
+    #[test]
+    fn response_abandoned_before_the_header_is_aborted() {
+        let (app, records) = collect(handler_fn(|_: Request<Bytes>| {
+            future::pending::<Result<Response<Bytes>, String>>()
+        }));
+        let mut events = MockEvents::default();
+        assert!(app.call(request(&mut events)).now_or_never().is_none());
+
+        let record = &records.lock().unwrap()[0];
+        assert_eq!(record.status, None);
+        assert!(!record.completed);
+        assert_eq!(record.class, "aborted");
+        assert_eq!(record.level, Level::WARN);
+    }
+
+    fn record(status: Option<u16>, completed: bool) -> ResponseRecord {
+        let now = Instant::now();
+        ResponseRecord {
+            method: Method::GET,
+            path: "/".into(),
+            status: status.map(|status| StatusCode::from_u16(status).unwrap()),
+            request_header_bytes: 0,
+            response_body_bytes: 0,
+            start: now,
+            end: now,
+            remote_addr: None,
+            protocol: None,
+            completed,
+            class: Cow::Borrowed(""),
+            level: Level::INFO,
+        }
+    }
+
+    #[test]
+    fn default_classifier_groups_by_status_class() {
+        let classifier = DefaultClassifier::new();
+        for &(status, class) in &[
+            (Some(101), "1xx"),
+            (Some(204), "2xx"),
+            (Some(304), "3xx"),
+            (Some(404), "4xx"),
+            (Some(503), "5xx"),
+            (None, "aborted"),
+        ] {
+            assert_eq!(classifier.classify(&record(status, true)), class);
+        }
+    }
+
+    #[test]
+    fn default_levels_follow_the_status() {
+        let classifier = DefaultClassifier::new();
+        assert_eq!(classifier.level(&record(Some(500), true)), Level::ERROR);
+        assert_eq!(classifier.level(&record(Some(404), true)), Level::WARN);
+        assert_eq!(classifier.level(&record(Some(200), true)), Level::INFO);
+        assert_eq!(classifier.level(&record(Some(302), true)), Level::INFO);
+        assert_eq!(classifier.level(&record(Some(200), false)), Level::WARN);
+        assert_eq!(classifier.level(&record(None, false)), Level::WARN);
+    }
+
+    /// A classifier that separates `404` from the other client errors.
+    struct NotFoundApart;
+
+    impl StatusClassifier for NotFoundApart {
+        fn classify(&self, record: &ResponseRecord) -> Cow<'static, str> {
+            match record.status {
+                Some(StatusCode::NOT_FOUND) => Cow::Borrowed("not_found"),
+                _ => DefaultClassifier::new().classify(record),
+            }
+        }
+
+        fn level(&self, record: &ResponseRecord) -> Level {
+            match record.status {
+                Some(StatusCode::NOT_FOUND) => Level::DEBUG,
+                _ => Level::TRACE,
+            }
+        }
+    }
+
+    #[test]
+    fn custom_classifier_is_applied_to_the_records() {
+        let records = Records::default();
+        let app = OnResponse::new(
+            handler_fn(|request: Request<Bytes>| async move {
+                let mut response = Response::new("");
+                *response.status_mut() = match request.uri().path() {
+                    "/missing" => StatusCode::NOT_FOUND,
+                    _ => StatusCode::FORBIDDEN,
+                };
+                Ok::<_, String>(response)
+            }),
+            {
+                let records = records.clone();
+                move |record: &ResponseRecord| records.lock().unwrap().push(record.clone())
+            },
+        )
+        .classifier(NotFoundApart);
+
+        for &path in &["/missing", "/secret"] {
+            let mut events = MockEvents::default();
+            let request = Request::get(path).body(&mut events).unwrap();
+            block_on(app.call(request)).unwrap();
+        }
+
+        let records = records.lock().unwrap();
+        let classes: Vec<_> = records
+            .iter()
+            .map(|record| (record.class.clone(), record.level))
+            .collect();
+        assert_eq!(
+            classes,
+            vec![
+                (Cow::Borrowed("not_found"), Level::DEBUG),
+                (Cow::Borrowed("4xx"), Level::TRACE),
+            ]
+        );
+    }
+}
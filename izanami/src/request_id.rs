@@ -0,0 +1,318 @@
+//! Generation and propagation of request IDs.
+
+use crate::{App, Events};
+use async_trait::async_trait;
+use http::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Request, Response,
+};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::Instrument;
+
+/// The name of the header field that carries the request ID.
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+const MAX_LEN: usize = 128;
+
+/// The identifier of a request.
+///
+/// The value is stored in the extensions of the request passed to
+/// the application wrapped by `SetRequestId`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    /// Generates a new, unique request ID.
+    ///
+    /// The ID is a 26-character string in the same format as ULID, i.e.
+    /// a millisecond timestamp followed by 80 bits of randomness encoded
+    /// with the Crockford's Base32 alphabet.
+    pub fn generate() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let value = (millis << 80) | (random_u128() & ((1 << 80) - 1));
+
+        const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        let encoded: Vec<u8> = (0..26)
+            .map(|i| ALPHABET[((value >> (125 - 5 * i)) & 0x1f) as usize])
+            .collect();
+        RequestId(HeaderValue::from_bytes(&encoded).expect("should be a valid header value"))
+    }
+
+    /// Creates a `RequestId` from the value of an incoming header field.
+    ///
+    /// It returns `None` if the value is empty, longer than 128 bytes, or
+    /// contains characters other than the printable ASCII characters.
+    pub fn from_header(value: &HeaderValue) -> Option<Self> {
+        let bytes = value.as_bytes();
+        if bytes.is_empty() || bytes.len() > MAX_LEN {
+            return None;
+        }
+        if !bytes.iter().all(|&b| b == b' ' || b.is_ascii_graphic()) {
+            return None;
+        }
+        Some(RequestId(value.clone()))
+    }
+
+    /// Returns the request ID associated with the specified request, if any.
+    pub fn get<T>(request: &Request<T>) -> Option<&Self> {
+        request.extensions().get()
+    }
+
+    /// Returns the string representation of this ID.
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().expect("should be a printable ASCII string")
+    }
+
+    /// Returns the representation of this ID as a header value.
+    pub fn as_header_value(&self) -> &HeaderValue {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn random_u128() -> u128 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let random = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+        u128::from(hasher.finish())
+    };
+    (random() << 64) | random()
+}
+
+/// An application that assigns an ID to each request.
+///
+/// The ID is taken from the `x-request-id` header of the request if it is
+/// well-formed, and is generated by `RequestId::generate` otherwise. It is
+/// stored in the request extensions, recorded as the field `request_id` of
+/// the span entered while calling the inner application, and echoed in the
+/// `x-request-id` header of the response unless the application sets the
+/// header by itself.
+#[derive(Debug, Clone)]
+pub struct SetRequestId<T> {
+    app: T,
+}
+
+impl<T> SetRequestId<T> {
+    /// Wraps the specified application.
+    pub fn new(app: T) -> Self {
+        Self { app }
+    }
+}
+
+#[async_trait]
+impl<T, E> App<E> for SetRequestId<T>
+where
+    T: App<RequestIdEvents<E>> + Send + Sync,
+    E: Events + Send,
+    E::Data: Send,
+{
+    type Error = T::Error;
+
+    async fn call(&self, request: Request<E>) -> Result<(), Self::Error>
+    where
+        E: 'async_trait,
+    {
+        let id = request
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+
+        let (mut parts, events) = request.into_parts();
+        parts.extensions.insert(id.clone());
+        let span = tracing::info_span!("request", request_id = %id);
+        let request = Request::from_parts(parts, RequestIdEvents { events, id });
+
+        self.app.call(request).instrument(span).await
+    }
+}
+
+/// An `Events` that adds the `x-request-id` header to the response.
+///
+/// The value of this type is passed to the application wrapped by `SetRequestId`.
+#[derive(Debug)]
+pub struct RequestIdEvents<E> {
+    events: E,
+    id: RequestId,
+}
+
+impl<E> RequestIdEvents<E> {
+    /// Returns the ID of the request.
+    pub fn request_id(&self) -> &RequestId {
+        &self.id
+    }
+
+    /// Returns a reference to the underlying `Events`.
+    pub fn get_ref(&self) -> &E {
+        &self.events
+    }
+
+    /// Returns a mutable reference to the underlying `Events`.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.events
+    }
+}
+
+#[async_trait]
+impl<E> Events for RequestIdEvents<E>
+where
+    E: Events + Send,
+    E::Data: Send,
+{
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.events.remote_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.events.local_addr()
+    }
+
+    async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        self.events.data().await
+    }
+
+    async fn trailers(&mut self) -> Result<Option<HeaderMap>, Self::Error> {
+        self.events.trailers().await
+    }
+
+    async fn start_send_response(
+        &mut self,
+        mut response: Response<()>,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        response
+            .headers_mut()
+            .entry(HeaderName::from_static(X_REQUEST_ID))
+            .expect("should be a valid header name")
+            .or_insert_with(|| self.id.0.clone());
+        self.events
+            .start_send_response(response, end_of_stream)
+            .await
+    }
+
+    async fn send_data(
+        &mut self,
+        data: Self::Data,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        self.events.send_data(data, end_of_stream).await
+    }
+
+    async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
+        self.events.send_trailers(trailers).await
+    }
+
+    async fn ready(&mut self) -> Result<(), Self::Error> {
+        self.events.ready().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::handler_fn, testing::MockEvents};
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use std::collections::HashSet;
+
+    fn is_well_formed(id: &str) -> bool {
+        id.len() == 26
+            && id
+                .bytes()
+                .all(|b| b"0123456789ABCDEFGHJKMNPQRSTVWXYZ".contains(&b))
+    }
+
+    /// Calls `SetRequestId` with the incoming header value, and returns the ID
+    /// seen by the inner application and the one sent in the response.
+    fn call(incoming: Option<HeaderValue>) -> (String, HeaderValue) {
+        let app = SetRequestId::new(handler_fn(|request: Request<Bytes>| async move {
+            let id = RequestId::get(&request).expect("the ID should be set");
+            Ok::<_, String>(Response::new(id.to_string()))
+        }));
+        let mut events = MockEvents::default();
+        let mut request = Request::new(&mut events);
+        if let Some(incoming) = incoming {
+            request.headers_mut().insert(X_REQUEST_ID, incoming);
+        }
+        block_on(app.call(request)).unwrap();
+
+        let response = events.response.unwrap();
+        (
+            String::from_utf8(events.response_body).unwrap(),
+            response.headers()[X_REQUEST_ID].clone(),
+        )
+    }
+
+    #[test]
+    fn generated_ids_are_unique_and_well_formed() {
+        let ids: HashSet<_> = (0..1000).map(|_| RequestId::generate()).collect();
+        assert_eq!(ids.len(), 1000);
+        for id in &ids {
+            assert!(is_well_formed(id.as_str()), "{}", id);
+        }
+    }
+
+    #[test]
+    fn id_is_generated_without_the_header() {
+        let (seen, sent) = call(None);
+        assert!(is_well_formed(&seen), "{}", seen);
+        assert_eq!(sent, seen.as_str());
+    }
+
+    #[test]
+    fn incoming_id_is_propagated_verbatim() {
+        for &incoming in &["abc-123", "Trace ID: {42}", &"x".repeat(128)[..]] {
+            let (seen, sent) = call(Some(HeaderValue::from_str(incoming).unwrap()));
+            assert_eq!(seen, incoming);
+            assert_eq!(sent, incoming);
+        }
+    }
+
+    #[test]
+    fn invalid_incoming_id_is_replaced() {
+        for incoming in &[
+            HeaderValue::from_str(&"x".repeat(129)).unwrap(),
+            HeaderValue::from_static(""),
+            HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap(),
+            HeaderValue::from_bytes(b"tab\there").unwrap(),
+        ] {
+            let (seen, sent) = call(Some(incoming.clone()));
+            assert!(is_well_formed(&seen), "{:?} => {}", incoming, seen);
+            assert_eq!(sent, seen.as_str());
+        }
+    }
+
+    #[test]
+    fn header_set_by_the_application_is_kept() {
+        let app = SetRequestId::new(handler_fn(|_: Request<Bytes>| async move {
+            let mut response = Response::new("");
+            response
+                .headers_mut()
+                .insert(X_REQUEST_ID, HeaderValue::from_static("custom"));
+            Ok::<_, String>(response)
+        }));
+        let mut events = MockEvents::default();
+        block_on(app.call(Request::new(&mut events))).unwrap();
+        let response = events.response.unwrap();
+        assert_eq!(response.headers()[X_REQUEST_ID], "custom");
+    }
+}
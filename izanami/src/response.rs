@@ -0,0 +1,217 @@
+//! Helper functions for creating common responses.
+//!
+//! The body of the created responses is `Bytes`, which can be converted
+//! into the `Data` of any `Events`.
+
+use bytes::Bytes;
+use http::{
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    HttpTryFrom, Response, StatusCode,
+};
+use std::{error, fmt};
+
+/// Creates a response with a `text/plain` body.
+pub fn text<T>(status: StatusCode, body: T) -> Response<Bytes>
+where
+    T: Into<Bytes>,
+{
+    with_content_type(status, "text/plain; charset=utf-8", body.into())
+}
+
+/// Creates a response with a `text/html` body.
+pub fn html<T>(status: StatusCode, body: T) -> Response<Bytes>
+where
+    T: Into<Bytes>,
+{
+    with_content_type(status, "text/html; charset=utf-8", body.into())
+}
+
+/// Creates a `200 OK` response with the JSON representation of `value`.
+#[cfg(feature = "json")]
+pub fn json<T>(value: &T) -> Result<Response<Bytes>, serde_json::Error>
+where
+    T: serde::Serialize + ?Sized,
+{
+    let body = serde_json::to_vec(value)?;
+    Ok(with_content_type(
+        StatusCode::OK,
+        "application/json",
+        body.into(),
+    ))
+}
+
+/// Creates a redirect response to `location`.
+///
+/// It returns an error if `status` is not a redirection (`3xx`) or
+/// `location` is not a valid header value.
+pub fn redirect(status: StatusCode, location: &str) -> Result<Response<Bytes>, InvalidRedirect> {
+    if !status.is_redirection() {
+        return Err(InvalidRedirect(RedirectErrorKind::Status(status)));
+    }
+    let location = HeaderValue::from_str(location)
+        .map_err(|_| InvalidRedirect(RedirectErrorKind::Location))?;
+
+    let mut response = self::status(status);
+    response.headers_mut().insert(LOCATION, location);
+    Ok(response)
+}
+
+/// Creates a `204 No Content` response.
+pub fn no_content() -> Response<Bytes> {
+    status(StatusCode::NO_CONTENT)
+}
+
+/// Creates a response with the specified status code and an empty body.
+pub fn status(status: StatusCode) -> Response<Bytes> {
+    let mut response = Response::new(Bytes::new());
+    *response.status_mut() = status;
+    response
+}
+
+fn with_content_type(
+    status: StatusCode,
+    content_type: &'static str,
+    body: Bytes,
+) -> Response<Bytes> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let len = response.body().len() as u64;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CONTENT_LENGTH, len.into());
+    response
+}
+
+/// The error returned from `redirect`.
+#[derive(Debug)]
+pub struct InvalidRedirect(RedirectErrorKind);
+
+#[derive(Debug)]
+enum RedirectErrorKind {
+    Status(StatusCode),
+    Location,
+}
+
+impl fmt::Display for InvalidRedirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            RedirectErrorKind::Status(status) => {
+                write!(f, "the status code is not a redirection: {}", status)
+            }
+            RedirectErrorKind::Location => f.write_str("invalid location"),
+        }
+    }
+}
+
+impl error::Error for InvalidRedirect {}
+
+/// An extension trait for `Response`.
+pub trait ResponseExt: Sized {
+    /// Appends a header field to the response.
+    ///
+    /// It returns an error if the name or value is invalid.
+    fn with_header<K, V>(self, name: K, value: V) -> Result<Self, http::Error>
+    where
+        HeaderName: HttpTryFrom<K>,
+        HeaderValue: HttpTryFrom<V>;
+}
+
+impl<T> ResponseExt for Response<T> {
+    fn with_header<K, V>(mut self, name: K, value: V) -> Result<Self, http::Error>
+    where
+        HeaderName: HttpTryFrom<K>,
+        HeaderValue: HttpTryFrom<V>,
+    {
+        let name = HeaderName::try_from(name).map_err(Into::into)?;
+        let value = HeaderValue::try_from(value).map_err(Into::into)?;
+        self.headers_mut().append(name, value);
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_sets_the_content_type_and_length() {
+        let response = text(StatusCode::OK, "hello");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        assert_eq!(response.body(), "hello");
+    }
+
+    #[test]
+    fn html_sets_the_content_type_and_length() {
+        let response = html(StatusCode::NOT_FOUND, "<p>not found</p>");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "16");
+        assert_eq!(response.body(), "<p>not found</p>");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_serializes_the_value() {
+        let response = json(&serde_json::json!({ "ok": true })).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "11");
+        assert_eq!(response.body(), r#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn redirect_sets_the_location() {
+        let response = redirect(StatusCode::SEE_OTHER, "/login").unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[LOCATION], "/login");
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn redirect_rejects_the_status_other_than_3xx() {
+        let err = redirect(StatusCode::OK, "/login").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the status code is not a redirection: 200 OK"
+        );
+    }
+
+    #[test]
+    fn redirect_rejects_the_invalid_location() {
+        let err = redirect(StatusCode::FOUND, "/a\nb").unwrap_err();
+        assert_eq!(err.to_string(), "invalid location");
+    }
+
+    #[test]
+    fn no_content_has_neither_body_nor_headers() {
+        let response = no_content();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().is_empty());
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn status_has_an_empty_body() {
+        let response = status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().is_empty());
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn with_header_appends_the_field() {
+        let response = no_content()
+            .with_header("x-a", "1")
+            .and_then(|response| response.with_header("x-a", "2"))
+            .unwrap();
+        let values: Vec<_> = response.headers().get_all("x-a").iter().collect();
+        assert_eq!(values, ["1", "2"]);
+
+        assert!(no_content().with_header("x a", "1").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! An in-memory `Events` for the unit tests.
+
+use crate::{finalize::InvalidResponseState, Events};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use http::{HeaderMap, Response};
+use std::{collections::VecDeque, error, io::Cursor, net::SocketAddr};
+
+pub(crate) type Error = Box<dyn error::Error + Send + Sync + 'static>;
+
+/// The chunk type of `MockEvents`.
+#[derive(Debug)]
+pub(crate) struct Chunk(Cursor<Bytes>);
+
+impl Buf for Chunk {
+    fn remaining(&self) -> usize {
+        self.0.remaining()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.0.bytes()
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        self.0.advance(cnt)
+    }
+}
+
+impl From<Chunk> for Bytes {
+    fn from(chunk: Chunk) -> Self {
+        let pos = chunk.0.position() as usize;
+        chunk.0.into_inner().slice_from(pos)
+    }
+}
+
+impl From<Bytes> for Chunk {
+    fn from(bytes: Bytes) -> Self {
+        Chunk(Cursor::new(bytes))
+    }
+}
+
+impl From<&'static str> for Chunk {
+    fn from(s: &'static str) -> Self {
+        Bytes::from_static(s.as_bytes()).into()
+    }
+}
+
+impl From<&'static [u8]> for Chunk {
+    fn from(s: &'static [u8]) -> Self {
+        Bytes::from_static(s).into()
+    }
+}
+
+impl From<String> for Chunk {
+    fn from(s: String) -> Self {
+        Bytes::from(s).into()
+    }
+}
+
+impl From<Vec<u8>> for Chunk {
+    fn from(v: Vec<u8>) -> Self {
+        Bytes::from(v).into()
+    }
+}
+
+/// An `Events` that returns the given request body and records the response.
+#[derive(Debug, Default)]
+pub(crate) struct MockEvents {
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) request_body: VecDeque<Bytes>,
+    pub(crate) request_trailers: Option<HeaderMap>,
+    pub(crate) response: Option<Response<()>>,
+    pub(crate) response_body: Vec<u8>,
+    pub(crate) response_trailers: Option<HeaderMap>,
+    pub(crate) finished: bool,
+}
+
+impl MockEvents {
+    pub(crate) fn new<I>(request_body: I) -> Self
+    where
+        I: IntoIterator<Item = &'static str>,
+    {
+        Self {
+            request_body: request_body
+                .into_iter()
+                .map(|chunk| Bytes::from_static(chunk.as_bytes()))
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    fn check_sending(&self) -> Result<(), Error> {
+        if self.response.is_none() {
+            Err(InvalidResponseState::not_started().into())
+        } else if self.finished {
+            Err(InvalidResponseState::finished().into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl Events for MockEvents {
+    type Data = Chunk;
+    type Error = Error;
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    async fn data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        self.request_body.pop_front().map(|chunk| Ok(chunk.into()))
+    }
+
+    async fn trailers(&mut self) -> Result<Option<HeaderMap>, Self::Error> {
+        Ok(self.request_trailers.take())
+    }
+
+    async fn start_send_response(
+        &mut self,
+        response: Response<()>,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        if self.response.is_some() {
+            return Err(InvalidResponseState::already_started().into());
+        }
+        self.response = Some(response);
+        self.finished = end_of_stream;
+        Ok(())
+    }
+
+    async fn send_data(
+        &mut self,
+        data: Self::Data,
+        end_of_stream: bool,
+    ) -> Result<(), Self::Error> {
+        self.check_sending()?;
+        self.response_body.extend_from_slice(data.bytes());
+        self.finished = end_of_stream;
+        Ok(())
+    }
+
+    async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Self::Error> {
+        self.check_sending()?;
+        self.response_trailers = Some(trailers);
+        self.finished = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+//! Percent-decoding of the components of request URIs.
+//!
+//! The path returned by `Uri::path` is still percent-encoded, and its
+//! decoded form is not necessarily a valid UTF-8 string. The functions in
+//! this module decode the path (or a query component) exactly once and
+//! reject the inputs that the applications usually do not expect, so that
+//! routing and parameter extraction can work on plain strings.
+//!
+//! A double-encoded sequence such as `%252e` is decoded to `%2e` and
+//! is never decoded again.
+
+use std::{borrow::Cow, error, fmt};
+
+/// Decodes the percent-encoded bytes in a path.
+///
+/// `+` is kept as a literal character. It returns an error if the path
+/// contains a malformed escape, the decoded bytes are not valid UTF-8
+/// (including overlong encodings such as `%C0%AF`), or it contains a NUL
+/// character or a `.`/`..` segment after decoding.
+pub fn decode_path(path: &str) -> Result<Cow<'_, str>, DecodeError> {
+    let decoded = decode_path_bytes(path)?;
+    let decoded = match decoded {
+        Cow::Borrowed(..) => Cow::Borrowed(path),
+        Cow::Owned(bytes) => {
+            Cow::Owned(String::from_utf8(bytes).map_err(|_| DecodeError(ErrorKind::NotUtf8))?)
+        }
+    };
+    if decoded.contains('\0') {
+        return Err(DecodeError(ErrorKind::Nul));
+    }
+    if decoded
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Err(DecodeError(ErrorKind::DotSegment));
+    }
+    Ok(decoded)
+}
+
+/// Decodes the percent-encoded bytes in a path without interpreting the result.
+///
+/// This is an escape hatch for the applications that need to handle
+/// the paths that `decode_path` rejects. Only the malformed escapes
+/// are reported as an error.
+pub fn decode_path_bytes(path: &str) -> Result<Cow<'_, [u8]>, DecodeError> {
+    percent_decode(path.as_bytes(), false)
+}
+
+/// Decodes a key or value of the query string.
+///
+/// Unlike the path, `+` is decoded as a space. It returns an error if the
+/// component contains a malformed escape or the decoded bytes are not valid UTF-8.
+pub fn decode_query_component(component: &str) -> Result<Cow<'_, str>, DecodeError> {
+    match percent_decode(component.as_bytes(), true)? {
+        Cow::Borrowed(..) => Ok(Cow::Borrowed(component)),
+        Cow::Owned(bytes) => String::from_utf8(bytes)
+            .map(Cow::Owned)
+            .map_err(|_| DecodeError(ErrorKind::NotUtf8)),
+    }
+}
+
+fn percent_decode(input: &[u8], plus_as_space: bool) -> Result<Cow<'_, [u8]>, DecodeError> {
+    if !input
+        .iter()
+        .any(|&b| b == b'%' || (plus_as_space && b == b'+'))
+    {
+        return Ok(Cow::Borrowed(input));
+    }
+
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hi = bytes.next().and_then(|&b| hex_value(b));
+                let lo = bytes.next().and_then(|&b| hex_value(b));
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => decoded.push(hi << 4 | lo),
+                    _ => return Err(DecodeError(ErrorKind::MalformedEscape)),
+                }
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+    Ok(Cow::Owned(decoded))
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// The error returned when decoding a component of the URI fails.
+#[derive(Debug)]
+pub struct DecodeError(ErrorKind);
+
+#[derive(Debug)]
+enum ErrorKind {
+    MalformedEscape,
+    NotUtf8,
+    Nul,
+    DotSegment,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            ErrorKind::MalformedEscape => "malformed percent-encoding",
+            ErrorKind::NotUtf8 => "the decoded string is not valid UTF-8",
+            ErrorKind::Nul => "the decoded string contains a NUL character",
+            ErrorKind::DotSegment => "the decoded path contains a dot-segment",
+        })
+    }
+}
+
+impl error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_error(path: &str) -> ErrorKind {
+        decode_path(path).unwrap_err().0
+    }
+
+    #[test]
+    fn plain_path_is_borrowed() {
+        assert!(matches!(decode_path("/a/b+c"), Ok(Cow::Borrowed("/a/b+c"))));
+    }
+
+    #[test]
+    fn escapes_are_decoded_once() {
+        assert_eq!(decode_path("/caf%C3%A9").unwrap(), "/café");
+        assert_eq!(decode_path("/a%252e").unwrap(), "/a%2e");
+        assert_eq!(decode_path("/a%2fb").unwrap(), "/a/b");
+        assert_eq!(decode_path("/a%2Fb").unwrap(), "/a/b");
+    }
+
+    #[test]
+    fn encoded_slash_cannot_form_dot_segments() {
+        assert!(matches!(path_error("/a%2F..%2Fb"), ErrorKind::DotSegment));
+        assert!(matches!(path_error("/a/%2e%2e/b"), ErrorKind::DotSegment));
+    }
+
+    #[test]
+    fn dot_segments_are_rejected() {
+        for path in &["/a/../b", "/a/./b", "/..", "/a/.", "/%2E/b", "/.%2e"] {
+            assert!(
+                matches!(path_error(path), ErrorKind::DotSegment),
+                "{}",
+                path
+            );
+        }
+        // Dots that are a part of a segment are allowed.
+        assert_eq!(decode_path("/a/..b/.c/d.").unwrap(), "/a/..b/.c/d.");
+    }
+
+    #[test]
+    fn nul_is_rejected() {
+        assert!(matches!(path_error("/a%00b"), ErrorKind::Nul));
+        assert_eq!(&*decode_path_bytes("/a%00b").unwrap(), b"/a\0b");
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        // overlong encodings of `/` and `.`
+        for path in &["/%C0%AF", "/%C0%AE%C0%AE", "/%E0%80%AF", "/%FF", "/%C3"] {
+            assert!(matches!(path_error(path), ErrorKind::NotUtf8), "{}", path);
+        }
+        assert_eq!(&*decode_path_bytes("/%C0%AF").unwrap(), b"/\xC0\xAF");
+    }
+
+    #[test]
+    fn malformed_escapes_are_rejected() {
+        for path in &["/%", "/%2", "/a%G0", "/a%0G", "/%%20"] {
+            assert!(
+                matches!(path_error(path), ErrorKind::MalformedEscape),
+                "{}",
+                path
+            );
+            assert!(decode_path_bytes(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn query_component_decodes_plus_as_space() {
+        assert_eq!(decode_query_component("a+b%2Bc").unwrap(), "a b+c");
+        assert!(matches!(
+            decode_query_component("plain"),
+            Ok(Cow::Borrowed("plain"))
+        ));
+        assert!(decode_query_component("%FF").is_err());
+        assert!(decode_query_component("%F").is_err());
+    }
+}